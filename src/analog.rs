@@ -0,0 +1,302 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::prelude::*;
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    Buffer, BufferSize, BytesRequired, Connection, ConstantBufferSize, EmptyResult, Error, LocalId,
+    Message, MessageTypeIdentifier, Result, SenderId, SenderName, StaticTypeName, TimeVal,
+    TypedMessageBody, Unbuffer,
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Maximum number of channels in an analog report, matching vrpn_CHANNEL_MAX.
+pub const CHANNEL_MAX: usize = 128;
+
+/// Values of all channels of an analog device.
+///
+/// On the wire, the channel count is sent as an f64, followed by the channel values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelReport {
+    pub channels: Vec<f64>,
+}
+
+impl ChannelReport {
+    pub fn new(channels: Vec<f64>) -> ChannelReport {
+        ChannelReport { channels }
+    }
+}
+
+impl TypedMessageBody for ChannelReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Analog Channel"));
+}
+
+impl BufferSize for ChannelReport {
+    fn buffer_size(&self) -> usize {
+        f64::constant_buffer_size() * (self.channels.len() + 1)
+    }
+}
+
+impl Buffer for ChannelReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if self.channels.len() > CHANNEL_MAX {
+            return Err(Error::OtherMessage(format!(
+                "analog report has {} channels, but the maximum is {}",
+                self.channels.len(),
+                CHANNEL_MAX
+            )));
+        }
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        (self.channels.len() as f64).buffer_ref(buf)?;
+        for channel in &self.channels {
+            channel.buffer_ref(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Unbuffer for ChannelReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let num_channels = f64::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        if !num_channels.is_finite() || num_channels.fract() != 0.0 {
+            return Err(Error::OtherMessage(format!(
+                "analog report claims {} channels, which is not a whole number",
+                num_channels
+            )));
+        }
+        if num_channels < 0.0 || num_channels > CHANNEL_MAX as f64 {
+            return Err(Error::OtherMessage(format!(
+                "analog report claims {} channels, but the maximum is {}",
+                num_channels, CHANNEL_MAX
+            )));
+        }
+        let num_channels = num_channels as usize;
+        let needed = num_channels * f64::constant_buffer_size();
+        if buf.len() < needed {
+            return Err(Error::NeedMoreData(BytesRequired::Exactly(
+                needed - buf.len(),
+            )));
+        }
+        let channels = (0..num_channels)
+            .map(|_| f64::unbuffer_ref(buf))
+            .collect::<Result<Vec<f64>>>()?;
+        Ok(ChannelReport { channels })
+    }
+}
+
+/// The most recent channel report received, with its timestamp.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelState {
+    pub time: TimeVal,
+    pub channels: Vec<f64>,
+}
+
+struct ChannelHandler {
+    state: Weak<Mutex<Option<ChannelState>>>,
+}
+
+impl fmt::Debug for ChannelHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelHandler").finish()
+    }
+}
+
+impl TypedHandler for ChannelHandler {
+    type Item = ChannelReport;
+    fn handle_typed(&mut self, msg: &Message<ChannelReport>) -> Result<HandlerCode> {
+        match self.state.upgrade() {
+            Some(state) => {
+                let mut state = state.lock()?;
+                *state = Some(ChannelState {
+                    time: msg.header.time,
+                    channels: msg.body.channels.clone(),
+                });
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for an analog device: keeps the latest channel values.
+///
+/// For notification of each report as it arrives, add your own TypedHandler
+/// with `Item = ChannelReport` to the connection.
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    state: Arc<Mutex<Option<ChannelState>>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let state = Arc::new(Mutex::new(None));
+        let _ = connection.add_typed_handler(
+            Box::new(ChannelHandler {
+                state: Arc::downgrade(&state),
+            }),
+            Some(sender),
+        )?;
+        Ok(Remote {
+            connection,
+            sender,
+            state,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// Access the connection this remote is using.
+    pub fn connection(&self) -> &Arc<T> {
+        &self.connection
+    }
+
+    /// The local sender ID of the device.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Get the most recently reported channel values, if any have been received.
+    pub fn state(&self) -> Result<Option<ChannelState>> {
+        Ok(self.state.lock()?.clone())
+    }
+
+    /// Get the most recently reported value of one channel, if it has been received.
+    pub fn channel(&self, index: usize) -> Result<Option<f64>> {
+        Ok(self
+            .state
+            .lock()?
+            .as_ref()
+            .and_then(|state| state.channels.get(index).cloned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        async_io::ConnectionIp,
+        test_util::{dispatch, unconnected},
+        ServiceFlags, StaticSenderName,
+    };
+    use std::time::Duration;
+    use tokio::{prelude::*, runtime::current_thread::Runtime};
+
+    fn report_bytes() -> Vec<u8> {
+        Vec::from(&hex!("40 08 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 bf f0 00 00 00 00 00 00 3f d0 00 00 00 00 00 00")[..])
+    }
+
+    #[test]
+    fn buffer() {
+        let report = ChannelReport::new(vec![0.5, -1.0, 0.25]);
+        assert_eq!(report.buffer_size(), 32);
+        let buf = BytesMut::new()
+            .allocate_and_buffer(report)
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &report_bytes()[..]);
+    }
+
+    #[test]
+    fn unbuffer() {
+        let mut buf = Bytes::from(report_bytes());
+        let report = ChannelReport::unbuffer_ref(&mut buf).expect("Unbuffering needs to succeed");
+        assert_eq!(report, ChannelReport::new(vec![0.5, -1.0, 0.25]));
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn unbuffer_short() {
+        let mut buf = Bytes::from(&report_bytes()[..20]);
+        match ChannelReport::unbuffer_ref(&mut buf) {
+            Err(Error::NeedMoreData(BytesRequired::Exactly(n))) => assert_eq!(n, 12),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn too_many_channels() {
+        let report = ChannelReport::new(vec![0.0; CHANNEL_MAX + 1]);
+        assert!(BytesMut::new().allocate_and_buffer(report).is_err());
+    }
+
+    #[test]
+    fn unbuffer_bad_count() {
+        for count in &[f64::NAN, f64::INFINITY, -1.0, 2.7, (CHANNEL_MAX + 1) as f64] {
+            let mut bytes = report_bytes();
+            bytes.splice(..8, count.to_bits().to_be_bytes().iter().cloned());
+            let mut buf = Bytes::from(bytes);
+            assert!(
+                ChannelReport::unbuffer_ref(&mut buf).is_err(),
+                "a count of {} should be rejected",
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn remote() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Analog0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+        assert_eq!(remote.state().unwrap(), None);
+
+        dispatch(&conn, remote.sender(), ChannelReport::new(vec![0.5, -1.0]));
+        assert_eq!(remote.channel(1).unwrap(), Some(-1.0));
+        assert_eq!(remote.channel(2).unwrap(), None);
+    }
+
+    #[test]
+    fn remote_over_network() {
+        let server = ConnectionIp::new_server(None, Some("127.0.0.1:0".parse().unwrap())).unwrap();
+        let sender = server
+            .register_sender(StaticSenderName(b"Analog0"))
+            .unwrap();
+        let report_type = server
+            .register_type(StaticTypeName(b"vrpn_Analog Channel"))
+            .unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+
+        let client = ConnectionIp::new_reconnecting_client(None, None, addr);
+        let remote = Remote::new_from_name(StaticSenderName(b"Analog0"), Arc::clone(&client))
+            .expect("should be able to create remote");
+
+        let mut rt = Runtime::new().unwrap();
+        let state = rt
+            .block_on(
+                future::poll_fn(|| -> Poll<_, Error> {
+                    let _ = server.poll_endpoints()?;
+                    // Until the client has connected, this goes nowhere: so keep sending.
+                    let report = ChannelReport::new(vec![0.5, -1.0]);
+                    server.pack_message(
+                        Message::new(None, report_type, sender, report),
+                        ServiceFlags::RELIABLE.into(),
+                    )?;
+                    let _ = client.poll_endpoints()?;
+                    match remote.state()? {
+                        Some(state) => Ok(Async::Ready(state)),
+                        None => Ok(Async::NotReady),
+                    }
+                })
+                .timeout(Duration::from_secs(5)),
+            )
+            .expect("should have gotten a report");
+        assert_eq!(state.channels, vec![0.5, -1.0]);
+    }
+}
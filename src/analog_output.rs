@@ -0,0 +1,393 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::prelude::*;
+use crate::{
+    analog::CHANNEL_MAX,
+    handler::{HandlerCode, TypedHandler},
+    Buffer, BufferSize, BytesRequired, Connection, ConstantBufferSize, EmptyMessage, EmptyResult,
+    Error, LocalId, Message, MessageTypeIdentifier, Result, SenderId, SenderName, ServiceFlags,
+    StaticTypeName, TypedMessageBody, Unbuffer,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, Weak},
+};
+
+fn check_channel_count(count: usize) -> EmptyResult {
+    if count > CHANNEL_MAX {
+        return Err(Error::OtherMessage(format!(
+            "analog output message has {} channels, but the maximum is {}",
+            count, CHANNEL_MAX
+        )));
+    }
+    Ok(())
+}
+
+/// Client request to set the value of a single output channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeChannelRequest {
+    pub channel: i32,
+    pub value: f64,
+}
+
+impl TypedMessageBody for ChangeChannelRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Analog_Output Change_Channel_Request"),
+    );
+}
+
+impl ConstantBufferSize for ChangeChannelRequest {
+    fn constant_buffer_size() -> usize {
+        2 * i32::constant_buffer_size() + f64::constant_buffer_size()
+    }
+}
+
+impl Buffer for ChangeChannelRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.channel.buffer_ref(buf)?;
+        // padding
+        0_i32.buffer_ref(buf)?;
+        self.value.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for ChangeChannelRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let channel = i32::unbuffer_ref(buf)?;
+        let _ = i32::unbuffer_ref(buf)?;
+        let value = f64::unbuffer_ref(buf)?;
+        Ok(ChangeChannelRequest { channel, value })
+    }
+}
+
+/// Client request to set the values of the first `values.len()` output channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeChannelsRequest {
+    pub values: Vec<f64>,
+}
+
+impl ChangeChannelsRequest {
+    pub fn new(values: Vec<f64>) -> ChangeChannelsRequest {
+        ChangeChannelsRequest { values }
+    }
+}
+
+impl TypedMessageBody for ChangeChannelsRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Analog_Output Change_Channels_Request"),
+    );
+}
+
+impl BufferSize for ChangeChannelsRequest {
+    fn buffer_size(&self) -> usize {
+        2 * i32::constant_buffer_size() + f64::constant_buffer_size() * self.values.len()
+    }
+}
+
+impl Buffer for ChangeChannelsRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        check_channel_count(self.values.len())?;
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        (self.values.len() as i32).buffer_ref(buf)?;
+        // padding
+        0_i32.buffer_ref(buf)?;
+        for value in &self.values {
+            value.buffer_ref(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Unbuffer for ChangeChannelsRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let num_channels = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        let _ = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        if num_channels < 0 {
+            return Err(Error::OtherMessage(format!(
+                "analog output request claims {} channels",
+                num_channels
+            )));
+        }
+        let num_channels = num_channels as usize;
+        check_channel_count(num_channels)?;
+        let needed = num_channels * f64::constant_buffer_size();
+        if buf.len() < needed {
+            return Err(Error::NeedMoreData(BytesRequired::Exactly(
+                needed - buf.len(),
+            )));
+        }
+        let values = (0..num_channels)
+            .map(|_| f64::unbuffer_ref(buf))
+            .collect::<Result<Vec<f64>>>()?;
+        Ok(ChangeChannelsRequest { values })
+    }
+}
+
+/// Server report of how many output channels it has.
+///
+/// On the wire, the count is sent twice, the second copy serving as padding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumChannels {
+    pub num_channels: i32,
+}
+
+impl TypedMessageBody for NumChannels {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Analog_Output Num_Channels"));
+}
+
+impl ConstantBufferSize for NumChannels {
+    fn constant_buffer_size() -> usize {
+        2 * i32::constant_buffer_size()
+    }
+}
+
+impl Buffer for NumChannels {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.num_channels.buffer_ref(buf)?;
+        self.num_channels.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for NumChannels {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let num_channels = i32::unbuffer_ref(buf)?;
+        let _ = i32::unbuffer_ref(buf)?;
+        Ok(NumChannels { num_channels })
+    }
+}
+
+/// Server notice that a change request was refused, for instance because
+/// another client has control of the outputs.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RequestChannelsDenied;
+impl EmptyMessage for RequestChannelsDenied {}
+impl TypedMessageBody for RequestChannelsDenied {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Analog_Output Request_Channels_Denied"),
+    );
+}
+
+/// What a Remote has heard from the analog output server.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutputStatus {
+    /// Number of channels the server reported, if it has.
+    pub num_channels: Option<usize>,
+    /// Number of change requests the server has denied.
+    pub denied: usize,
+}
+
+/// Implemented by the messages that update the OutputStatus kept by Remote.
+trait StatusUpdate: TypedMessageBody + Unbuffer + Send + Sync {
+    fn update(&self, status: &mut OutputStatus);
+}
+
+impl StatusUpdate for NumChannels {
+    fn update(&self, status: &mut OutputStatus) {
+        status.num_channels = Some(self.num_channels.max(0) as usize);
+    }
+}
+
+impl StatusUpdate for RequestChannelsDenied {
+    fn update(&self, status: &mut OutputStatus) {
+        status.denied += 1;
+    }
+}
+
+struct StatusHandler<U: StatusUpdate> {
+    status: Weak<Mutex<OutputStatus>>,
+    phantom: PhantomData<U>,
+}
+
+impl<U: StatusUpdate> StatusHandler<U> {
+    fn new(status: &Arc<Mutex<OutputStatus>>) -> Box<StatusHandler<U>> {
+        Box::new(StatusHandler {
+            status: Arc::downgrade(status),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<U: StatusUpdate> fmt::Debug for StatusHandler<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StatusHandler").finish()
+    }
+}
+
+impl<U: StatusUpdate> TypedHandler for StatusHandler<U> {
+    type Item = U;
+    fn handle_typed(&mut self, msg: &Message<U>) -> Result<HandlerCode> {
+        match self.status.upgrade() {
+            Some(status) => {
+                let mut status = status.lock()?;
+                msg.body.update(&mut status);
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for an analog output device: sends channel changes to the server
+/// and keeps track of its channel count and any denied requests.
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    status: Arc<Mutex<OutputStatus>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let status = Arc::new(Mutex::new(OutputStatus::default()));
+        let _ = connection
+            .add_typed_handler(StatusHandler::<NumChannels>::new(&status), Some(sender))?;
+        let _ = connection.add_typed_handler(
+            StatusHandler::<RequestChannelsDenied>::new(&status),
+            Some(sender),
+        )?;
+        Ok(Remote {
+            connection,
+            sender,
+            status,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// The local sender ID of the device.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Get what the server has told us so far.
+    pub fn status(&self) -> Result<OutputStatus> {
+        Ok(self.status.lock()?.clone())
+    }
+
+    /// Number of channels the server reported, if it has.
+    pub fn num_channels(&self) -> Result<Option<usize>> {
+        Ok(self.status.lock()?.num_channels)
+    }
+
+    /// Ask the server to set one output channel to a value.
+    pub fn set_channel(&self, channel: usize, value: f64) -> Result<()> {
+        check_channel_count(channel.saturating_add(1))?;
+        self.connection.pack_message_body(
+            None,
+            self.sender,
+            ChangeChannelRequest {
+                channel: channel as i32,
+                value,
+            },
+            ServiceFlags::RELIABLE.into(),
+        )
+    }
+
+    /// Ask the server to set its first `values.len()` output channels.
+    pub fn set_channels(&self, values: &[f64]) -> Result<()> {
+        check_channel_count(values.len())?;
+        self.connection.pack_message_body(
+            None,
+            self.sender,
+            ChangeChannelsRequest::new(values.to_vec()),
+            ServiceFlags::RELIABLE.into(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        test_util::{dispatch, unconnected},
+        StaticSenderName,
+    };
+
+    #[test]
+    fn change_channel() {
+        let request = ChangeChannelRequest {
+            channel: 3,
+            value: 0.5,
+        };
+        let expected = hex!("00 00 00 03 00 00 00 00 3f e0 00 00 00 00 00 00");
+        assert_eq!(request.buffer_size(), expected.len());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(request.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(
+            ChangeChannelRequest::unbuffer_ref(&mut buf).unwrap(),
+            request
+        );
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn change_channels() {
+        let request = ChangeChannelsRequest::new(vec![0.5, -1.0]);
+        let expected =
+            hex!("00 00 00 02 00 00 00 00 3f e0 00 00 00 00 00 00 bf f0 00 00 00 00 00 00");
+        assert_eq!(request.buffer_size(), expected.len());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(request.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(
+            ChangeChannelsRequest::unbuffer_ref(&mut buf).unwrap(),
+            request
+        );
+        assert_eq!(buf.len(), 0);
+
+        let mut short = Bytes::from(&expected[..20]);
+        match ChangeChannelsRequest::unbuffer_ref(&mut short) {
+            Err(Error::NeedMoreData(BytesRequired::Exactly(n))) => assert_eq!(n, 4),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn num_channels() {
+        let report = NumChannels { num_channels: 4 };
+        let expected = hex!("00 00 00 04 00 00 00 04");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(report)
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(NumChannels::unbuffer_ref(&mut buf).unwrap(), report);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn remote() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Output0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+        assert_eq!(remote.num_channels().unwrap(), None);
+        assert!(remote.set_channel(CHANNEL_MAX, 1.0).is_err());
+        assert!(remote.set_channel(usize::MAX, 1.0).is_err());
+
+        dispatch(&conn, remote.sender(), NumChannels { num_channels: 4 });
+        assert_eq!(remote.num_channels().unwrap(), Some(4));
+
+        dispatch(&conn, remote.sender(), RequestChannelsDenied);
+        assert_eq!(remote.status().unwrap().denied, 1);
+    }
+}
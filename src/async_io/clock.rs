@@ -0,0 +1,140 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use crate::{
+    clock::{Client as RawClient, Config},
+    Connection, Error, Result,
+};
+use std::sync::Arc;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// Queries the server's clock regularly, keeping the offset and round-trip estimates fresh.
+///
+/// Read them with `client().estimate()`.
+pub struct Client<T: Connection + 'static> {
+    client: RawClient<T>,
+    interval: Interval,
+}
+
+impl<T: Connection + 'static> Client<T> {
+    pub fn new(connection: Arc<T>) -> Result<Client<T>> {
+        Client::with_config(connection, Config::default())
+    }
+
+    pub fn with_config(connection: Arc<T>, config: Config) -> Result<Client<T>> {
+        Ok(Client {
+            client: RawClient::with_config(connection, config)?,
+            interval: Interval::new_interval(config.interval),
+        })
+    }
+
+    /// The underlying client: for the estimates.
+    pub fn client(&self) -> &RawClient<T> {
+        &self.client
+    }
+}
+
+impl<T: Connection + 'static> Stream for Client<T> {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let _ = try_ready!(self
+            .interval
+            .poll()
+            .map_err(|e| Error::OtherMessage(e.to_string())));
+        self.client.send_query()?;
+        Ok(Async::Ready(Some(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{async_io::ConnectionIp, clock::Server};
+    use std::time::{Duration, Instant};
+    use tokio::{runtime::current_thread::Runtime, timer::Delay};
+
+    #[test]
+    fn estimate_local_clock() {
+        let server = ConnectionIp::new_server(None, Some("127.0.0.1:0".parse().unwrap())).unwrap();
+        let _clock_server = Server::new(Arc::clone(&server)).unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+
+        let client = ConnectionIp::new_reconnecting_client(None, None, addr);
+        let config = Config {
+            interval: Duration::from_millis(50),
+            adjust_incoming: true,
+            ..Config::default()
+        };
+        let mut clock = Client::with_config(Arc::clone(&client), config).unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        let estimate = rt
+            .block_on(
+                future::poll_fn(|| -> Poll<_, Error> {
+                    let _ = server.poll_endpoints()?;
+                    let _ = client.poll_endpoints()?;
+                    while let Async::Ready(Some(())) = clock.poll()? {}
+                    match clock.client().estimate()? {
+                        Some(estimate) => Ok(Async::Ready(estimate)),
+                        None => Ok(Async::NotReady),
+                    }
+                })
+                .timeout(Duration::from_secs(5)),
+            )
+            .expect("should have gotten an answer");
+
+        // Same clock on both ends: the offset is at most the uncertainty from the round trip.
+        let round_trip = estimate.round_trip.as_micros() as i64;
+        assert!(estimate.offset.0.abs() <= round_trip / 2 + 1);
+        let adjustment = client
+            .dispatcher()
+            .lock()
+            .unwrap()
+            .incoming_time_adjustment();
+        assert_eq!(adjustment.offset(), estimate.offset);
+    }
+
+    #[test]
+    fn ignore_replies_to_other_clients() {
+        let server = ConnectionIp::new_server(None, Some("127.0.0.1:0".parse().unwrap())).unwrap();
+        let _clock_server = Server::new(Arc::clone(&server)).unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+
+        // Only one of the clients asks: the server's replies go to both.
+        let asking = ConnectionIp::new_reconnecting_client(None, None, addr);
+        let config = Config {
+            interval: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let mut asking_clock = Client::with_config(Arc::clone(&asking), config).unwrap();
+        let listening = ConnectionIp::new_reconnecting_client(None, None, addr);
+        let listening_clock = RawClient::new(Arc::clone(&listening)).unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        let mut settle: Option<Delay> = None;
+        rt.block_on(
+            future::poll_fn(|| -> Poll<_, Error> {
+                let _ = server.poll_endpoints()?;
+                let _ = asking.poll_endpoints()?;
+                let _ = listening.poll_endpoints()?;
+                while let Async::Ready(Some(())) = asking_clock.poll()? {}
+                if settle.is_none() && asking_clock.client().estimate()?.is_some() {
+                    // Give the other client time to get the same replies.
+                    settle = Some(Delay::new(Instant::now() + Duration::from_millis(200)));
+                }
+                match &mut settle {
+                    Some(delay) => delay.poll().map_err(|e| Error::OtherMessage(e.to_string())),
+                    None => Ok(Async::NotReady),
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("should have gotten an answer");
+
+        assert!(asking_clock.client().estimate().unwrap().is_some());
+        assert_eq!(listening_clock.estimate().unwrap(), None);
+    }
+}
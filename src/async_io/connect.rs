@@ -3,11 +3,29 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use crate::{
-    async_io::cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie},
-    Error,
+    async_io::{
+        codec::FramedMessageCodec,
+        cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie},
+        endpoint_ip::MessageFramedUdp,
+    },
+    descriptions::LobPacket,
+    prelude::*,
+    Error, LogFileNames, LogMode, Message, Result, SequenceNumber,
 };
-use std::net::SocketAddr;
-use tokio::{io, net::TcpStream, prelude::*};
+use bytes::BytesMut;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io,
+    net::{tcp::Incoming, TcpListener, TcpStream, UdpFramed, UdpSocket},
+    prelude::*,
+    timer::Interval,
+};
+
+/// How often a client re-sends its lob packet while waiting for the server to connect back.
+const LOB_PACKET_INTERVAL: Duration = Duration::from_secs(1);
 
 pub fn make_tcp_socket(addr: SocketAddr) -> io::Result<std::net::TcpStream> {
     use socket2::*;
@@ -33,7 +51,18 @@ pub fn make_tcp_socket(addr: SocketAddr) -> io::Result<std::net::TcpStream> {
     Ok(sock.into_tcp_stream())
 }
 
-fn outgoing_tcp_connect(
+/// Open a UDP socket to serve as the low-latency channel alongside a reliable (TCP) stream.
+///
+/// The socket is bound to the same local address as the stream, on an arbitrary port,
+/// so the address we describe to the other end is one it can reach.
+pub fn make_udp_channel(reliable_stream: &TcpStream) -> Result<MessageFramedUdp> {
+    let addr = SocketAddr::new(reliable_stream.local_addr()?.ip(), 0);
+    let sock = UdpSocket::bind(&addr)?;
+    // A datagram may hold more than one message.
+    Ok(UdpFramed::with_decode(sock, FramedMessageCodec, true))
+}
+
+pub(crate) fn outgoing_tcp_connect(
     addr: std::net::SocketAddr,
 ) -> impl Future<Item = tokio::net::TcpStream, Error = Error> {
     make_tcp_socket(addr)
@@ -50,28 +79,164 @@ pub fn outgoing_handshake<T>(socket: T) -> impl Future<Item = T, Error = Error>
 where
     T: AsyncRead + AsyncWrite,
 {
-    send_nonfile_cookie(socket).and_then(read_and_check_nonfile_cookie)
-    // TODO can pack log description here if we're enabling remote logging.
-    // TODO if we have permission to use UDP, open an incoming socket and notify the other end about it here.
+    outgoing_handshake_log_mode(socket, LogMode::none()).map(|(socket, _)| socket)
+}
+
+/// Handshake for a connection we initiated, asking the other end to log it in the given mode.
+///
+/// Future resolves to the stream and the logging mode the other end asked for.
+pub(crate) fn outgoing_handshake_log_mode<T>(
+    socket: T,
+    log_mode: LogMode,
+) -> impl Future<Item = (T, LogMode), Error = Error>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    send_nonfile_cookie(socket, log_mode).and_then(read_and_check_nonfile_cookie)
+}
+
+/// Handshake for a connection we initiated, then ask the other end to log it to the named files.
+pub fn outgoing_handshake_with_remote_logging<T>(
+    socket: T,
+    remote_log_names: LogFileNames,
+) -> impl Future<Item = T, Error = Error>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    outgoing_handshake_log_mode(socket, remote_log_names.log_mode())
+        .and_then(move |(socket, _)| send_log_description(socket, remote_log_names))
+}
+
+/// Write a log description as the first message on a newly-handshaken stream,
+/// if there's any logging to ask for.
+fn send_log_description<T>(socket: T, names: LogFileNames) -> impl Future<Item = T, Error = Error>
+where
+    T: AsyncWrite,
+{
+    if names.log_mode().is_none() {
+        return future::Either::A(future::ok(socket));
+    }
+    let msg = Message::from(names)
+        .try_into_generic()
+        .map(|msg| msg.into_sequenced_message(SequenceNumber(0)))
+        .and_then(|msg| BytesMut::new().allocate_and_buffer(msg));
+    future::Either::B(msg.into_future().and_then(|buf| {
+        io::write_all(socket, buf.freeze())
+            .map(|(socket, _)| socket)
+            .from_err()
+    }))
 }
 
 pub fn connect_tcp(
     addr: std::net::SocketAddr,
 ) -> impl Future<Item = tokio::net::TcpStream, Error = Error> {
     outgoing_tcp_connect(addr).and_then(outgoing_handshake)
-    // TODO can pack log description here if we're enabling remote logging.
-    // TODO if we have permission to use UDP, open an incoming socket and notify the other end about it here.
+}
+
+/// Connect to a server over TCP, asking it to log the connection to the named files.
+pub fn connect_tcp_with_remote_logging(
+    addr: std::net::SocketAddr,
+    remote_log_names: LogFileNames,
+) -> impl Future<Item = tokio::net::TcpStream, Error = Error> {
+    outgoing_tcp_connect(addr)
+        .and_then(move |stream| outgoing_handshake_with_remote_logging(stream, remote_log_names))
+}
+
+/// Future for the client side of UDP+TCP connection establishment:
+/// repeatedly "lobs" a packet at the server's UDP port naming a TCP port we listen on,
+/// until the server connects back to it.
+#[derive(Debug)]
+struct LobPacketConnect {
+    incoming: Incoming,
+    udp: std::net::UdpSocket,
+    packet: BytesMut,
+    interval: Interval,
+}
+
+impl LobPacketConnect {
+    fn new(server: SocketAddr) -> Result<LobPacketConnect> {
+        // Find the address the server can reach us at by letting the OS pick a route to it.
+        let unspecified = if server.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let udp = std::net::UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+        udp.connect(server)?;
+        udp.set_nonblocking(true)?;
+        let local_ip = udp.local_addr()?.ip();
+
+        let listener = TcpListener::bind(&SocketAddr::new(local_ip, 0))?;
+        let packet = BytesMut::new().allocate_and_buffer(LobPacket::new(listener.local_addr()?))?;
+        Ok(LobPacketConnect {
+            incoming: listener.incoming(),
+            udp,
+            packet,
+            interval: Interval::new(Instant::now(), LOB_PACKET_INTERVAL),
+        })
+    }
+}
+
+impl Future for LobPacketConnect {
+    type Item = TcpStream;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while self
+            .interval
+            .poll()
+            .map_err(|e| Error::OtherMessage(e.to_string()))?
+            .is_ready()
+        {
+            match self.udp.send(&self.packet) {
+                Ok(_) => (),
+                // Not fatal: we'll try again next time.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+        match try_ready!(self.incoming.poll()) {
+            Some(stream) => {
+                stream.set_nodelay(true)?;
+                Ok(Async::Ready(stream))
+            }
+            None => Err(Error::OtherMessage(String::from(
+                "listener closed before the server connected back",
+            ))),
+        }
+    }
+}
+
+/// Connect to a server in UDP+TCP mode: send a lob packet to its UDP port,
+/// then accept and handshake the TCP connection it makes back to us.
+pub fn connect_lob_packet(
+    server: std::net::SocketAddr,
+) -> impl Future<Item = tokio::net::TcpStream, Error = Error> {
+    LobPacketConnect::new(server)
+        .into_future()
+        .flatten()
+        .and_then(incoming_handshake)
 }
 
 pub fn incoming_handshake<T>(socket: T) -> impl Future<Item = T, Error = Error>
 where
     T: AsyncRead + AsyncWrite,
 {
-    // If connection is incoming
-    read_and_check_nonfile_cookie(socket).and_then(send_nonfile_cookie)
+    incoming_handshake_log_mode(socket, LogMode::none()).map(|(socket, _)| socket)
+}
 
-    // TODO can pack log description here if we're enabling remote logging.
-    // TODO should send descriptions here.
+/// Handshake for a connection the other end initiated, asking it to log it in the given mode.
+///
+/// Future resolves to the stream and the logging mode the other end asked for.
+pub(crate) fn incoming_handshake_log_mode<T>(
+    socket: T,
+    log_mode: LogMode,
+) -> impl Future<Item = (T, LogMode), Error = Error>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    read_and_check_nonfile_cookie(socket).and_then(move |(socket, their_mode)| {
+        send_nonfile_cookie(socket, log_mode).map(move |socket| (socket, their_mode))
+    })
 }
 
 #[cfg(test)]
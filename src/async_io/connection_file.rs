@@ -2,19 +2,391 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
+pub use crate::async_io::endpoint_file::ReplayRate;
 use crate::{
-    async_io::endpoint_ip::EndpointIp,
-    descriptions::InnerDescription,
-    type_dispatcher::{HandlerHandle, RegisterMapping},
-    BaseTypeSafeId, Error, Handler, LocalId, LogFileNames, MatchingTable, Message,
-    MessageTypeIdentifier, Result, SenderId, SenderName, StaticSenderName, StaticTypeName,
-    TranslationTables, TypeDispatcher, TypeId, TypeName, TypedHandler, TypedMessageBody,
-};
-use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{Arc, Mutex},
-};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    prelude::*,
+    async_io::endpoint_file::{EndpointFile, FileControlIds},
+    connection::*,
+    file_controller::{PlayToTime, Reset, SetReplayRate, FILE_CONTROLLER_SENDER},
+    Error, LocalId, MessageTypeIdentifier, Result, TimeVal, TypeId, TypedMessageBody,
 };
+use std::{fs, path::Path, sync::Arc};
+use tokio::prelude::*;
+
+/// A connection that plays back a log file, dispatching the recorded messages
+/// to handlers just as if they had come from a live connection.
+#[derive(Debug)]
+pub struct ConnectionFile {
+    core: ConnectionCore<EndpointFile>,
+}
+
+impl ConnectionFile {
+    /// Open a log file for playback at the given rate.
+    ///
+    /// Playback can also be controlled by messages from a `file_controller::Controller`
+    /// on this connection.
+    pub fn new(path: impl AsRef<Path>, rate: ReplayRate) -> Result<Arc<ConnectionFile>> {
+        let mut endpoint = EndpointFile::new(fs::File::open(path)?)?;
+        endpoint.set_replay_rate(rate);
+        let conn = Arc::new(ConnectionFile {
+            core: ConnectionCore::new(vec![Some(endpoint)], None, None),
+        });
+        let control = FileControlIds {
+            sender: conn.register_sender(FILE_CONTROLLER_SENDER)?,
+            set_replay_rate: conn.register_message_type::<SetReplayRate>()?,
+            reset: conn.register_message_type::<Reset>()?,
+            play_to_time: conn.register_message_type::<PlayToTime>()?,
+        };
+        conn.with_endpoint(|ep| ep.set_control_ids(control))?;
+        Ok(conn)
+    }
+
+    fn register_message_type<T: TypedMessageBody>(&self) -> Result<LocalId<TypeId>> {
+        match T::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => self.register_type(name),
+            MessageTypeIdentifier::SystemMessageId(id) => Ok(LocalId(id)),
+        }
+    }
+
+    fn with_endpoint<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut EndpointFile) -> R,
+    {
+        let endpoints = self.endpoints();
+        let mut endpoints = endpoints.lock()?;
+        match endpoints.iter_mut().flatten().next() {
+            Some(ep) => Ok(f(ep)),
+            None => Err(Error::OtherMessage(String::from(
+                "file connection has no endpoint",
+            ))),
+        }
+    }
+
+    pub fn replay_rate(&self) -> Result<ReplayRate> {
+        self.with_endpoint(|ep| ep.replay_rate())
+    }
+
+    /// Change the playback rate, continuing from the current playback time.
+    pub fn set_replay_rate(&self, rate: ReplayRate) -> Result<()> {
+        self.with_endpoint(|ep| ep.set_replay_rate(rate))
+    }
+
+    /// The file time we have played back to, if playback has started.
+    pub fn playback_time(&self) -> Result<Option<TimeVal>> {
+        self.with_endpoint(|ep| ep.playback_time())
+    }
+
+    /// The time of the first message in the file, if any.
+    pub fn start_time(&self) -> Result<Option<TimeVal>> {
+        self.with_endpoint(|ep| ep.start_time())
+    }
+
+    /// The time of the last message in the file, if any.
+    pub fn end_time(&self) -> Result<Option<TimeVal>> {
+        self.with_endpoint(|ep| ep.end_time())
+    }
+
+    /// Continue playback from the given file time, skipping the messages in between.
+    pub fn jump_to_time(&self, time: TimeVal) -> Result<()> {
+        self.with_endpoint(|ep| ep.jump_to_time(time))?
+    }
+
+    /// Start playback again from the beginning of the file.
+    pub fn rewind(&self) -> Result<()> {
+        self.with_endpoint(|ep| ep.rewind())?
+    }
+
+    /// Immediately play every message up to the given file time, whatever the rate,
+    /// then continue at the current rate from there.
+    pub fn play_to_time(&self, time: TimeVal) -> Result<()> {
+        self.with_endpoint(|ep| ep.play_to_time(time))
+    }
+
+    /// Play back any messages that are due.
+    ///
+    /// Resolves to `None` once the whole file has been played.
+    pub fn poll_endpoints(&self) -> Poll<Option<()>, Error> {
+        let result = self.poll_playback();
+        // Now nothing's locked, send whatever handlers replied.
+        self.pack_deferred_messages()?;
+        result
+    }
+
+    fn poll_playback(&self) -> Poll<Option<()>, Error> {
+        let endpoints = self.endpoints();
+        let dispatcher = self.dispatcher();
+        let mut endpoints = endpoints.lock()?;
+        let mut dispatcher = dispatcher.lock()?;
+        match endpoints.iter_mut().flatten().next() {
+            Some(ep) => ep.poll_playback(&mut dispatcher),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl Connection for ConnectionFile {
+    type SpecificEndpoint = EndpointFile;
+    fn connection_core(&self) -> &ConnectionCore<Self::SpecificEndpoint> {
+        &self.core
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionFileStream {
+    connection: Arc<ConnectionFile>,
+}
+
+impl ConnectionFileStream {
+    pub fn new(connection: Arc<ConnectionFile>) -> ConnectionFileStream {
+        ConnectionFileStream { connection }
+    }
+}
+
+impl Stream for ConnectionFileStream {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.connection.poll_endpoints()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::{
+        descriptions::InnerDescription,
+        handler::{HandlerCode, TypedHandler},
+        time::{Microseconds, Seconds},
+        tracker::PoseReport,
+        Buffer, Description, LogFileWriter, Message, Quat, SenderId, Sensor, StaticTypeName,
+        TypeId, Vec3,
+    };
+    use std::{
+        env,
+        path::PathBuf,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    #[derive(Debug)]
+    struct CountingHandler {
+        count: Arc<Mutex<usize>>,
+    }
+    impl TypedHandler for CountingHandler {
+        type Item = PoseReport;
+        fn handle_typed(&mut self, _msg: &Message<PoseReport>) -> Result<HandlerCode> {
+            *self.count.lock()? += 1;
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    const NUM_REPORTS: usize = 5;
+    const REPORT_SPACING_USEC: i32 = 50_000;
+
+    fn append_entry<T>(writer: &mut LogFileWriter, time: TimeVal, mut msg: Message<T>)
+    where
+        T: crate::TypedMessageBody + Buffer,
+    {
+        msg.header.time = time;
+        writer
+            .write_message(&msg.try_into_generic().unwrap())
+            .expect("should be able to write log entry");
+    }
+
+    /// The recorded time of a tracker report in our log file.
+    fn report_time(i: usize) -> TimeVal {
+        let usec = REPORT_SPACING_USEC * i as i32;
+        TimeVal::new(
+            Seconds(1_500_000_000 + usec / 1_000_000),
+            Microseconds(usec % 1_000_000),
+        )
+    }
+
+    /// Write a log file with descriptions and a few tracker reports, spaced 50ms apart.
+    fn write_log_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("vrpn-rs-{}-{}.vrpn", name, std::process::id()));
+        let mut writer = LogFileWriter::create(&path).expect("should be able to create log file");
+        let start = report_time(0);
+        append_entry(
+            &mut writer,
+            start,
+            Message::<InnerDescription<SenderId>>::from(Description::new(
+                SenderId(0),
+                Bytes::from(&b"Tracker0"[..]),
+            )),
+        );
+        append_entry(
+            &mut writer,
+            start,
+            Message::<InnerDescription<TypeId>>::from(Description::new(
+                TypeId(0),
+                Bytes::from(&b"vrpn_Tracker Pos_Quat"[..]),
+            )),
+        );
+        for i in 0..NUM_REPORTS {
+            append_entry(
+                &mut writer,
+                report_time(i),
+                Message::new(
+                    None,
+                    TypeId(0),
+                    SenderId(0),
+                    PoseReport {
+                        sensor: Sensor(0),
+                        pos: Vec3::new(i as f64, 0.0, 0.0),
+                        quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                    },
+                ),
+            );
+        }
+        writer.flush().expect("should be able to write log file");
+        path
+    }
+
+    fn add_counter(conn: &Arc<ConnectionFile>) -> Arc<Mutex<usize>> {
+        let count = Arc::new(Mutex::new(0));
+        let sender = conn
+            .register_sender(crate::StaticSenderName(b"Tracker0"))
+            .unwrap();
+        let _ = conn.register_type(StaticTypeName(b"vrpn_Tracker Pos_Quat"));
+        conn.add_typed_handler(
+            Box::new(CountingHandler {
+                count: Arc::clone(&count),
+            }),
+            Some(sender),
+        )
+        .unwrap();
+        count
+    }
+
+    #[test]
+    fn play_as_fast_as_possible() {
+        let path = write_log_file("fast");
+        let conn = ConnectionFile::new(&path, ReplayRate::AsFastAsPossible).unwrap();
+        let count = add_counter(&conn);
+        // Everything plays without ever having to wait.
+        assert_eq!(play_available(&conn, &count), NUM_REPORTS);
+        assert!(conn.playback_time().unwrap().is_some());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn play_scaled() {
+        let path = write_log_file("scaled");
+        let conn = ConnectionFile::new(&path, ReplayRate::Scaled(2.0)).unwrap();
+        let count = add_counter(&conn);
+        let start = Instant::now();
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(ConnectionFileStream::new(Arc::clone(&conn)).for_each(|()| Ok(())))
+            .unwrap();
+        // 4 gaps of 50ms, at twice real time.
+        assert!(start.elapsed() >= Duration::from_millis(95));
+        assert_eq!(*count.lock().unwrap(), NUM_REPORTS);
+        assert_eq!(conn.replay_rate().unwrap(), ReplayRate::Scaled(2.0));
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Poll until playback has to wait, returning how many reports were played.
+    fn play_available(conn: &Arc<ConnectionFile>, count: &Mutex<usize>) -> usize {
+        future::lazy(|| {
+            while let Async::Ready(Some(())) = conn.poll_endpoints()? {}
+            Ok::<(), Error>(())
+        })
+        .wait()
+        .unwrap();
+        *count.lock().unwrap()
+    }
+
+    #[test]
+    fn play_very_slowly() {
+        let path = write_log_file("slow");
+        let conn = ConnectionFile::new(&path, ReplayRate::Scaled(1e-30)).unwrap();
+        let count = add_counter(&conn);
+        // Only the first report is due in any time we can represent.
+        assert_eq!(play_available(&conn, &count), 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn controller_extreme_rates() {
+        use crate::file_controller::Controller;
+
+        let path = write_log_file("extreme");
+        let conn = ConnectionFile::new(&path, ReplayRate::RealTime).unwrap();
+        let count = add_counter(&conn);
+        let controller = Controller::new(Arc::clone(&conn)).unwrap();
+
+        assert!(controller.set_replay_rate(f32::INFINITY).is_err());
+        assert!(controller.set_replay_rate(f32::NAN).is_err());
+        assert_eq!(conn.replay_rate().unwrap(), ReplayRate::RealTime);
+
+        controller.set_replay_rate(f32::MAX).unwrap();
+        match conn.replay_rate().unwrap() {
+            ReplayRate::Scaled(rate) => assert!(rate < f64::from(f32::MAX)),
+            other => panic!("unexpected rate {:?}", other),
+        }
+        assert_eq!(play_available(&conn, &count), NUM_REPORTS);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(conn.playback_time().unwrap().is_some());
+        let _ = fs::remove_file(&path);
+    }
+
+    fn play_all(conn: &Arc<ConnectionFile>) {
+        ConnectionFileStream::new(Arc::clone(conn))
+            .collect()
+            .wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn seek_and_rewind() {
+        let path = write_log_file("seek");
+        let conn = ConnectionFile::new(&path, ReplayRate::AsFastAsPossible).unwrap();
+        let count = add_counter(&conn);
+        assert_eq!(conn.start_time().unwrap(), Some(report_time(0)));
+        assert_eq!(conn.end_time().unwrap(), Some(report_time(NUM_REPORTS - 1)));
+
+        // Skipping the descriptions and the first two reports.
+        conn.jump_to_time(report_time(2)).unwrap();
+        assert_eq!(conn.playback_time().unwrap(), Some(report_time(2)));
+        play_all(&conn);
+        assert_eq!(*count.lock().unwrap(), NUM_REPORTS - 2);
+
+        conn.rewind().unwrap();
+        play_all(&conn);
+        assert_eq!(*count.lock().unwrap(), 2 * NUM_REPORTS - 2);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn controller() {
+        use crate::file_controller::Controller;
+
+        let path = write_log_file("controller");
+        let conn = ConnectionFile::new(&path, ReplayRate::RealTime).unwrap();
+        let count = add_counter(&conn);
+        let controller = Controller::new(Arc::clone(&conn)).unwrap();
+
+        controller.set_replay_rate(0.0).unwrap();
+        assert_eq!(conn.replay_rate().unwrap(), ReplayRate::Scaled(0.0));
+        controller.play_to_time(report_time(1)).unwrap();
+        future::lazy(|| {
+            assert_eq!(conn.poll_endpoints()?, Async::Ready(Some(())));
+            // Paused once we've caught up.
+            assert_eq!(conn.poll_endpoints()?, Async::NotReady);
+            Ok::<(), Error>(())
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(*count.lock().unwrap(), 2);
+        assert_eq!(conn.playback_time().unwrap(), Some(report_time(1)));
+
+        controller.reset().unwrap();
+        assert_eq!(conn.playback_time().unwrap(), None);
+        conn.set_replay_rate(ReplayRate::AsFastAsPossible).unwrap();
+        play_all(&conn);
+        assert_eq!(*count.lock().unwrap(), 2 + NUM_REPORTS);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -2,87 +2,285 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
+use bytes::Bytes;
 use crate::{
-    async_io::{connect::incoming_handshake, endpoint_ip::EndpointIp},
+    async_io::{
+        connect::{
+            connect_tcp, incoming_handshake_log_mode, make_udp_channel,
+            outgoing_handshake_log_mode, outgoing_tcp_connect,
+        },
+        endpoint_ip::{EndpointIp, MessageFramedUdp},
+    },
     connection::*,
-    Error, LogFileNames, Result, TypeSafeId,
+    constants,
+    descriptions::LobPacket,
+    endpoint::Endpoint,
+    Error, LogFileNames, LogMode, Message, Result, ServiceFlags, TypeSafeId, Unbuffer,
 };
+use futures::task::AtomicTask;
 use std::{
+    fmt,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{Arc, Mutex, Weak},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
 };
 use tokio::{
-    net::{tcp::Incoming, TcpListener, TcpStream},
+    net::{tcp::Incoming, TcpListener, TcpStream, UdpSocket},
     prelude::*,
+    timer::Delay,
 };
 
 #[derive(Debug)]
 pub struct ConnectionIp {
     core: ConnectionCore<EndpointIp>,
-    // server_tcp: Option<Mutex<TcpListener>>,
     server_acceptor: Arc<Mutex<Option<ConnectionIpAcceptor>>>,
+    endpoint_added: AtomicTask,
+    endpoints_ever_added: AtomicUsize,
+    reconnect: Mutex<Option<Reconnect>>,
+}
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(8);
+
+/// Where a reconnecting client is in getting its connection back.
+enum ReconnectState {
+    /// Connected, or about to try again.
+    Idle,
+    Connecting(Box<dyn Future<Item = TcpStream, Error = Error> + Send>),
+    /// Backing off after a failed attempt.
+    Waiting(Delay),
+}
+
+struct Reconnect {
+    server: SocketAddr,
+    state: ReconnectState,
+    delay: Duration,
+}
+
+impl fmt::Debug for Reconnect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reconnect")
+            .field("server", &self.server)
+            .field("delay", &self.delay)
+            .finish()
+    }
 }
-const DEFAULT_PORT: u16 = 3883;
 
 impl ConnectionIp {
-    /// Create a new ConnectionIp that is a server.
+    /// Create a new ConnectionIp that is a server, listening on the given address
+    /// (or all interfaces on the default port if `None`).
+    ///
+    /// New connections are accepted as part of `poll_endpoints`.
     pub fn new_server(
         local_log_names: Option<LogFileNames>,
-        _addr: Option<SocketAddr>,
+        addr: Option<SocketAddr>,
     ) -> Result<Arc<ConnectionIp>> {
         let conn = Arc::new(ConnectionIp {
             core: ConnectionCore::new(Vec::new(), local_log_names, None),
             server_acceptor: Arc::new(Mutex::new(None)),
-            // server_tcp: Some(Mutex::new(server_tcp)),
+            endpoint_added: AtomicTask::new(),
+            endpoints_ever_added: AtomicUsize::new(0),
+            reconnect: Mutex::new(None),
         });
-        // {
-        //     let accepter = ConnectionIpAcceptor::new(Arc::downgrade(&conn), addr)?;
-        //     let mut locked_acceptor = conn.server_acceptor.lock()?;
-        //     *locked_acceptor = Some(accepter);
-        // }
+        {
+            let acceptor = ConnectionIpAcceptor::new(Arc::downgrade(&conn), addr)?;
+            let mut locked_acceptor = conn.server_acceptor.lock()?;
+            *locked_acceptor = Some(acceptor);
+        }
         Ok(conn)
     }
 
+    /// The address a server is listening on, or `None` for a client.
+    pub fn server_addr(&self) -> Result<Option<SocketAddr>> {
+        let acceptor = self.server_acceptor.lock()?;
+        Ok(acceptor.as_ref().map(ConnectionIpAcceptor::local_addr))
+    }
+
     /// Create a new ConnectionIp that is a client.
+    ///
+    /// Pass a low-latency channel (see `make_udp_channel`) to use TCP+UDP mode,
+    /// or `None` to send everything over TCP.
+    ///
+    /// If remote log names are given, the server is asked to log this connection to them.
+    /// (Alternately, pass them to `connect_tcp_with_remote_logging` to ask during the handshake.)
     pub fn new_client(
         local_log_names: Option<LogFileNames>,
         remote_log_names: Option<LogFileNames>,
         reliable_channel: TcpStream,
-        // low_latency_channel: Option<MessageFramedUdp>,
+        low_latency_channel: Option<MessageFramedUdp>,
     ) -> Result<Arc<ConnectionIp>> {
         let mut endpoints: Vec<Option<EndpointIp>> = Vec::new();
-        endpoints.push(Some(EndpointIp::new(reliable_channel)));
-        Ok(Arc::new(ConnectionIp {
+        endpoints.push(Some(EndpointIp::new(
+            reliable_channel,
+            low_latency_channel,
+        )?));
+        let conn = Arc::new(ConnectionIp {
             core: ConnectionCore::new(endpoints, local_log_names, remote_log_names),
             server_acceptor: Arc::new(Mutex::new(None)),
-        }))
+            endpoint_added: AtomicTask::new(),
+            endpoints_ever_added: AtomicUsize::new(0),
+            reconnect: Mutex::new(None),
+        });
+        {
+            let endpoints = conn.endpoints();
+            let mut endpoints = endpoints.lock()?;
+            for ep in endpoints.iter_mut().flatten() {
+                conn.start_local_logging(ep)?;
+                conn.request_remote_logging(ep)?;
+            }
+        }
+        Ok(conn)
+    }
+
+    /// Create a new ConnectionIp that is a client of the server at the given address,
+    /// using TCP+UDP mode, and that connects again whenever it loses that connection.
+    ///
+    /// The first attempt is made when it is first polled. After a failed attempt,
+    /// it waits before trying again, backing off up to a few seconds between tries.
+    /// Registered senders, types and handlers are all kept, and described to the server
+    /// again on each new connection, so handlers just start getting messages again.
+    pub fn new_reconnecting_client(
+        local_log_names: Option<LogFileNames>,
+        remote_log_names: Option<LogFileNames>,
+        server: SocketAddr,
+    ) -> Arc<ConnectionIp> {
+        Arc::new(ConnectionIp {
+            core: ConnectionCore::new(Vec::new(), local_log_names, remote_log_names),
+            server_acceptor: Arc::new(Mutex::new(None)),
+            endpoint_added: AtomicTask::new(),
+            endpoints_ever_added: AtomicUsize::new(0),
+            reconnect: Mutex::new(Some(Reconnect {
+                server,
+                state: ReconnectState::Idle,
+                delay: INITIAL_RECONNECT_DELAY,
+            })),
+        })
+    }
+
+    /// Create a new ConnectionIp that never has any endpoints, and so opens no sockets:
+    /// for testing handlers by calling the dispatcher directly.
+    #[cfg(test)]
+    pub(crate) fn new_unconnected() -> Arc<ConnectionIp> {
+        Arc::new(ConnectionIp {
+            core: ConnectionCore::new(Vec::new(), None, None),
+            server_acceptor: Arc::new(Mutex::new(None)),
+            endpoint_added: AtomicTask::new(),
+            endpoints_ever_added: AtomicUsize::new(0),
+            reconnect: Mutex::new(None),
+        })
+    }
+
+    /// Ask the other end of an endpoint to log it, if this connection has remote log files.
+    fn request_remote_logging(&self, ep: &mut EndpointIp) -> Result<()> {
+        let names = self.core.remote_log_names();
+        if names.log_mode().is_none() {
+            return Ok(());
+        }
+        let msg = Message::from(names.clone()).try_into_generic()?;
+        ep.buffer_generic_message(msg, ServiceFlags::RELIABLE.into())
+    }
+
+    /// Start logging a new endpoint's messages, if this connection has local log files.
+    ///
+    /// Each endpoint gets its own files, even if earlier ones have since been closed.
+    fn start_local_logging(&self, ep: &mut EndpointIp) -> Result<()> {
+        let index = self.endpoints_ever_added.fetch_add(1, Ordering::SeqCst);
+        let names = self.core.local_log_names_for_endpoint(index);
+        if names.log_mode().is_none() {
+            return Ok(());
+        }
+        let dispatcher = self.dispatcher();
+        let dispatcher = dispatcher.lock()?;
+        ep.start_logging(&names, &dispatcher)
+    }
+
+    /// Start using a new endpoint: set up its logs, describe everything registered to it,
+    /// and get it announced and serviced by the next `poll_endpoints`.
+    fn push_endpoint(&self, mut ep: EndpointIp) -> Result<()> {
+        let endpoints = self.endpoints();
+        let mut endpoints = endpoints.lock()?;
+        self.start_local_logging(&mut ep)?;
+
+        let dispatcher = self.dispatcher();
+        let dispatcher = dispatcher.lock()?;
+        ep.pack_all_descriptions(&dispatcher)?;
+        endpoints.push(Some(ep));
+        self.endpoint_added.notify();
+        Ok(())
+    }
+
+    /// For a reconnecting client, work on getting connected if we aren't.
+    fn poll_reconnect(&self) -> Result<()> {
+        let mut reconnect = self.reconnect.lock()?;
+        let reconnect = match &mut *reconnect {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        loop {
+            let next_state = match &mut reconnect.state {
+                ReconnectState::Idle => {
+                    if !self.endpoints().lock()?.is_empty() {
+                        return Ok(());
+                    }
+                    ReconnectState::Connecting(Box::new(connect_tcp(reconnect.server)))
+                }
+                ReconnectState::Connecting(connecting) => {
+                    let connected = match connecting.poll() {
+                        Ok(Async::NotReady) => return Ok(()),
+                        Ok(Async::Ready(stream)) => {
+                            make_udp_channel(&stream).map(|channel| (stream, channel))
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match connected {
+                        Ok((stream, low_latency_channel)) => {
+                            // A brand new endpoint, so its translation tables start out empty
+                            // and get filled in by the server's descriptions.
+                            let mut ep = EndpointIp::new(stream, Some(low_latency_channel))?;
+                            self.request_remote_logging(&mut ep)?;
+                            self.push_endpoint(ep)?;
+                            reconnect.delay = INITIAL_RECONNECT_DELAY;
+                            ReconnectState::Idle
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "Could not connect to {}, retrying in {:?}: {}",
+                                reconnect.server, reconnect.delay, e
+                            );
+                            let waiting = Delay::new(Instant::now() + reconnect.delay);
+                            reconnect.delay =
+                                std::cmp::min(reconnect.delay * 2, MAX_RECONNECT_DELAY);
+                            ReconnectState::Waiting(waiting)
+                        }
+                    }
+                }
+                ReconnectState::Waiting(waiting) => {
+                    match waiting
+                        .poll()
+                        .map_err(|e| Error::OtherMessage(e.to_string()))?
+                    {
+                        Async::NotReady => return Ok(()),
+                        Async::Ready(()) => ReconnectState::Idle,
+                    }
+                }
+            };
+            reconnect.state = next_state;
+        }
     }
 
     pub fn poll_endpoints(&self) -> Poll<Option<()>, Error> {
-        // eprintln!("in <ConnectionIp as Future>::poll");
-        // if let Some(listener_mutex) = &self.server_tcp {
-        //     let listener = listener_mutex.lock()?;
-        //     match listener.incoming().poll()? {
-        //         Async::Ready(Some(sock)) => {
-        //             // OK, we got a new one.
-        //             let endpoints = self.endpoints();
-        //             tokio::spawn(
-        //                 incoming_handshake(sock)
-        //                     .and_then(move |stream| {
-        //                         if let Ok(mut epoints) = endpoints.lock() {
-        //                             epoints.push(Some(EndpointIp::new(stream)));
-        //                         }
-        //                         Ok(())
-        //                     })
-        //                     .map_err(|e| {
-        //                         eprintln!("err: {:?}", e);
-        //                     }),
-        //             );
-        //         }
-        //         Async::Ready(None) => return Ok(Async::Ready(None)),
-        //         Async::NotReady => (),
-        //     }
-        // }
+        let result = self.poll_endpoints_impl();
+        // Now nothing's locked, send whatever handlers replied.
+        self.pack_deferred_messages()?;
+        result
+    }
+
+    fn poll_endpoints_impl(&self) -> Poll<Option<()>, Error> {
+        // Endpoints get added by tasks spawned by the acceptor, so make sure we hear about them.
+        self.endpoint_added.register();
         let mut acceptor = self.server_acceptor.lock()?;
         match &mut (*acceptor) {
             Some(a) => loop {
@@ -95,39 +293,52 @@ impl ConnectionIp {
             },
             None => (),
         }
+        self.poll_reconnect()?;
         let endpoints = self.endpoints();
         let dispatcher = self.dispatcher();
-        {
-            let mut endpoints = endpoints.lock()?;
-            let mut dispatcher = dispatcher.lock()?;
-            // eprintln!("dispatcher:");
-            // for (id, name) in dispatcher.senders_iter() {
-            //     eprintln!("  sender {}: {:?}", id.get(), name.0);
-            // }
-            // for (id, name) in dispatcher.types_iter() {
-            //     eprintln!("  type {}: {:?}", id.get(), name.0);
-            // }
-            let mut got_not_ready = false;
-            for ep in endpoints.iter_mut().flatten() {
-                let poll_result = ep.poll_endpoint(&mut dispatcher)?;
-                match poll_result {
-                    Async::Ready(()) => {
-                        eprintln!("endpoint closed apparently");
-                        // TODO do we delete this?
-                        //return Ok(Async::Read);
-                    }
-                    Async::NotReady => {
-                        got_not_ready = true;
-                        // this is normal.
-                    }
-                }
+        let mut endpoints = endpoints.lock()?;
+        let mut dispatcher = dispatcher.lock()?;
+
+        // Let local handlers know about any new connections before their messages.
+        let mut connected = endpoints.iter().flatten().any(EndpointIp::announced);
+        for ep in endpoints.iter_mut().flatten().filter(|ep| !ep.announced()) {
+            ep.set_announced();
+            if !connected {
+                dispatcher.call_control_event(constants::GOT_FIRST_CONNECTION)?;
+                connected = true;
             }
-            if got_not_ready {
-                Ok(Async::NotReady)
-            } else {
-                Ok(Async::Ready(Some(())))
+            dispatcher.call_control_event(constants::GOT_CONNECTION)?;
+        }
+
+        let mut dropped = false;
+        for ep_slot in endpoints.iter_mut() {
+            let closed = match ep_slot {
+                Some(ep) => match ep.poll_endpoint(&mut dispatcher) {
+                    Ok(poll) => poll.is_ready(),
+                    // A reset connection and the like: as good as closed.
+                    Err(e) => {
+                        eprintln!("endpoint failed: {}", e);
+                        true
+                    }
+                },
+                None => false,
+            };
+            if closed {
+                eprintln!("endpoint closed");
+                // Dropping the endpoint closes its channels and finishes its logs.
+                *ep_slot = None;
+                dropped = true;
+                dispatcher.call_control_event(constants::DROPPED_CONNECTION)?;
             }
         }
+        if !dropped {
+            return Ok(Async::NotReady);
+        }
+        endpoints.retain(Option::is_some);
+        if endpoints.is_empty() {
+            dispatcher.call_control_event(constants::DROPPED_LAST_CONNECTION)?;
+        }
+        Ok(Async::Ready(Some(())))
     }
 }
 
@@ -136,6 +347,16 @@ impl Connection for ConnectionIp {
     fn connection_core(&self) -> &ConnectionCore<Self::SpecificEndpoint> {
         &self.core
     }
+
+    fn close(&self) -> Result<()> {
+        // Don't reconnect after closing on purpose.
+        *self.reconnect.lock()? = None;
+        let mut endpoints = self.core.endpoints.lock()?;
+        for ep in endpoints.iter_mut().flatten() {
+            ep.close()?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -158,10 +379,17 @@ impl Stream for ConnectionIpStream {
     }
 }
 
+/// Accepts incoming connections for a server ConnectionIp.
+///
+/// Listens for TCP connections (TCP-only mode), as well as for lob packets
+/// on the UDP port of the same number (UDP+TCP mode), in which case we connect back
+/// to the client over TCP.
 #[derive(Debug)]
 pub struct ConnectionIpAcceptor {
     connection: Weak<ConnectionIp>,
     server_tcp: Mutex<Incoming>,
+    server_udp: Mutex<UdpSocket>,
+    local_addr: SocketAddr,
 }
 impl ConnectionIpAcceptor {
     pub fn new(
@@ -169,15 +397,68 @@ impl ConnectionIpAcceptor {
         addr: Option<SocketAddr>,
     ) -> Result<ConnectionIpAcceptor> {
         let addr = addr.unwrap_or_else(|| {
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), DEFAULT_PORT)
+            SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                constants::DEFAULT_PORT,
+            )
         });
-        let server_tcp = Mutex::new(TcpListener::bind(&addr)?.incoming());
+        let listener = TcpListener::bind(&addr)?;
+        // If we were asked for any port, use the same one for UDP as we got for TCP.
+        let local_addr = listener.local_addr()?;
+        let server_udp = Mutex::new(UdpSocket::bind(&local_addr)?);
+        let server_tcp = Mutex::new(listener.incoming());
         Ok(ConnectionIpAcceptor {
             connection,
             server_tcp,
+            server_udp,
+            local_addr,
         })
     }
+
+    /// The address we are listening on, for both TCP and UDP.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Check for a lob packet: the callback address of a client that wants us to connect.
+    fn poll_lob_packet(&self) -> Poll<LobPacket, Error> {
+        let mut udp = self.server_udp.lock()?;
+        let mut buf = [0u8; 256];
+        loop {
+            let (n, from) = try_ready!(udp.poll_recv_from(&mut buf));
+            match LobPacket::unbuffer_ref(&mut Bytes::from(&buf[..n])) {
+                Ok(packet) => return Ok(Async::Ready(packet)),
+                Err(e) => eprintln!("Ignoring bad lob packet from {:?}: {}", from, e),
+            }
+        }
+    }
 }
+
+/// Finish setting up a new (handshaken) connection and add it to the connection's endpoints.
+///
+/// The remote log mode is the one the other end asked for in its cookie.
+fn add_endpoint(
+    connection: &Weak<ConnectionIp>,
+    stream: TcpStream,
+    remote_log_mode: LogMode,
+) -> Result<()> {
+    let connection = match connection.upgrade() {
+        Some(c) => c,
+        // The connection has gone away while we were handshaking.
+        None => return Ok(()),
+    };
+    if let Ok(peer) = stream.peer_addr() {
+        eprintln!("Got connection from {:?}", peer);
+    } else {
+        eprintln!("Got connection from some peer we couldn't identify");
+    }
+    let low_latency_channel = make_udp_channel(&stream)?;
+    let mut ep = EndpointIp::new(stream, Some(low_latency_channel))?;
+    ep.set_remote_log_mode(remote_log_mode);
+    // Tell the new client about everything we've registered so far.
+    connection.push_endpoint(ep)
+}
+
 impl Stream for ConnectionIpAcceptor {
     type Item = ();
     type Error = Error;
@@ -188,29 +469,43 @@ impl Stream for ConnectionIpAcceptor {
                 Some(c) => c,
                 None => return Ok(Async::Ready(None)),
             };
-            let socket = match try_ready!(incoming.poll()) {
-                Some(s) => s,
-                None => return Ok(Async::Ready(None)),
-            };
-            // OK, we got a new one.
-            let endpoints = connection.endpoints();
-            tokio::spawn(
-                incoming_handshake(socket)
-                    .and_then(move |stream| {
-                        if let Ok(peer) = stream.peer_addr() {
-                            eprintln!("Got connection from {:?}", peer);
-                        } else {
-                            eprintln!("Got connection from some peer we couldn't identify");
-                        }
-                        if let Ok(mut epoints) = endpoints.lock() {
-                            epoints.push(Some(EndpointIp::new(stream)));
-                        }
-                        Ok(())
-                    })
-                    .map_err(|e| {
-                        eprintln!("err: {:?}", e);
-                    }),
-            );
+            let mut got_one = false;
+            match incoming.poll()? {
+                Async::Ready(Some(socket)) => {
+                    // OK, we got a new one.
+                    got_one = true;
+                    let connection = Arc::downgrade(&connection);
+                    tokio::spawn(
+                        incoming_handshake_log_mode(socket, LogMode::none())
+                            .and_then(move |(stream, mode)| add_endpoint(&connection, stream, mode))
+                            .map_err(|e| {
+                                eprintln!("err: {:?}", e);
+                            }),
+                    );
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => (),
+            }
+            if let Async::Ready(packet) = self.poll_lob_packet()? {
+                // A client wants us to call it back.
+                got_one = true;
+                eprintln!(
+                    "Got lob packet, connecting to {:?}",
+                    packet.callback_address
+                );
+                let connection = Arc::downgrade(&connection);
+                tokio::spawn(
+                    outgoing_tcp_connect(packet.callback_address)
+                        .and_then(|stream| outgoing_handshake_log_mode(stream, LogMode::none()))
+                        .and_then(move |(stream, mode)| add_endpoint(&connection, stream, mode))
+                        .map_err(|e| {
+                            eprintln!("err: {:?}", e);
+                        }),
+                );
+            }
+            if !got_one {
+                return Ok(Async::NotReady);
+            }
         }
     }
 }
@@ -218,12 +513,37 @@ impl Stream for ConnectionIpAcceptor {
 mod tests {
     use super::*;
     use crate::{
-        handler::{HandlerCode, TypedHandler},
+        handler::{Handler, HandlerCode, TypedHandler},
         tracker::*,
-        Message, StaticSenderName, StaticTypeName, TypeSafeId,
+        GenericMessage, Message, StaticSenderName, StaticTypeName, TypeSafeId,
     };
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Make a server listening on some free local port.
+    fn local_server(local_log_names: Option<LogFileNames>) -> (Arc<ConnectionIp>, SocketAddr) {
+        let server =
+            ConnectionIp::new_server(local_log_names, Some("127.0.0.1:0".parse().unwrap()))
+                .unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+        (server, addr)
+    }
+
+    /// Run a future to completion, polling the server (and so accepting connections) meanwhile.
+    fn block_on_with_server<F>(rt: &mut Runtime, server: &ConnectionIp, mut f: F) -> F::Item
+    where
+        F: Future<Error = Error>,
+    {
+        rt.block_on(
+            future::poll_fn(|| -> Poll<F::Item, Error> {
+                let _ = server.poll_endpoints()?;
+                f.poll()
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("should be able to connect")
+    }
 
     #[derive(Debug)]
     struct TrackerHandler {
@@ -239,6 +559,627 @@ mod tests {
         }
     }
 
+    #[test]
+    fn lob_packet_connect() {
+        use crate::async_io::connect::connect_lob_packet;
+
+        let (conn, server_addr) = local_server(None);
+
+        let mut rt = Runtime::new().unwrap();
+        let _stream = block_on_with_server(&mut rt, &conn, connect_lob_packet(server_addr));
+
+        // The server finishes its side of the handshake on its own time.
+        let endpoints = conn.endpoints();
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                if endpoints.lock().unwrap().is_empty() {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                } else {
+                    Ok(Async::Ready(()))
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("server should have added an endpoint");
+    }
+
+    #[derive(Debug)]
+    struct CountingHandler {
+        count: Arc<Mutex<usize>>,
+    }
+    impl TypedHandler for CountingHandler {
+        type Item = PoseReport;
+        fn handle_typed(&mut self, _msg: &Message<PoseReport>) -> Result<HandlerCode> {
+            *self.count.lock()? += 1;
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[derive(Debug)]
+    struct EventCounter {
+        count: Arc<Mutex<usize>>,
+    }
+    impl Handler for EventCounter {
+        fn handle(&mut self, _msg: &GenericMessage) -> Result<HandlerCode> {
+            *self.count.lock()? += 1;
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    /// Count the times a connection event is dispatched to local handlers.
+    fn count_event(conn: &ConnectionIp, event: StaticTypeName) -> Arc<Mutex<usize>> {
+        let count = Arc::new(Mutex::new(0));
+        let event = conn.register_type(event).unwrap();
+        conn.add_handler(
+            Box::new(EventCounter {
+                count: Arc::clone(&count),
+            }),
+            Some(event),
+            None,
+        )
+        .unwrap();
+        count
+    }
+
+    #[test]
+    fn server_greets_new_clients() {
+        use crate::{async_io::connect_tcp, constants, Quat, Sensor, ServiceFlags, Vec3};
+
+        let (server, server_addr) = local_server(None);
+        // Registered before anyone connects, so only known to clients if we describe it.
+        let server_sender = server
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        let first_connections = count_event(&server, constants::GOT_FIRST_CONNECTION);
+        let connections = count_event(&server, constants::GOT_CONNECTION);
+
+        let mut rt = Runtime::new().unwrap();
+        let stream = block_on_with_server(&mut rt, &server, connect_tcp(server_addr));
+        let client = ConnectionIp::new_client(None, None, stream, None).unwrap();
+        let flag = Arc::new(Mutex::new(false));
+        let client_sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .add_typed_handler(
+                Box::new(TrackerHandler {
+                    flag: Arc::clone(&flag),
+                }),
+                Some(client_sender),
+            )
+            .unwrap();
+
+        // The server finishes its side of the handshake on its own time.
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = server.poll_endpoints()?;
+                if *connections.lock()? == 0 {
+                    Ok(Async::NotReady)
+                } else {
+                    Ok(Async::Ready(()))
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("server should have added an endpoint");
+        assert_eq!(*first_connections.lock().unwrap(), 1);
+
+        server
+            .pack_message_body(
+                None,
+                server_sender,
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(1.0, 2.0, 3.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+                ServiceFlags::RELIABLE.into(),
+            )
+            .unwrap();
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = server.poll_endpoints()?;
+                let _ = client.poll_endpoints()?;
+                if *flag.lock()? {
+                    Ok(Async::Ready(()))
+                } else {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("client should have received the report");
+    }
+
+    #[test]
+    fn connection_events() {
+        use crate::{async_io::connect_tcp, constants};
+
+        let (server, server_addr) = local_server(None);
+        let server_first = count_event(&server, constants::GOT_FIRST_CONNECTION);
+        let server_got = count_event(&server, constants::GOT_CONNECTION);
+        let server_dropped = count_event(&server, constants::DROPPED_CONNECTION);
+        let server_dropped_last = count_event(&server, constants::DROPPED_LAST_CONNECTION);
+
+        let mut rt = Runtime::new().unwrap();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let stream = block_on_with_server(&mut rt, &server, connect_tcp(server_addr));
+            clients.push(ConnectionIp::new_client(None, None, stream, None).unwrap());
+        }
+        let client_first = count_event(&clients[0], constants::GOT_FIRST_CONNECTION);
+        let client_got = count_event(&clients[0], constants::GOT_CONNECTION);
+
+        let poll_until =
+            |rt: &mut Runtime, clients: &[Arc<ConnectionIp>], done: &dyn Fn() -> bool| {
+                rt.block_on(
+                    future::poll_fn(|| -> Poll<(), Error> {
+                        let _ = server.poll_endpoints()?;
+                        for client in clients {
+                            let _ = client.poll_endpoints()?;
+                        }
+                        if done() {
+                            Ok(Async::Ready(()))
+                        } else {
+                            Ok(Async::NotReady)
+                        }
+                    })
+                    .timeout(Duration::from_secs(5)),
+                )
+                .expect("should have seen the events");
+            };
+        poll_until(&mut rt, &clients, &|| *server_got.lock().unwrap() == 2);
+        assert_eq!(*server_first.lock().unwrap(), 1);
+        assert_eq!(*client_first.lock().unwrap(), 1);
+        assert_eq!(*client_got.lock().unwrap(), 1);
+
+        clients.pop();
+        poll_until(&mut rt, &clients, &|| *server_dropped.lock().unwrap() == 1);
+        assert_eq!(*server_dropped_last.lock().unwrap(), 0);
+        assert_eq!(server.endpoints().lock().unwrap().len(), 1);
+
+        clients.clear();
+        poll_until(&mut rt, &clients, &|| {
+            *server_dropped_last.lock().unwrap() == 1
+        });
+        assert_eq!(*server_dropped.lock().unwrap(), 2);
+        assert!(server.endpoints().lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn close_connection() {
+        use crate::{async_io::connect_tcp, constants, Quat, Sensor, ServiceFlags, Vec3};
+
+        let (server, server_addr) = local_server(None);
+        let server_connected = count_event(&server, constants::GOT_CONNECTION);
+        let server_dropped_last = count_event(&server, constants::DROPPED_LAST_CONNECTION);
+        let flag = Arc::new(Mutex::new(false));
+        server
+            .add_typed_handler(
+                Box::new(TrackerHandler {
+                    flag: Arc::clone(&flag),
+                }),
+                None,
+            )
+            .unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        let stream = block_on_with_server(&mut rt, &server, connect_tcp(server_addr));
+        let client = ConnectionIp::new_client(None, None, stream, None).unwrap();
+        let client_dropped_last = count_event(&client, constants::DROPPED_LAST_CONNECTION);
+
+        // Anything sent before closing still gets there.
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .pack_message_body(
+                None,
+                sender,
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(1.0, 2.0, 3.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+                ServiceFlags::RELIABLE.into(),
+            )
+            .unwrap();
+        client.close().unwrap();
+
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = server.poll_endpoints()?;
+                let _ = client.poll_endpoints()?;
+                if *server_dropped_last.lock()? == 1 && *client_dropped_last.lock()? == 1 {
+                    Ok(Async::Ready(()))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("both sides should have dropped the connection");
+        assert!(*flag.lock().unwrap());
+        assert_eq!(*server_connected.lock().unwrap(), 1);
+        assert!(server.endpoints().lock().unwrap().is_empty());
+        assert!(client.endpoints().lock().unwrap().is_empty());
+        assert!(client.disconnect_endpoint(0).is_err());
+    }
+
+    /// Have the server send a tracker report once it has a client,
+    /// running until the client has received `expected` reports in all.
+    fn report_until_received(
+        rt: &mut Runtime,
+        server: &ConnectionIp,
+        client: &ConnectionIp,
+        count: &Mutex<usize>,
+        expected: usize,
+    ) {
+        use crate::{Quat, Sensor, ServiceFlags, Vec3};
+
+        let sender = server
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        let mut sent = false;
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = server.poll_endpoints()?;
+                let _ = client.poll_endpoints()?;
+                if *count.lock()? == expected {
+                    return Ok(Async::Ready(()));
+                }
+                if !sent && !server.endpoints().lock()?.is_empty() {
+                    server.pack_message_body(
+                        None,
+                        sender,
+                        PoseReport {
+                            sensor: Sensor(0),
+                            pos: Vec3::new(1.0, 2.0, 3.0),
+                            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                        },
+                        ServiceFlags::RELIABLE.into(),
+                    )?;
+                    sent = true;
+                }
+                task::current().notify();
+                Ok(Async::NotReady)
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("client should have received a report");
+    }
+
+    #[test]
+    fn reconnect() {
+        use crate::constants;
+
+        let (server, server_addr) = local_server(None);
+        let client = ConnectionIp::new_reconnecting_client(None, None, server_addr);
+        let client_connected = count_event(&client, constants::GOT_CONNECTION);
+        let count = Arc::new(Mutex::new(0));
+        let client_sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .add_typed_handler(
+                Box::new(CountingHandler {
+                    count: Arc::clone(&count),
+                }),
+                Some(client_sender),
+            )
+            .unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        report_until_received(&mut rt, &server, &client, &count, 1);
+
+        // "Restart" the server: the client should notice, reconnect, and keep its handler.
+        drop(server);
+        let server = ConnectionIp::new_server(None, Some(server_addr)).unwrap();
+        report_until_received(&mut rt, &server, &client, &count, 2);
+        assert_eq!(*client_connected.lock().unwrap(), 2);
+
+        // Closing on purpose stops it reconnecting.
+        client.close().unwrap();
+        assert!(client.reconnect.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn reconnect_after_reset() {
+        use crate::{constants, cookie::CookieData, BytesMutExtras, ConstantBufferSize};
+        use bytes::BytesMut;
+        use std::{io::Read, io::Write, net::TcpListener as StdTcpListener, thread};
+
+        // A "server" that shakes hands, then resets the connection instead of closing it.
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let resetter = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut cookie = vec![0u8; CookieData::constant_buffer_size()];
+            stream.read_exact(&mut cookie).unwrap();
+            let ours = BytesMut::new()
+                .allocate_and_buffer(CookieData::from(constants::MAGIC_DATA))
+                .unwrap();
+            stream.write_all(&ours).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            let stream = TcpStream::from_std(stream, &tokio::reactor::Handle::default()).unwrap();
+            stream.set_linger(Some(Duration::from_secs(0))).unwrap();
+        });
+
+        let client = ConnectionIp::new_reconnecting_client(None, None, server_addr);
+        let dropped = count_event(&client, constants::DROPPED_CONNECTION);
+        let count = Arc::new(Mutex::new(0));
+        let client_sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .add_typed_handler(
+                Box::new(CountingHandler {
+                    count: Arc::clone(&count),
+                }),
+                Some(client_sender),
+            )
+            .unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = client.poll_endpoints()?;
+                if *dropped.lock()? > 0 {
+                    return Ok(Async::Ready(()));
+                }
+                task::current().notify();
+                Ok(Async::NotReady)
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("client should have dropped the reset connection");
+        resetter.join().unwrap();
+
+        let server = ConnectionIp::new_server(None, Some(server_addr)).unwrap();
+        report_until_received(&mut rt, &server, &client, &count, 1);
+    }
+
+    /// Play back a log file, counting the tracker reports from "Tracker0".
+    fn count_logged_reports(path: &std::path::Path) -> usize {
+        use crate::async_io::{ConnectionFile, ConnectionFileStream, ReplayRate};
+        let conn = ConnectionFile::new(path, ReplayRate::AsFastAsPossible).unwrap();
+        let count = Arc::new(Mutex::new(0));
+        let sender = conn.register_sender(StaticSenderName(b"Tracker0")).unwrap();
+        conn.add_typed_handler(
+            Box::new(CountingHandler {
+                count: Arc::clone(&count),
+            }),
+            Some(sender),
+        )
+        .unwrap();
+        ConnectionFileStream::new(conn).collect().wait().unwrap();
+        let count = *count.lock().unwrap();
+        count
+    }
+
+    #[test]
+    fn local_logging() {
+        use crate::{async_io::connect_tcp, Quat, Sensor, ServiceFlags, Vec3};
+
+        let log_path = |name: &str| {
+            std::env::temp_dir().join(format!("vrpn-rs-{}-{}.vrpn", name, std::process::id()))
+        };
+        let log_name = |path: &std::path::PathBuf| Bytes::from(path.to_string_lossy().as_bytes());
+        let server_in = log_path("server-in");
+        let client_out = log_path("client-out");
+        // Log files are never overwritten, so clear out any left by an earlier run.
+        let _ = std::fs::remove_file(&server_in);
+        let _ = std::fs::remove_file(&client_out);
+
+        let (server, server_addr) = local_server(Some(LogFileNames::from_names(
+            Some(log_name(&server_in)),
+            None,
+        )));
+        let flag = Arc::new(Mutex::new(false));
+        server
+            .add_typed_handler(
+                Box::new(TrackerHandler {
+                    flag: Arc::clone(&flag),
+                }),
+                None,
+            )
+            .unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        let stream = block_on_with_server(&mut rt, &server, connect_tcp(server_addr));
+        let client = ConnectionIp::new_client(
+            Some(LogFileNames::from_names(None, Some(log_name(&client_out)))),
+            None,
+            stream,
+            None,
+        )
+        .unwrap();
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .pack_message_body(
+                None,
+                sender,
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(1.0, 2.0, 3.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+                ServiceFlags::RELIABLE.into(),
+            )
+            .unwrap();
+
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = client.poll_endpoints()?;
+                let _ = server.poll_endpoints()?;
+                if *flag.lock()? {
+                    Ok(Async::Ready(()))
+                } else {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("server should have received the report");
+
+        // Closing the connections finishes writing the logs.
+        drop(client);
+        drop(server);
+        assert_eq!(count_logged_reports(&client_out), 1);
+        assert_eq!(count_logged_reports(&server_in), 1);
+        let _ = std::fs::remove_file(&client_out);
+        let _ = std::fs::remove_file(&server_in);
+    }
+
+    #[test]
+    fn remote_logging() {
+        use crate::{async_io::connect_tcp_with_remote_logging, Quat, Sensor, ServiceFlags, Vec3};
+
+        let log_path = |name: &str| {
+            std::env::temp_dir().join(format!("vrpn-rs-{}-{}.vrpn", name, std::process::id()))
+        };
+        let log_name = |path: &std::path::PathBuf| Bytes::from(path.to_string_lossy().as_bytes());
+        let remote_in = log_path("remote-in");
+        let remote_out = log_path("remote-out");
+        let _ = std::fs::remove_file(&remote_in);
+        let _ = std::fs::remove_file(&remote_out);
+
+        let (server, server_addr) = local_server(None);
+        let flag = Arc::new(Mutex::new(false));
+        server
+            .add_typed_handler(
+                Box::new(TrackerHandler {
+                    flag: Arc::clone(&flag),
+                }),
+                None,
+            )
+            .unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        let stream = block_on_with_server(
+            &mut rt,
+            &server,
+            connect_tcp_with_remote_logging(
+                server_addr,
+                LogFileNames::from_names(Some(log_name(&remote_in)), Some(log_name(&remote_out))),
+            ),
+        );
+        let client = ConnectionIp::new_client(None, None, stream, None).unwrap();
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .pack_message_body(
+                None,
+                sender,
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(1.0, 2.0, 3.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+                ServiceFlags::RELIABLE.into(),
+            )
+            .unwrap();
+
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = client.poll_endpoints()?;
+                let _ = server.poll_endpoints()?;
+                if *flag.lock()? {
+                    Ok(Async::Ready(()))
+                } else {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("server should have received the report");
+
+        // Closing the connections finishes writing the logs.
+        drop(client);
+        drop(server);
+        assert_eq!(count_logged_reports(&remote_in), 1);
+        assert!(remote_out.exists());
+        let _ = std::fs::remove_file(&remote_in);
+        let _ = std::fs::remove_file(&remote_out);
+    }
+
+    #[test]
+    fn remote_logging_leaves_existing_file() {
+        use crate::{async_io::connect_tcp_with_remote_logging, Quat, Sensor, ServiceFlags, Vec3};
+
+        let existing =
+            std::env::temp_dir().join(format!("vrpn-rs-existing-{}.vrpn", std::process::id()));
+        std::fs::write(&existing, b"not a log").unwrap();
+
+        let (server, server_addr) = local_server(None);
+        let flag = Arc::new(Mutex::new(false));
+        server
+            .add_typed_handler(
+                Box::new(TrackerHandler {
+                    flag: Arc::clone(&flag),
+                }),
+                None,
+            )
+            .unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        let stream = block_on_with_server(
+            &mut rt,
+            &server,
+            connect_tcp_with_remote_logging(
+                server_addr,
+                LogFileNames::from_names(
+                    Some(Bytes::from(existing.to_string_lossy().as_bytes())),
+                    None,
+                ),
+            ),
+        );
+        let client = ConnectionIp::new_client(None, None, stream, None).unwrap();
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .pack_message_body(
+                None,
+                sender,
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(1.0, 2.0, 3.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+                ServiceFlags::RELIABLE.into(),
+            )
+            .unwrap();
+
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                let _ = client.poll_endpoints()?;
+                let _ = server.poll_endpoints()?;
+                if *flag.lock()? {
+                    Ok(Async::Ready(()))
+                } else {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("the connection should survive the refused log request");
+
+        drop(client);
+        drop(server);
+        let contents = std::fs::read(&existing).unwrap();
+        let _ = std::fs::remove_file(&existing);
+        assert_eq!(&contents[..], &b"not a log"[..]);
+    }
+
     #[ignore] // because it requires an external server to be running.
     #[test]
     fn tracker() {
@@ -248,7 +1189,7 @@ mod tests {
 
         connect_tcp(addr)
             .and_then(|stream| -> Result<()> {
-                let conn = ConnectionIp::new_client(None, None, stream)?;
+                let conn = ConnectionIp::new_client(None, None, stream, None)?;
                 let sender = conn
                     .register_sender(StaticSenderName(b"Tracker0"))
                     .expect("should be able to register sender");
@@ -280,7 +1221,7 @@ mod tests {
 
         connect_tcp(addr)
             .and_then(|stream| {
-                let conn = ConnectionIp::new_client(None, None, stream)?;
+                let conn = ConnectionIp::new_client(None, None, stream, None)?;
                 let tracker_message_id = conn
                     .register_type(StaticTypeName(b"vrpn_Tracker Pos_Quat"))
                     .expect("should be able to register type");
@@ -7,7 +7,7 @@ use crate::{
     constants::{FILE_MAGIC_DATA, MAGIC_DATA},
     cookie::{check_ver_file_compatible, check_ver_nonfile_compatible},
     prelude::{BytesMutExtras, WrappedConstantSize},
-    ConstantBufferSize, CookieData, Error, Unbuffer,
+    ConstantBufferSize, CookieData, Error, LogMode, Result, Unbuffer,
 };
 use tokio::{io, prelude::*};
 
@@ -38,22 +38,26 @@ where
     io::read_exact(stream, vec![0u8; CookieData::constant_buffer_size()]).from_err()
 }
 
-fn verify_version_nonfile(msg: CookieData) -> impl Future<Item = (), Error = Error> {
-    check_ver_nonfile_compatible(msg.version).into_future()
-}
-
-fn verify_version_file(msg: CookieData) -> impl Future<Item = (), Error = Error> {
-    check_ver_file_compatible(msg.version).into_future()
+fn verify_version_nonfile(msg: CookieData) -> impl Future<Item = LogMode, Error = Error> {
+    check_ver_nonfile_compatible(msg.version)
+        .map(|()| msg.log_mode.unwrap_or_else(LogMode::none))
+        .into_future()
 }
 
-// /// Writes the "non-file" magic cookie to the stream.
+/// Writes the "non-file" magic cookie to the stream,
+/// asking the other end to log this connection in the given mode.
 ///
 /// Future resolves to the provided stream on success.
-pub(crate) fn send_nonfile_cookie<T>(stream: T) -> impl Future<Item = T, Error = Error>
+pub(crate) fn send_nonfile_cookie<T>(
+    stream: T,
+    log_mode: LogMode,
+) -> impl Future<Item = T, Error = Error>
 where
     T: AsyncWrite,
 {
-    write_cookie(stream, CookieData::from(MAGIC_DATA))
+    let mut cookie = CookieData::from(MAGIC_DATA);
+    cookie.log_mode = Some(log_mode);
+    write_cookie(stream, cookie)
 }
 
 /// Writes the "file" magic cookie to the stream.
@@ -66,10 +70,12 @@ where
     write_cookie(stream, CookieData::from(FILE_MAGIC_DATA))
 }
 
-/// Reads a cookie's worth of data from the stream, and cheacks to make sure it is the right version.
+/// Reads a cookie's worth of data from the stream, and checks to make sure it is the right version.
 ///
-/// Future resolves to the provided stream on success.
-pub(crate) fn read_and_check_nonfile_cookie<T>(stream: T) -> impl Future<Item = T, Error = Error>
+/// Future resolves to the provided stream and the logging mode the other end asked for.
+pub(crate) fn read_and_check_nonfile_cookie<T>(
+    stream: T,
+) -> impl Future<Item = (T, LogMode), Error = Error>
 where
     T: AsyncRead,
 {
@@ -78,22 +84,19 @@ where
         CookieData::unbuffer_ref(&mut buf)
             .into_future()
             .and_then(verify_version_nonfile)
-            .and_then(|()| Ok(stream))
+            .map(|log_mode| (stream, log_mode))
     })
 }
 
-/// Reads a cookie's worth of data from the stream, and cheacks to make sure it is the right version.
+/// Reads a cookie's worth of data from a log file, and checks to make sure it is the right version.
 ///
-/// Future resolves to the provided stream on success.
-pub(crate) fn read_and_check_file_cookie<T>(stream: T) -> impl Future<Item = T, Error = Error>
+/// Log files are read synchronously, so this is too.
+pub(crate) fn read_and_check_file_cookie<T>(file: &mut T) -> Result<()>
 where
-    T: AsyncRead,
+    T: std::io::Read,
 {
-    read_cookie(stream).and_then(|(stream, read_buf)| {
-        let mut buf = Bytes::from(&read_buf[..]);
-        CookieData::unbuffer_ref(&mut buf)
-            .into_future()
-            .and_then(verify_version_file)
-            .and_then(|()| Ok(stream))
-    })
+    let mut read_buf = vec![0u8; CookieData::constant_buffer_size()];
+    file.read_exact(&mut read_buf)?;
+    let mut buf = Bytes::from(&read_buf[..]);
+    CookieData::unbuffer_ref(&mut buf).and_then(|msg| check_ver_file_compatible(msg.version))
 }
@@ -0,0 +1,129 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use crate::{
+    async_io::{
+        connect::{connect_lob_packet, connect_tcp, make_udp_channel},
+        ConnectionFile, ConnectionIp, ReplayRate,
+    },
+    locator::Transport,
+    Connection, DeviceLocator, Error, LocalId, Result, SenderId,
+};
+use std::sync::Arc;
+use tokio::prelude::*;
+
+/// The connection made by `connect_device`: what kind depends on the locator.
+#[derive(Clone, Debug)]
+pub enum DeviceConnection {
+    Ip(Arc<ConnectionIp>),
+    File(Arc<ConnectionFile>),
+}
+
+/// Connect to the device named by a locator like "Tracker0@localhost",
+/// "Tracker0@tcp://host:port" or "Tracker0@file:///path/to/log.vrpn".
+///
+/// Resolves to the connection (not yet being polled) and the device's sender ID on it.
+pub fn connect_device(
+    locator: &str,
+) -> impl Future<Item = (DeviceConnection, LocalId<SenderId>), Error = Error> {
+    let locator = match DeviceLocator::parse(locator) {
+        Ok(locator) => locator,
+        Err(e) => return future::Either::A(future::err(e)),
+    };
+    if locator.transport() == Transport::File {
+        return future::Either::A(future::result(open_file(&locator)));
+    }
+    future::Either::B(connect_ip(locator.clone()).and_then(move |connection| {
+        let sender = connection.register_sender(locator.device())?;
+        Ok((DeviceConnection::Ip(connection), sender))
+    }))
+}
+
+/// Make a client connection to the server in a (non-file) locator, using its transport.
+pub(crate) fn connect_ip(
+    locator: DeviceLocator,
+) -> impl Future<Item = Arc<ConnectionIp>, Error = Error> {
+    let addr = match locator.socket_addr() {
+        Ok(addr) => addr,
+        Err(e) => return future::Either::A(future::err(e)),
+    };
+    let stream = match locator.transport() {
+        Transport::Tcp => future::Either::A(connect_tcp(addr)),
+        _ => future::Either::B(connect_lob_packet(addr)),
+    };
+    future::Either::B(stream.and_then(move |stream| {
+        let udp = match locator.transport() {
+            Transport::UdpAndTcp => Some(make_udp_channel(&stream)?),
+            _ => None,
+        };
+        ConnectionIp::new_client(None, None, stream, udp)
+    }))
+}
+
+fn open_file(locator: &DeviceLocator) -> Result<(DeviceConnection, LocalId<SenderId>)> {
+    let connection = ConnectionFile::new(locator.address(), ReplayRate::default())?;
+    let sender = connection.register_sender(locator.device())?;
+    Ok((DeviceConnection::File(connection), sender))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LogFileWriter;
+    use std::{env, time::Duration};
+    use tokio::runtime::current_thread::Runtime;
+
+    fn connect_to_local_server(scheme: &str) -> DeviceConnection {
+        let server = ConnectionIp::new_server(None, Some("127.0.0.1:0".parse().unwrap())).unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+        let mut connecting = connect_device(&format!("Tracker0@{}{}", scheme, addr));
+
+        let mut rt = Runtime::new().unwrap();
+        let (connection, _sender) = rt
+            .block_on(
+                future::poll_fn(|| {
+                    let _ = server.poll_endpoints()?;
+                    connecting.poll()
+                })
+                .timeout(Duration::from_secs(5)),
+            )
+            .expect("should be able to connect");
+        connection
+    }
+
+    #[test]
+    fn tcp() {
+        match connect_to_local_server("tcp://") {
+            DeviceConnection::Ip(_) => {}
+            other => panic!("expected an IP connection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lob_packet() {
+        match connect_to_local_server("") {
+            DeviceConnection::Ip(_) => {}
+            other => panic!("expected an IP connection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn file() {
+        let path = env::temp_dir().join(format!("vrpn-rs-device-{}.vrpn", std::process::id()));
+        LogFileWriter::create(&path).unwrap().flush().unwrap();
+
+        let locator = format!("Tracker0@file://{}", path.display());
+        let result = connect_device(&locator).wait();
+        let _ = std::fs::remove_file(&path);
+        match result.expect("should be able to open the file") {
+            (DeviceConnection::File(_), _) => {}
+            other => panic!("expected a file connection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(connect_device("Tracker0").wait().is_err());
+    }
+}
@@ -5,12 +5,16 @@
 // https://github.com/tokio-rs/tokio/blob/24d99c029eff5d5b82aff567f1ad5ede8a8c2576/examples/chat.rs
 
 use crate::{
-    async_io::endpoint_ip::EndpointIp, Endpoint, EndpointGeneric, Error, GenericMessage, LocalId,
-    Message, MessageHeader, RemoteId, SequenceNumber, SequencedGenericMessage, TypeDispatcher,
+    async_io::endpoint_ip::{EndpointIp, MessageFramedUdp},
+    Endpoint, EndpointGeneric, Error, GenericMessage, LocalId, Message, MessageHeader, RemoteId,
+    SequenceNumber, SequencedGenericMessage, TypeDispatcher,
 };
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 use tokio::prelude::*;
 
@@ -81,6 +85,122 @@ where
     }
 }
 
+/// The low-latency (UDP) channel of an endpoint.
+///
+/// Messages may arrive from anywhere, but we can only send once we know
+/// where the other end is listening: that arrives in a UDP description
+/// over the reliable channel.
+#[derive(Debug)]
+pub(crate) struct UdpEndpointChannel {
+    framed: MessageFramedUdp,
+    peer: Option<SocketAddr>,
+    seq: AtomicUsize,
+}
+
+impl UdpEndpointChannel {
+    pub(crate) fn new(framed: MessageFramedUdp) -> Arc<Mutex<UdpEndpointChannel>> {
+        Arc::new(Mutex::new(UdpEndpointChannel {
+            framed,
+            peer: None,
+            seq: AtomicUsize::new(0),
+        }))
+    }
+
+    /// The address our socket is bound to.
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.framed.get_ref().local_addr()?)
+    }
+
+    /// The address we send to, if known yet.
+    pub(crate) fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub(crate) fn set_peer(&mut self, peer: SocketAddr) {
+        self.peer = Some(peer);
+    }
+}
+
+impl Stream for UdpEndpointChannel {
+    type Item = GenericMessage;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // drop the source address: this channel only ever talks to one peer.
+        self.framed
+            .poll()
+            .map(|a| a.map(|o| o.map(|(msg, _addr)| GenericMessage::from(msg))))
+    }
+}
+
+impl Sink for UdpEndpointChannel {
+    type SinkItem = GenericMessage;
+    type SinkError = Error;
+    fn start_send(
+        &mut self,
+        item: Self::SinkItem,
+    ) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
+        let peer = self.peer.ok_or_else(|| {
+            Error::OtherMessage(String::from(
+                "can't send on low-latency channel before receiving a UDP description",
+            ))
+        })?;
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+
+        match self.framed.start_send((
+            item.into_sequenced_message(SequenceNumber(seq as u32)),
+            peer,
+        ))? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+
+            // Unwrap the message again if not ready.
+            AsyncSink::NotReady((msg, _)) => Ok(AsyncSink::NotReady(GenericMessage::from(msg))),
+        }
+    }
+    fn poll_complete(&mut self) -> Result<Async<()>, Self::SinkError> {
+        self.framed.poll_complete()
+    }
+}
+
+/// Handle a message received by an endpoint.
+///
+/// System messages are passed to the endpoint, to queue up as system changes,
+/// while other messages have their IDs translated to local ones and are dispatched.
+pub(crate) fn dispatch_message<E>(
+    endpoint: &mut E,
+    msg: GenericMessage,
+    dispatcher: &mut TypeDispatcher,
+) -> Result<(), Error>
+where
+    E: Endpoint,
+{
+    if msg.is_system_message() {
+        // eprintln!("System message: {:?}", msg.header);
+        endpoint
+            .handle_system_message(msg)
+            .expect("this shouldn't fail");
+    } else if let Some(LocalId(new_type)) =
+        endpoint.map_to_local_id(RemoteId(msg.header.message_type))
+    {
+        if let Some(LocalId(new_sender)) = endpoint.map_to_local_id(RemoteId(msg.header.sender)) {
+            // eprintln!("user message: {:?}", msg.header);
+            let msg = Message::from_header_and_body(
+                MessageHeader::new(
+                    Some(dispatcher.incoming_time_adjustment().apply(msg.header.time)),
+                    new_type,
+                    new_sender,
+                ),
+                msg.body,
+            );
+            dispatcher.call(&msg)?;
+        } else {
+            eprintln!("Could not map sender to local");
+        }
+    } else {
+        eprintln!("Could not map type to local");
+    }
+    Ok(())
+}
+
 /// Given a stream of GenericMessage, poll the stream and dispatch received messages.
 pub(crate) fn poll_and_dispatch<T>(
     endpoint: &mut EndpointIp,
@@ -97,34 +217,12 @@ where
         let poll_result = stream.poll()?;
         match poll_result {
             Async::Ready(Some(msg)) => {
-                if msg.is_system_message() {
-                    // eprintln!("System message: {:?}", msg.header);
-                    endpoint
-                        .handle_system_message(msg)
-                        .expect("this shouldn't fail");
-                } else {
-                    if let Some(LocalId(new_type)) =
-                        endpoint.map_to_local_id(RemoteId(msg.header.message_type))
-                    {
-                        if let Some(LocalId(new_sender)) =
-                            endpoint.map_to_local_id(RemoteId(msg.header.sender))
-                        {
-                            // eprintln!("user message: {:?}", msg.header);
-                            let msg = Message::from_header_and_body(
-                                MessageHeader::new(
-                                    Some(msg.header.time.clone()),
-                                    new_type,
-                                    new_sender,
-                                ),
-                                msg.body,
-                            );
-                            dispatcher.call(&msg)?;
-                        } else {
-                            eprintln!("Could not map sender to local");
-                        }
-                    } else {
-                        eprintln!("Could not map type to local");
-                    }
+                endpoint.log_incoming(&msg)?;
+                let is_system = msg.is_system_message();
+                dispatch_message(endpoint, msg, dispatcher)?;
+                if is_system {
+                    // Apply descriptions right away, so the messages after them can be understood.
+                    let _ = endpoint.process_system_changes(dispatcher)?;
                 }
             }
             Async::Ready(None) => {
@@ -157,7 +255,64 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::async_io::{apply_message_framing, connect_tcp};
+    use bytes::{Bytes, BytesMut};
+    use crate::{
+        async_io::{apply_message_framing, codec::FramedMessageCodec, connect_tcp},
+        descriptions::InnerDescription,
+        prelude::*,
+        Description, SenderId,
+    };
+    use tokio::net::{UdpFramed, UdpSocket};
+
+    fn make_udp_channel() -> Arc<Mutex<UdpEndpointChannel>> {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let sock = UdpSocket::bind(&addr).unwrap();
+        UdpEndpointChannel::new(UdpFramed::with_decode(sock, FramedMessageCodec, true))
+    }
+
+    fn sender_description(id: i32, name: &'static [u8]) -> GenericMessage {
+        Message::from(Description::new(SenderId(id), Bytes::from(name)))
+            .try_into_generic()
+            .unwrap()
+    }
+
+    fn sender_name(msg: &GenericMessage) -> Bytes {
+        let msg: Message<InnerDescription<SenderId>> = Message::try_from_generic(msg).unwrap();
+        msg.body.name
+    }
+
+    #[test]
+    fn udp_channel() {
+        let a = make_udp_channel();
+        let b = make_udp_channel();
+        let mut a = a.lock().unwrap();
+        let mut b = b.lock().unwrap();
+        assert!(a.start_send(sender_description(0, b"Tracker0")).is_err());
+
+        let b_addr = b.local_addr().unwrap();
+        a.set_peer(b_addr);
+        assert_eq!(a.peer(), Some(b_addr));
+        a.by_ref()
+            .send(sender_description(0, b"Tracker0"))
+            .wait()
+            .unwrap();
+        let received = b.by_ref().take(1).collect().wait().unwrap();
+        assert_eq!(sender_name(&received[0]), Bytes::from(&b"Tracker0"[..]));
+
+        // Several messages may share one datagram.
+        let mut datagram = BytesMut::new();
+        for (i, name) in [&b"Tracker0"[..], &b"Button0"[..]].iter().enumerate() {
+            let msg =
+                sender_description(i as i32, name).into_sequenced_message(SequenceNumber(i as u32));
+            datagram.extend_from_slice(&BytesMut::new().allocate_and_buffer(msg).unwrap());
+        }
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.send_to(&datagram, b_addr).unwrap();
+        let received = b.by_ref().take(2).collect().wait().unwrap();
+        assert_eq!(sender_name(&received[0]), Bytes::from(&b"Tracker0"[..]));
+        assert_eq!(sender_name(&received[1]), Bytes::from(&b"Button0"[..]));
+    }
+
     #[test]
     fn make_endpoint_channel() {
         let addr = "127.0.0.1:3883".parse().unwrap();
@@ -2,40 +2,423 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use crate::async_io::codec::*;
-use crate::async_io::cookie::*;
+use bytes::Bytes;
 use crate::{
-    ClassOfService, Endpoint, EndpointGeneric, GenericMessage, Result, SystemMessage,
-    TranslationTables,
+    async_io::{cookie::read_and_check_file_cookie, endpoint_channel::dispatch_message},
+    file_controller::{PlayToTime, SetReplayRate},
+    log::{LogEntry, LOG_ENTRY_HEADER_SIZE},
+    ClassOfService, Endpoint, Error, GenericMessage, LocalId, Message, Result, SenderId,
+    SystemMessage, TimeVal, TranslationTables, TypeDispatcher, TypeId, Unbuffer,
 };
 use futures::sync::mpsc;
-use std::{fs, path::Path};
+use std::{
+    fs,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::{
-    codec::{Decoder, Framed},
-    fs::{file::OpenFuture, File},
-    prelude::*,
+    prelude::{task::Task, *},
+    timer::Delay,
 };
 
+/// How fast to replay a log file, relative to the timestamps recorded in it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplayRate {
+    /// Deliver messages with the same spacing as when they were recorded.
+    #[default]
+    RealTime,
+    /// Deliver messages with their recorded spacing divided by this factor:
+    /// 2.0 is twice as fast as real time.
+    /// Zero (or less) pauses playback.
+    Scaled(f64),
+    /// Deliver messages as fast as they can be read.
+    AsFastAsPossible,
+}
+
+/// The fastest replay rate a controller may ask for: anything faster is as good as
+/// `AsFastAsPossible`, and much faster overflows the playback clock.
+const MAX_REQUESTED_REPLAY_RATE: f64 = 1.0e6;
+
+/// When the next message should be played.
+enum Due {
+    Now,
+    In(Duration),
+    Never,
+}
+
+/// Maximum number of messages to play in one poll, so we don't starve other tasks.
+const MAX_PER_POLL: usize = 100;
+
+fn file_time_difference(later: TimeVal, earlier: TimeVal) -> Duration {
+    SystemTime::from(later)
+        .duration_since(SystemTime::from(earlier))
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+/// Where to find an entry in the log file, and what we need to know about it without reading it.
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    time: TimeVal,
+    offset: u64,
+    message_type: TypeId,
+}
+
+/// Scan the entries in a log file (positioned just after the cookie),
+/// recording their offsets, then return to the first one.
+fn build_index(file: &mut BufReader<fs::File>) -> Result<Vec<IndexEntry>> {
+    let file_len = file.get_ref().metadata()?.len();
+    let start = file.stream_position()?;
+    let mut offset = start;
+    let mut index = Vec::new();
+    let mut header = [0u8; LOG_ENTRY_HEADER_SIZE];
+    loop {
+        match file.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::from(e)),
+        }
+        let body_len = LogEntry::peek_body_len(&header)?.expect("we read a whole header");
+        let entry_len = (LOG_ENTRY_HEADER_SIZE + body_len) as u64;
+        if offset + entry_len > file_len {
+            eprintln!("Ignoring truncated entry at the end of the log file");
+            break;
+        }
+        let mut buf = Bytes::from(&header[..]);
+        let message_type = TypeId::unbuffer_ref(&mut buf)?;
+        let _ = SenderId::unbuffer_ref(&mut buf)?;
+        let time = TimeVal::unbuffer_ref(&mut buf)?;
+        index.push(IndexEntry {
+            time,
+            offset,
+            message_type,
+        });
+        file.seek_relative(body_len as i64)?;
+        offset += entry_len;
+    }
+    file.seek(SeekFrom::Start(start))?;
+    Ok(index)
+}
+
+/// The local IDs of the playback control messages a controller may send us.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FileControlIds {
+    pub(crate) sender: LocalId<SenderId>,
+    pub(crate) set_replay_rate: LocalId<TypeId>,
+    pub(crate) reset: LocalId<TypeId>,
+    pub(crate) play_to_time: LocalId<TypeId>,
+}
+
+/// Endpoint that plays back the messages recorded in a log file, at a chosen rate.
+#[derive(Debug)]
 pub struct EndpointFile {
     translation: TranslationTables,
-    file: Framed<File, FramedMessageCodec>,
+    file: BufReader<fs::File>,
+    index: Vec<IndexEntry>,
+    /// The index of the next entry to read from the file.
+    next_entry: usize,
     system_rx: mpsc::UnboundedReceiver<SystemMessage>,
     system_tx: mpsc::UnboundedSender<SystemMessage>,
+    rate: ReplayRate,
+    /// A wall-clock instant and the file time played back at that instant.
+    anchor: Option<(Instant, TimeVal)>,
+    /// The next message, read from the file but not yet played.
+    pending: Option<GenericMessage>,
+    /// The time of the last message played, or the time we last jumped to.
+    current_time: Option<TimeVal>,
+    /// A file time to play up to immediately, regardless of rate.
+    play_to: Option<TimeVal>,
+    delay: Option<Delay>,
+    /// Our task, if we're waiting and need waking when playback is changed.
+    task: Option<Task>,
+    control: Option<FileControlIds>,
 }
 
 impl EndpointFile {
     pub fn new(file: fs::File) -> Result<EndpointFile> {
         let (system_tx, system_rx) = mpsc::unbounded();
-        let file = File::from_std(file);
-        let file = read_and_check_file_cookie(file).wait()?;
+        let mut file = BufReader::new(file);
+        read_and_check_file_cookie(&mut file)?;
+        let index = build_index(&mut file)?;
         Ok(EndpointFile {
             translation: TranslationTables::new(),
-            file: FramedMessageCodec.framed(file),
+            file,
+            index,
+            next_entry: 0,
             system_tx,
             system_rx,
+            rate: ReplayRate::default(),
+            anchor: None,
+            pending: None,
+            current_time: None,
+            play_to: None,
+            delay: None,
+            task: None,
+            control: None,
         })
     }
+
+    /// Respond to playback control messages from this sender with these types.
+    pub(crate) fn set_control_ids(&mut self, control: FileControlIds) {
+        self.control = Some(control);
+    }
+
+    /// Wake our task, if it's waiting, to pick up a change in playback.
+    fn wake(&mut self) {
+        self.delay = None;
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+
+    pub fn replay_rate(&self) -> ReplayRate {
+        self.rate
+    }
+
+    /// Change the playback rate, continuing from the current playback time.
+    pub fn set_replay_rate(&mut self, rate: ReplayRate) {
+        let now = Instant::now();
+        self.anchor = self.playback_time_at(now).map(|time| (now, time));
+        self.rate = rate;
+        self.wake();
+    }
+
+    /// The time of the first message in the file.
+    pub fn start_time(&self) -> Option<TimeVal> {
+        self.index.first().map(|entry| entry.time)
+    }
+
+    /// The time of the last message in the file.
+    pub fn end_time(&self) -> Option<TimeVal> {
+        self.index.last().map(|entry| entry.time)
+    }
+
+    /// Move the file to the given entry, ready to read it next.
+    fn seek_to_entry(&mut self, entry: usize) -> Result<()> {
+        if let Some(IndexEntry { offset, .. }) = self.index.get(entry) {
+            self.file.seek(SeekFrom::Start(*offset))?;
+        }
+        self.next_entry = entry;
+        self.pending = None;
+        Ok(())
+    }
+
+    /// Continue playback from the given file time, without playing the messages in between.
+    ///
+    /// Any descriptions skipped over are still applied, so later messages can be understood.
+    pub fn jump_to_time(&mut self, time: TimeVal) -> Result<()> {
+        let target = self.index.partition_point(|entry| entry.time < time);
+        let first_unplayed = self.next_entry - self.pending.iter().count();
+        for i in first_unplayed..target {
+            if self.index[i].message_type.is_system_message() {
+                self.seek_to_entry(i)?;
+                if let Some(msg) = self.read_message()? {
+                    self.handle_system_message(msg)?;
+                }
+            }
+        }
+        self.seek_to_entry(target)?;
+        self.current_time = Some(time);
+        self.anchor = Some((Instant::now(), time));
+        self.play_to = None;
+        self.wake();
+        Ok(())
+    }
+
+    /// Start playback again from the beginning of the file.
+    pub fn rewind(&mut self) -> Result<()> {
+        self.seek_to_entry(0)?;
+        self.current_time = None;
+        self.anchor = None;
+        self.play_to = None;
+        self.wake();
+        Ok(())
+    }
+
+    /// Immediately play every message up to the given file time, whatever the rate,
+    /// then continue at the current rate from there.
+    pub fn play_to_time(&mut self, time: TimeVal) {
+        self.play_to = Some(time);
+        self.wake();
+    }
+
+    /// We've played everything up to a play_to_time target: carry on from there.
+    fn finish_play_to(&mut self, target: TimeVal) {
+        let now = Instant::now();
+        if self.playback_time_at(now).is_none_or(|time| time < target) {
+            self.current_time = Some(target);
+            self.anchor = Some((now, target));
+        }
+    }
+
+    /// The file time we have played back to: at least the time of the last message played.
+    pub fn playback_time(&self) -> Option<TimeVal> {
+        self.playback_time_at(Instant::now())
+    }
+
+    fn playback_time_at(&self, now: Instant) -> Option<TimeVal> {
+        let scale = match self.rate {
+            ReplayRate::RealTime => 1.0,
+            ReplayRate::Scaled(scale) if scale > 0.0 => scale,
+            _ => return self.current_time,
+        };
+        match self.anchor {
+            Some((instant, time)) => {
+                let elapsed = now.duration_since(instant).as_secs_f64() * scale;
+                let time = match Duration::try_from_secs_f64(elapsed)
+                    .ok()
+                    .and_then(|elapsed| SystemTime::from(time).checked_add(elapsed))
+                {
+                    Some(time) => TimeVal::from(time),
+                    // Beyond any time we can represent: as far as we've actually got, then.
+                    None => return self.current_time,
+                };
+                match self.current_time {
+                    Some(current) if current > time => Some(current),
+                    _ => Some(time),
+                }
+            }
+            None => self.current_time,
+        }
+    }
+
+    /// Read the next entry from the file, if any.
+    fn read_message(&mut self) -> Result<Option<GenericMessage>> {
+        if self.next_entry >= self.index.len() {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; LOG_ENTRY_HEADER_SIZE];
+        match self.file.read_exact(&mut buf) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::from(e)),
+        }
+        let body_len = LogEntry::peek_body_len(&buf)?.expect("we read a whole header");
+        buf.resize(LOG_ENTRY_HEADER_SIZE + body_len, 0);
+        self.file.read_exact(&mut buf[LOG_ENTRY_HEADER_SIZE..])?;
+        let LogEntry(msg) = LogEntry::unbuffer_ref(&mut Bytes::from(buf))?;
+        self.next_entry += 1;
+        Ok(Some(msg))
+    }
+
+    /// Get the time of the next message to play, reading it from the file if required.
+    fn next_message_time(&mut self) -> Result<Option<TimeVal>> {
+        if self.pending.is_none() {
+            self.pending = self.read_message()?;
+        }
+        Ok(self.pending.as_ref().map(|msg| msg.header.time))
+    }
+
+    fn due(&mut self, time: TimeVal) -> Due {
+        if let Some(target) = self.play_to {
+            if time <= target {
+                return Due::Now;
+            }
+            self.play_to = None;
+            self.finish_play_to(target);
+        }
+        let scale = match self.rate {
+            ReplayRate::AsFastAsPossible => return Due::Now,
+            ReplayRate::RealTime => 1.0,
+            ReplayRate::Scaled(scale) if scale > 0.0 => scale,
+            ReplayRate::Scaled(_) => return Due::Never,
+        };
+        let now = Instant::now();
+        let (instant, anchor_time) = *self.anchor.get_or_insert((now, time));
+        // A slow enough rate puts the message beyond any time we can represent.
+        let due = match Duration::try_from_secs_f64(
+            file_time_difference(time, anchor_time).as_secs_f64() / scale,
+        )
+        .ok()
+        .and_then(|wall_elapsed| instant.checked_add(wall_elapsed))
+        {
+            Some(due) => due,
+            None => return Due::Never,
+        };
+        if due <= now {
+            Due::Now
+        } else {
+            Due::In(due - now)
+        }
+    }
+
+    /// Apply the changes queued up by system messages we've played.
+    fn process_system_changes(&mut self, dispatcher: &mut TypeDispatcher) -> Result<()> {
+        loop {
+            let msg_poll = self.system_rx.poll().map_err(|()| {
+                Error::OtherMessage(String::from(
+                    "error when polling system change message channel",
+                ))
+            })?;
+            match msg_poll {
+                Async::Ready(Some(SystemMessage::SenderDescription(desc))) => {
+                    self.add_remote_sender(desc, dispatcher)?;
+                }
+                Async::Ready(Some(SystemMessage::TypeDescription(desc))) => {
+                    self.add_remote_type(desc, dispatcher)?;
+                }
+                // Nothing else recorded in a file affects playback.
+                Async::Ready(Some(_)) => (),
+                Async::Ready(None) | Async::NotReady => return Ok(()),
+            }
+        }
+    }
+
+    /// Play back the messages that are due.
+    ///
+    /// Returns `Async::Ready(Some(()))` after playing some messages,
+    /// `Async::NotReady` while waiting for the next message to be due,
+    /// and `Async::Ready(None)` once the end of the file has been reached.
+    pub(crate) fn poll_playback(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+    ) -> Poll<Option<()>, Error> {
+        // Apply any descriptions we skipped over when seeking.
+        self.process_system_changes(dispatcher)?;
+        let mut played = 0;
+        while played < MAX_PER_POLL {
+            let time = match self.next_message_time()? {
+                Some(time) => time,
+                None => {
+                    if let Some(target) = self.play_to.take() {
+                        self.finish_play_to(target);
+                    }
+                    if played > 0 {
+                        break;
+                    }
+                    return Ok(Async::Ready(None));
+                }
+            };
+            match self.due(time) {
+                Due::Now => (),
+                _ if played > 0 => break,
+                Due::In(wait) => {
+                    let deadline = Instant::now() + wait;
+                    let delay = self.delay.get_or_insert_with(|| Delay::new(deadline));
+                    delay.reset(deadline);
+                    if delay
+                        .poll()
+                        .map_err(|e| Error::OtherMessage(e.to_string()))?
+                        .is_not_ready()
+                    {
+                        self.task = Some(task::current());
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Due::Never => {
+                    self.task = Some(task::current());
+                    return Ok(Async::NotReady);
+                }
+            }
+            let msg = self.pending.take().expect("we just peeked at it");
+            self.current_time = Some(msg.header.time);
+            dispatch_message(self, msg, dispatcher)?;
+            self.process_system_changes(dispatcher)?;
+            played += 1;
+        }
+        Ok(Async::Ready(Some(())))
+    }
 }
+
 impl Endpoint for EndpointFile {
     fn translation_tables(&self) -> &TranslationTables {
         &self.translation
@@ -44,15 +427,52 @@ impl Endpoint for EndpointFile {
         &mut self.translation
     }
 
-    fn send_system_change(&self, _message: SystemMessage) -> Result<()> {
-        unimplemented!()
+    fn send_system_change(&self, message: SystemMessage) -> Result<()> {
+        self.system_tx
+            .unbounded_send(message)
+            .map_err(|e| Error::OtherMessage(e.to_string()))?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        // Nobody to tell: just stop playing.
+        self.next_entry = self.index.len();
+        self.pending = None;
+        self.play_to = None;
+        self.wake();
+        Ok(())
     }
 
     fn buffer_generic_message(
         &mut self,
-        _msg: GenericMessage,
+        msg: GenericMessage,
         _class: ClassOfService,
     ) -> Result<()> {
-        unimplemented!()
+        // There's nobody on the other end of a file to send to,
+        // but a controller may be asking us to change playback.
+        let control = match self.control {
+            Some(control) if LocalId(msg.header.sender) == control.sender => control,
+            _ => return Ok(()),
+        };
+        let message_type = LocalId(msg.header.message_type);
+        if message_type == control.set_replay_rate {
+            let msg: Message<SetReplayRate> = Message::try_from_generic(&msg)?;
+            let rate = f64::from(msg.body.0);
+            if !rate.is_finite() {
+                return Err(Error::OtherMessage(format!(
+                    "controller asked for an invalid replay rate {}",
+                    rate
+                )));
+            }
+            self.set_replay_rate(ReplayRate::Scaled(
+                rate.clamp(0.0, MAX_REQUESTED_REPLAY_RATE),
+            ));
+        } else if message_type == control.reset {
+            self.rewind()?;
+        } else if message_type == control.play_to_time {
+            let msg: Message<PlayToTime> = Message::try_from_generic(&msg)?;
+            self.play_to_time(msg.body.0);
+        }
+        Ok(())
     }
 }
@@ -6,10 +6,14 @@ use crate::types::*;
 use crate::{
     async_io::{
         codec::{self, FramedMessageCodec},
-        endpoint_channel::{poll_and_dispatch, EndpointChannel},
+        endpoint_channel::{poll_and_dispatch, EndpointChannel, UdpEndpointChannel},
     },
+    constants,
+    descriptions::{UdpDescription, UdpInnerDescription},
     endpoint::*,
-    Error, GenericMessage, MatchingTable, Result, TranslationTables, TypeDispatcher,
+    log::EndpointLog,
+    Error, GenericBody, GenericMessage, LogFileNames, LogFlags, LogMode, Message, Result,
+    TranslationTables, TypeDispatcher,
 };
 use futures::sync::mpsc;
 use std::{
@@ -18,7 +22,7 @@ use std::{
 };
 use tokio::{
     net::{TcpStream, UdpFramed},
-    prelude::*,
+    prelude::{task::Task, *},
 };
 
 pub type MessageFramed = codec::MessageFramed<TcpStream>;
@@ -28,36 +32,189 @@ pub type MessageFramedUdp = UdpFramed<FramedMessageCodec>;
 pub struct EndpointIp {
     translation: TranslationTables,
     reliable_channel: Arc<Mutex<EndpointChannel<MessageFramed>>>,
-    low_latency_channel: Option<()>,
+    low_latency_channel: Option<Arc<Mutex<UdpEndpointChannel>>>,
     system_rx: mpsc::UnboundedReceiver<SystemMessage>,
     system_tx: mpsc::UnboundedSender<SystemMessage>,
+    log: EndpointLog,
+    remote_log_mode: LogMode,
+    requested_log: EndpointLog,
+    descriptions_needed: bool,
+    announced: bool,
+    closing: bool,
+    /// The task polling us, to wake if we're asked to close or have something to send.
+    task: Option<Task>,
 }
 impl EndpointIp {
+    /// Create an endpoint from an already-handshaken reliable stream.
+    ///
+    /// If a low-latency channel is supplied, its address is described to the other end,
+    /// and once the other end describes its own, messages not requiring reliability
+    /// are sent over it.
     pub(crate) fn new(
-        reliable_stream: TcpStream //low_latency_channel: Option<MessageFramedUdp>
-    ) -> EndpointIp {
+        reliable_stream: TcpStream,
+        low_latency_channel: Option<MessageFramedUdp>,
+    ) -> Result<EndpointIp> {
         let framed = codec::apply_message_framing(reliable_stream);
         let (system_tx, system_rx) = mpsc::unbounded();
-        EndpointIp {
+        let mut ep = EndpointIp {
             translation: TranslationTables::new(),
             reliable_channel: EndpointChannel::new(framed),
-            low_latency_channel: None,
+            low_latency_channel: low_latency_channel.map(UdpEndpointChannel::new),
             system_tx,
             system_rx,
+            log: EndpointLog::new(),
+            remote_log_mode: LogMode::none(),
+            requested_log: EndpointLog::new(),
+            descriptions_needed: false,
+            announced: false,
+            closing: false,
+            task: None,
+        };
+        ep.pack_udp_description()?;
+        Ok(ep)
+    }
+
+    /// Tell the other end where to send low-latency messages, if we have a channel for them.
+    fn pack_udp_description(&mut self) -> Result<()> {
+        let addr = match &self.low_latency_channel {
+            Some(channel) => channel.lock()?.local_addr()?,
+            None => return Ok(()),
+        };
+        let msg = Message::<UdpInnerDescription>::from(UdpDescription::new(addr));
+        self.buffer_generic_message(
+            msg.try_into_generic()?,
+            ClassOfService::from(ServiceFlags::RELIABLE),
+        )
+    }
+
+    /// Start logging the messages we receive and/or send to the named files,
+    /// replacing any logging in progress.
+    ///
+    /// When logging outgoing messages, all our descriptions are sent (and so logged) again,
+    /// so the log can be understood from the start.
+    pub(crate) fn start_logging(
+        &mut self,
+        names: &LogFileNames,
+        dispatcher: &TypeDispatcher,
+    ) -> Result<()> {
+        self.log = EndpointLog::open(names)?;
+        if self.log.log_mode().contains(LogFlags::OUTGOING) {
+            self.pack_all_descriptions(dispatcher)?;
+        }
+        Ok(())
+    }
+
+    /// Record the logging mode the other end asked for in its cookie.
+    ///
+    /// Logging only starts once the other end sends the file names in a log description.
+    pub(crate) fn set_remote_log_mode(&mut self, mode: LogMode) {
+        self.remote_log_mode = mode;
+    }
+
+    /// Start logging to the files the other end asked for.
+    ///
+    /// The mode from the cookie takes priority, if one was given:
+    /// otherwise, we log the directions the other end supplied names for.
+    fn start_requested_logging(&mut self, names: &LogFileNames) -> Result<()> {
+        let mode = if self.remote_log_mode.is_none() {
+            names.log_mode()
+        } else {
+            self.remote_log_mode
+        };
+        self.requested_log = match EndpointLog::open(&names.with_mode(mode)) {
+            Ok(log) => log,
+            // Typically because the file exists: that's no reason to drop the connection.
+            Err(e) => {
+                eprintln!("Could not start logging as requested: {}", e);
+                return Ok(());
+            }
+        };
+        // We're in the middle of polling our channels here, so the descriptions
+        // get packed once we're done with them.
+        if self.requested_log.log_mode().contains(LogFlags::OUTGOING) {
+            self.descriptions_needed = true;
+        }
+        Ok(())
+    }
+
+    /// Record a message we've received, if we're logging incoming messages.
+    pub(crate) fn log_incoming(&mut self, msg: &GenericMessage) -> Result<()> {
+        self.log.log_incoming(msg)?;
+        self.requested_log.log_incoming(msg)
+    }
+
+    /// Whether local handlers have been told about this endpoint's connection yet.
+    pub(crate) fn announced(&self) -> bool {
+        self.announced
+    }
+
+    pub(crate) fn set_announced(&mut self) {
+        self.announced = true;
+    }
+
+    /// Whether we have a low-latency channel that knows where to send.
+    fn low_latency_ready(&self) -> Result<bool> {
+        match &self.low_latency_channel {
+            Some(channel) => Ok(channel.lock()?.peer().is_some()),
+            None => Ok(false),
         }
     }
 
     pub(crate) fn poll_endpoint(&mut self, dispatcher: &mut TypeDispatcher) -> Poll<(), Error> {
-        let channel_arc = Arc::clone(&self.reliable_channel);
-        let mut channel = channel_arc
-            .lock()
-            .map_err(|e| Error::OtherMessage(e.to_string()))?;
-        let _ = channel.poll_complete()?;
-        let mut closed = poll_and_dispatch(self, channel.deref_mut(), dispatcher)?.is_ready();
+        let mut closed = {
+            let channel_arc = Arc::clone(&self.reliable_channel);
+            let mut channel = channel_arc
+                .lock()
+                .map_err(|e| Error::OtherMessage(e.to_string()))?;
+            let _ = channel.poll_complete()?;
+            poll_and_dispatch(self, channel.deref_mut(), dispatcher)?.is_ready()
+        };
 
-        // todo UDP here.
+        if let Some(udp_arc) = self.low_latency_channel.as_ref().map(Arc::clone) {
+            let mut udp_channel = udp_arc
+                .lock()
+                .map_err(|e| Error::OtherMessage(e.to_string()))?;
+            let _ = udp_channel.poll_complete()?;
+            // The UDP channel never "closes": only the reliable channel decides that.
+            let _ = poll_and_dispatch(self, udp_channel.deref_mut(), dispatcher)?;
+        }
 
         // Now, process the messages we sent ourself.
+        if self.process_system_changes(dispatcher)? {
+            closed = true;
+        }
+
+        if self.descriptions_needed {
+            self.descriptions_needed = false;
+            self.pack_all_descriptions(dispatcher)?;
+        }
+
+        if self.closing {
+            // Flushes everything queued, then shuts down our side of the connection.
+            let mut channel = self
+                .reliable_channel
+                .lock()
+                .map_err(|e| Error::OtherMessage(e.to_string()))?;
+            if channel.close()?.is_ready() {
+                closed = true;
+            }
+        }
+
+        if closed {
+            Ok(Async::Ready(()))
+        } else {
+            self.task = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Apply the changes queued up by system messages we've received.
+    ///
+    /// Returns true if the queue has been closed.
+    pub(crate) fn process_system_changes(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+    ) -> Result<bool> {
         loop {
             let msg_poll = self.system_rx.poll().map_err(|()| {
                 Error::OtherMessage(String::from(
@@ -65,56 +222,37 @@ impl EndpointIp {
                 ))
             })?;
             match msg_poll {
-                Async::Ready(None) => {
-                    closed = true;
-                    break;
-                }
+                Async::Ready(None) => return Ok(true),
                 Async::Ready(Some(msg)) => match msg {
                     SystemMessage::SenderDescription(desc) => {
-                        let local_id = dispatcher
-                            .register_sender(SenderName(desc.name.clone()))?
-                            .get();
-                        eprintln!(
-                            "Registering sender {:?}: local {:?} = remote {:?}",
-                            desc.name, local_id, desc.which
-                        );
-                        let _ = self.translation.add_remote_entry(
-                            desc.name,
-                            RemoteId(desc.which),
-                            local_id,
-                        )?;
+                        self.add_remote_sender(desc, dispatcher)?;
                     }
                     SystemMessage::TypeDescription(desc) => {
-                        let local_id = dispatcher.register_type(TypeName(desc.name.clone()))?.get();
-                        eprintln!(
-                            "Registering type {:?}: local {:?} = remote {:?}",
-                            desc.name, local_id, desc.which
-                        );
-                        let _ = self.translation.add_remote_entry(
-                            desc.name,
-                            RemoteId(desc.which),
-                            local_id,
-                        )?;
+                        self.add_remote_type(desc, dispatcher)?;
                     }
-                    SystemMessage::UdpDescription(desc) => {
-                        eprintln!("UdpDescription: {:?}", desc);
-                    }
-                    SystemMessage::LogDescription(desc) => {
-                        eprintln!("LogDescription: {:?}", desc);
+                    SystemMessage::UdpDescription(desc) => match &self.low_latency_channel {
+                        Some(channel) => {
+                            eprintln!("Sending low-latency messages to {:?}", desc.socket_address);
+                            channel.lock()?.set_peer(desc.socket_address);
+                        }
+                        None => {
+                            eprintln!(
+                                "Ignoring UdpDescription, no low-latency channel: {:?}",
+                                desc
+                            );
+                        }
+                    },
+                    SystemMessage::LogDescription(names) => {
+                        self.start_requested_logging(&names)?;
                     }
                     SystemMessage::DisconnectMessage => {
-                        eprintln!("DesconnectMessage");
+                        // The other end is going away: finish up our side too.
+                        self.closing = true;
                     }
                 },
-                Async::NotReady => break,
+                Async::NotReady => return Ok(false),
             }
         }
-
-        if closed {
-            Ok(Async::Ready(()))
-        } else {
-            Ok(Async::NotReady)
-        }
     }
 }
 
@@ -127,6 +265,24 @@ impl Endpoint for EndpointIp {
         &mut self.translation
     }
 
+    fn close(&mut self) -> Result<()> {
+        if self.closing {
+            return Ok(());
+        }
+        let msg = GenericMessage::new(
+            None,
+            constants::DISCONNECT_MESSAGE,
+            SenderId(0),
+            GenericBody::default(),
+        );
+        self.buffer_generic_message(msg, ClassOfService::from(ServiceFlags::RELIABLE))?;
+        self.closing = true;
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+        Ok(())
+    }
+
     fn send_system_change(&self, message: SystemMessage) -> Result<()> {
         println!("send_system_change {:?}", message);
         self.system_tx
@@ -135,26 +291,50 @@ impl Endpoint for EndpointIp {
         Ok(())
     }
 
+    /// Messages buffered once we've started closing are dropped.
     fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
-        if class.contains(ServiceFlags::RELIABLE) || self.low_latency_channel.is_none() {
+        if self.closing {
+            return Ok(());
+        }
+        self.log.log_outgoing(&msg)?;
+        self.requested_log.log_outgoing(&msg)?;
+        let result = if class.contains(ServiceFlags::RELIABLE) || !self.low_latency_ready()? {
             // We either need reliable, or don't have low-latency
             let mut channel = self
                 .reliable_channel
                 .lock()
                 .map_err(|e| Error::OtherMessage(e.to_string()))?;
-            match channel
-                .start_send(msg)
-                .map_err(|e| Error::OtherMessage(e.to_string()))?
-            {
-                AsyncSink::Ready => Ok(()),
-                AsyncSink::NotReady(_) => Err(Error::OtherMessage(String::from(
-                    "Didn't have room in send buffer",
-                ))),
-            }
+            start_send_generic(channel.deref_mut(), msg)
         } else {
             // have and can use low-latency
-            unimplemented!()
+            let mut channel = self
+                .low_latency_channel
+                .as_ref()
+                .expect("just checked that we have a low-latency channel")
+                .lock()
+                .map_err(|e| Error::OtherMessage(e.to_string()))?;
+            start_send_generic(channel.deref_mut(), msg)
+        };
+        // Get polled soon, to actually send it.
+        if let Some(task) = &self.task {
+            task.notify();
         }
+        result
+    }
+}
+
+fn start_send_generic<T>(channel: &mut T, msg: GenericMessage) -> Result<()>
+where
+    T: Sink<SinkItem = GenericMessage, SinkError = Error>,
+{
+    match channel
+        .start_send(msg)
+        .map_err(|e| Error::OtherMessage(e.to_string()))?
+    {
+        AsyncSink::Ready => Ok(()),
+        AsyncSink::NotReady(_) => Err(Error::OtherMessage(String::from(
+            "Didn't have room in send buffer",
+        ))),
     }
 }
 
@@ -169,7 +349,7 @@ mod tests {
         let addr = "127.0.0.1:3883".parse().unwrap();
         let _ = connect_tcp(addr)
             .and_then(|stream| {
-                let ep = EndpointIp::new(stream);
+                let ep = EndpointIp::new(stream, None).unwrap();
                 for _i in 0..4 {
                     let _ = ep
                         .reliable_channel
@@ -194,7 +374,7 @@ mod tests {
         let addr = "127.0.0.1:3883".parse().unwrap();
         let _ = connect_tcp(addr)
             .and_then(|stream| {
-                let mut ep = EndpointIp::new(stream);
+                let mut ep = EndpointIp::new(stream, None).unwrap();
                 let mut disp = TypeDispatcher::new();
                 for _i in 0..4 {
                     let _ = ep.poll_endpoint(&mut disp).unwrap();
@@ -6,16 +6,24 @@ pub mod codec;
 pub mod connect;
 pub mod connection_file;
 pub mod connection_ip;
+pub mod clock;
 pub mod cookie;
+pub mod device;
 pub mod endpoint_channel;
 pub mod endpoint_file;
 pub mod endpoint_ip;
 pub mod ping;
+pub mod registry;
 pub mod util;
 
 pub use self::{
     codec::apply_message_framing,
-    connect::connect_tcp,
+    connect::{
+        connect_lob_packet, connect_tcp, connect_tcp_with_remote_logging, make_udp_channel,
+    },
+    connection_file::{ConnectionFile, ConnectionFileStream, ReplayRate},
     connection_ip::{ConnectionIp, ConnectionIpStream},
+    device::{connect_device, DeviceConnection},
+    registry::{ConnectionRegistry, ConnectionRegistryDriver},
     util::*,
 };
@@ -2,35 +2,54 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use crate::{ping::Client as RawClient, Connection, Error, LocalId, Result, SenderId, SenderName};
-use std::{
-    sync::{Arc, Weak},
-    time::Duration,
+use crate::{
+    ping::{Client as RawClient, Config},
+    Connection, Error, LocalId, Result, SenderId, SenderName,
 };
+use std::sync::Arc;
 use tokio::prelude::*;
-use tokio::{net::TcpStream, timer::Interval};
+use tokio::timer::Interval;
 
+/// Pings the server regularly, watching for it flatlining and recovering.
+///
+/// Subscribe to those events with `client().add_event_listener()`.
 pub struct Client<T: Connection + 'static> {
     client: RawClient<T>,
     interval: Interval,
 }
 
 impl<T: Connection + 'static> Client<T> {
-    fn new_impl(client: RawClient<T>) -> Result<Client<T>> {
+    fn new_impl(client: RawClient<T>, config: Config) -> Result<Client<T>> {
         Ok(Client {
             client,
-            interval: Interval::new_interval(Duration::from_secs(1)),
+            interval: Interval::new_interval(config.interval),
         })
     }
     pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Client<T>> {
-        Client::new_impl(RawClient::new(sender, connection)?)
+        Client::with_config(sender, connection, Config::default())
+    }
+
+    pub fn with_config(
+        sender: LocalId<SenderId>,
+        connection: Arc<T>,
+        config: Config,
+    ) -> Result<Client<T>> {
+        Client::new_impl(RawClient::with_config(sender, connection, config)?, config)
     }
 
     pub fn new_from_name(
         sender: impl Into<SenderName> + Clone,
         connection: Arc<T>,
     ) -> Result<Client<T>> {
-        Client::new_impl(RawClient::new_from_name(sender, connection)?)
+        Client::new_impl(
+            RawClient::new_from_name(sender, connection)?,
+            Config::default(),
+        )
+    }
+
+    /// The underlying client: for round-trip times, flatlined state and events.
+    pub fn client(&self) -> &RawClient<T> {
+        &self.client
     }
 }
 
@@ -42,19 +61,96 @@ impl<T: Connection + 'static> Stream for Client<T> {
             .interval
             .poll()
             .map_err(|e| Error::OtherMessage(e.to_string())));
+        let _ = self.client.check_ping_cycle()?;
+        Ok(Async::Ready(Some(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{async_io::ConnectionIp, ping::PingEvent, ping::Server, StaticSenderName};
+    use std::{net::SocketAddr, sync::Mutex, time::Duration};
+    use tokio::runtime::current_thread::Runtime;
+
+    const DEVICE: StaticSenderName = StaticSenderName(b"Tracker0");
 
-        // match self.interval.poll()? {
-        //     Async::NotReady => Ok(Async::NotReady),
-        //     Async::Ready(Some(_)) => {
-        if let Some(radio_silence) = self.client.check_ping_cycle()? {
-            eprintln!(
-                "It has been {} since the first unanwered ping was sent to the server!",
-                radio_silence
-            );
+    fn server_with_ping(addr: SocketAddr) -> (Arc<ConnectionIp>, Server) {
+        let server = ConnectionIp::new_server(None, Some(addr)).unwrap();
+        let ping_server = Server::new_from_name(DEVICE, Arc::clone(&server)).unwrap();
+        (server, ping_server)
+    }
+
+    /// Poll everything until the condition holds.
+    fn run_until(
+        rt: &mut Runtime,
+        server: Option<&ConnectionIp>,
+        client: &ConnectionIp,
+        ping: &mut Client<ConnectionIp>,
+        mut done: impl FnMut(&Client<ConnectionIp>) -> bool,
+    ) {
+        rt.block_on(
+            future::poll_fn(|| -> Poll<(), Error> {
+                if let Some(server) = server {
+                    let _ = server.poll_endpoints()?;
+                }
+                let _ = client.poll_endpoints()?;
+                while let Async::Ready(Some(())) = ping.poll()? {}
+                if done(ping) {
+                    Ok(Async::Ready(()))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("condition should have been reached");
+    }
+
+    #[test]
+    fn flatline_and_recover() {
+        let (server, ping_server) = server_with_ping("127.0.0.1:0".parse().unwrap());
+        let addr = server.server_addr().unwrap().unwrap();
+
+        let client = ConnectionIp::new_reconnecting_client(None, None, addr);
+        let sender = client.register_sender(DEVICE).unwrap();
+        let config = Config {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(300),
+        };
+        let mut ping = Client::with_config(sender, Arc::clone(&client), config).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = Arc::clone(&events);
+            ping.client()
+                .add_event_listener(move |event| events.lock().unwrap().push(event))
+                .unwrap();
+        }
+
+        let mut rt = Runtime::new().unwrap();
+        run_until(&mut rt, Some(&server), &client, &mut ping, |ping| {
+            ping.client().round_trip_time().unwrap().is_some()
+        });
+        assert!(events.lock().unwrap().is_empty());
+
+        drop((server, ping_server));
+        run_until(&mut rt, None, &client, &mut ping, |ping| {
+            ping.client().is_flatlined().unwrap()
+        });
+        let seen = events.lock().unwrap().clone();
+        match seen[..] {
+            [PingEvent::Flatlined { radio_silence }] => assert!(radio_silence >= config.timeout),
+            ref other => panic!("expected one flatlined event, got {:?}", other),
+        }
+
+        let (server, _ping_server) = server_with_ping(addr);
+        run_until(&mut rt, Some(&server), &client, &mut ping, |ping| {
+            !ping.client().is_flatlined().unwrap()
+        });
+        let seen = events.lock().unwrap().clone();
+        match seen[..] {
+            [PingEvent::Flatlined { .. }, PingEvent::Recovered { .. }] => {}
+            ref other => panic!("expected flatlined then recovered, got {:?}", other),
         }
-        Ok(Async::Ready(Some(())))
-        //     }
-        //     Async::Ready(None) => Ok(Async::Ready(None)),
-        // }
     }
 }
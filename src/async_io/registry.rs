@@ -0,0 +1,295 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use crate::{
+    async_io::{device::connect_ip, ConnectionIp},
+    locator::Transport,
+    Connection, DeviceLocator, Error, LocalId, Result, SenderId,
+};
+use futures::{future::Shared, task::AtomicTask};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, Weak},
+};
+use tokio::prelude::*;
+
+/// The error is kept as a message, since the `Error` itself may not be shareable between threads.
+type PendingConnection = Shared<Box<dyn Future<Item = Arc<ConnectionIp>, Error = String> + Send>>;
+
+/// Host and port.
+type ServerKey = (String, u16);
+
+#[derive(Clone)]
+enum Entry {
+    /// Someone asked for this server and the connection isn't done yet:
+    /// anyone else asking waits on the same attempt.
+    Connecting(PendingConnection),
+    /// The registry doesn't keep connections alive by itself:
+    /// that's up to the handles it gave out.
+    Connected(Weak<ConnectionIp>),
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Connecting(_) => write!(f, "Connecting"),
+            Entry::Connected(conn) => f.debug_tuple("Connected").field(conn).finish(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RegistryInner {
+    connections: Mutex<HashMap<ServerKey, Entry>>,
+    connection_added: AtomicTask,
+}
+
+impl RegistryInner {
+    fn finish_connecting(&self, key: ServerKey, result: &Result<Arc<ConnectionIp>>) {
+        if let Ok(mut connections) = self.connections.lock() {
+            match result {
+                Ok(conn) => {
+                    connections.insert(key, Entry::Connected(Arc::downgrade(conn)));
+                    self.connection_added.notify();
+                }
+                Err(_) => {
+                    connections.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Get handles to all the connections still in use, forgetting the rest.
+    fn live_connections(&self) -> Result<Vec<(ServerKey, Arc<ConnectionIp>)>> {
+        let mut connections = self.connections.lock()?;
+        connections.retain(|_, entry| match entry {
+            Entry::Connecting(_) => true,
+            Entry::Connected(conn) => conn.upgrade().is_some(),
+        });
+        Ok(connections
+            .iter()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Connected(conn) => conn.upgrade().map(|conn| (key.clone(), conn)),
+                Entry::Connecting(_) => None,
+            })
+            .collect())
+    }
+
+    /// Stop handing out this connection, so the next request for its server reconnects.
+    fn forget(&self, key: &ServerKey, conn: &Arc<ConnectionIp>) -> Result<()> {
+        let mut connections = self.connections.lock()?;
+        let same = match connections.get(key) {
+            Some(Entry::Connected(existing)) => existing
+                .upgrade()
+                .is_none_or(|existing| Arc::ptr_eq(&existing, conn)),
+            _ => false,
+        };
+        if same {
+            connections.remove(key);
+        }
+        Ok(())
+    }
+}
+
+/// Shares one client `ConnectionIp` per server among all the devices opened through it,
+/// like asking the C++ `vrpn_get_connection_by_name` for "Tracker0@host" and "Button0@host".
+///
+/// Connections are reference-counted by the handles given out: once they're all dropped,
+/// the connection closes and the next request for that server makes a new one.
+/// The connections are all polled by the future from `driver()`, which must be spawned
+/// (once) on the runtime using the registry.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionRegistry {
+    inner: Arc<RegistryInner>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry::default()
+    }
+
+    /// Get a connection to the server named in a device locator, like "Tracker0@host:port",
+    /// and the device's sender ID on it.
+    ///
+    /// Connections are shared by host and port: if the first request for a server
+    /// is still connecting, later ones wait for it, and use its transport.
+    pub fn get(
+        &self,
+        locator: &str,
+    ) -> impl Future<Item = (Arc<ConnectionIp>, LocalId<SenderId>), Error = Error> {
+        let pending = DeviceLocator::parse(locator).and_then(|locator| {
+            let pending = self.connection_for(&locator)?;
+            Ok((pending, locator))
+        });
+        future::result(pending).and_then(|(pending, locator)| {
+            pending
+                .map_err(|e| Error::OtherMessage((*e).clone()))
+                .and_then(move |conn| {
+                    let conn = Arc::clone(&*conn);
+                    let sender = conn.register_sender(locator.device())?;
+                    Ok((conn, sender))
+                })
+        })
+    }
+
+    /// Get the future that polls all the registry's connections: it never completes.
+    pub fn driver(&self) -> ConnectionRegistryDriver {
+        ConnectionRegistryDriver {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    fn connection_for(&self, locator: &DeviceLocator) -> Result<PendingConnection> {
+        let port = match (locator.transport(), locator.port()) {
+            (Transport::File, _) | (_, None) => {
+                return Err(Error::OtherMessage(format!(
+                    "{} is a file: only network connections are shared",
+                    locator.address()
+                )));
+            }
+            (_, Some(port)) => port,
+        };
+        let key = (locator.address().to_string(), port);
+        let mut connections = self.inner.connections.lock()?;
+        match connections.get(&key) {
+            Some(Entry::Connecting(pending)) => return Ok(pending.clone()),
+            Some(Entry::Connected(conn)) => {
+                if let Some(conn) = conn.upgrade() {
+                    let ready: Box<dyn Future<Item = _, Error = String> + Send> =
+                        Box::new(future::ok(conn));
+                    return Ok(ready.shared());
+                }
+            }
+            None => (),
+        }
+
+        let inner = Arc::downgrade(&self.inner);
+        let finished_key = key.clone();
+        let connecting: Box<dyn Future<Item = _, Error = _> + Send> =
+            Box::new(connect_ip(locator.clone()).then(move |result| {
+                if let Some(inner) = inner.upgrade() {
+                    inner.finish_connecting(finished_key, &result);
+                }
+                result.map_err(|e| e.to_string())
+            }));
+        let pending = connecting.shared();
+        connections.insert(key, Entry::Connecting(pending.clone()));
+        Ok(pending)
+    }
+}
+
+/// Polls every connection in a `ConnectionRegistry`.
+///
+/// A connection whose server goes away (or that fails) is dropped from the registry.
+#[derive(Debug)]
+pub struct ConnectionRegistryDriver {
+    inner: Arc<RegistryInner>,
+}
+
+impl Future for ConnectionRegistryDriver {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.inner.connection_added.register();
+        for (key, conn) in self.inner.live_connections()? {
+            let live = match poll_connection(&conn) {
+                Ok(live) => live,
+                Err(e) => {
+                    eprintln!("dropping connection to {}:{}: {}", key.0, key.1, e);
+                    false
+                }
+            };
+            if !live {
+                self.inner.forget(&key, &conn)?;
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Poll a connection until it has nothing more to do: returns whether it is still connected.
+fn poll_connection(conn: &ConnectionIp) -> Result<bool> {
+    loop {
+        match conn.poll_endpoints()? {
+            Async::NotReady => break,
+            Async::Ready(Some(())) => (),
+            Async::Ready(None) => return Ok(false),
+        }
+    }
+    Ok(!conn.endpoints().lock()?.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Run a future to completion, polling the server and the registry's connections meanwhile.
+    fn run<F>(
+        rt: &mut Runtime,
+        server: &ConnectionIp,
+        driver: &mut ConnectionRegistryDriver,
+        mut f: F,
+    ) -> F::Item
+    where
+        F: Future<Error = Error>,
+    {
+        rt.block_on(
+            future::poll_fn(|| -> Poll<F::Item, Error> {
+                let _ = server.poll_endpoints()?;
+                let _ = driver.poll()?;
+                f.poll()
+            })
+            .timeout(Duration::from_secs(5)),
+        )
+        .expect("should be able to connect")
+    }
+
+    #[test]
+    fn shared_connection() {
+        let server = ConnectionIp::new_server(None, Some("127.0.0.1:0".parse().unwrap())).unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+        let registry = ConnectionRegistry::new();
+        let mut driver = registry.driver();
+        let mut rt = Runtime::new().unwrap();
+
+        // Ask for both devices before either connection is done.
+        let both = registry
+            .get(&format!("Tracker0@tcp://{}", addr))
+            .join(registry.get(&format!("Button0@tcp://{}", addr)));
+        let ((tracker_conn, tracker), (button_conn, button)) =
+            run(&mut rt, &server, &mut driver, both);
+        assert!(Arc::ptr_eq(&tracker_conn, &button_conn));
+        assert_ne!(tracker, button);
+
+        // Once connected, it's handed out directly, whatever the transport asked for.
+        let (again, again_tracker) = run(
+            &mut rt,
+            &server,
+            &mut driver,
+            registry.get(&format!("Tracker0@{}", addr)),
+        );
+        assert!(Arc::ptr_eq(&tracker_conn, &again));
+        assert_eq!(tracker, again_tracker);
+
+        // When nobody is using it any more, it goes away, and the next request reconnects.
+        let weak = Arc::downgrade(&tracker_conn);
+        drop((tracker_conn, button_conn, again));
+        assert!(weak.upgrade().is_none());
+        let _ = run(
+            &mut rt,
+            &server,
+            &mut driver,
+            registry.get(&format!("Tracker0@tcp://{}", addr)),
+        );
+    }
+
+    #[test]
+    fn no_files() {
+        let registry = ConnectionRegistry::new();
+        assert!(registry.get("Tracker0@file:log.vrpn").wait().is_err());
+    }
+}
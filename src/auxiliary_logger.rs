@@ -0,0 +1,325 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::prelude::*;
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    log::LogFileNames,
+    Buffer, BufferSize, BytesRequired, Connection, ConstantBufferSize, EmptyMessage, EmptyResult,
+    Error, LocalId, Message, MessageTypeIdentifier, Result, SenderId, SenderName, ServiceFlags,
+    StaticTypeName, TypedMessageBody, Unbuffer,
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Log file names for the logging server's own connection ("local")
+/// and for the server it is logging ("remote").
+///
+/// On the wire, four i32 string lengths (local in, local out, remote in, remote out)
+/// are followed by the strings, without null terminators.
+/// An empty (absent) name means "not logging" in that direction.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LoggingNames {
+    pub local: LogFileNames,
+    pub remote: LogFileNames,
+}
+
+impl LoggingNames {
+    pub fn new(local: LogFileNames, remote: LogFileNames) -> LoggingNames {
+        LoggingNames { local, remote }
+    }
+
+    fn names(&self) -> [&Option<Bytes>; 4] {
+        [
+            self.local.in_log(),
+            self.local.out_log(),
+            self.remote.in_log(),
+            self.remote.out_log(),
+        ]
+    }
+}
+
+fn name_len(name: &Option<Bytes>) -> usize {
+    name.as_ref().map(|n| n.len()).unwrap_or(0)
+}
+
+impl BufferSize for LoggingNames {
+    fn buffer_size(&self) -> usize {
+        4 * i32::constant_buffer_size()
+            + self
+                .names()
+                .iter()
+                .fold(0, |acc, name| acc + name_len(name))
+    }
+}
+
+impl Buffer for LoggingNames {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        let names = self.names();
+        for name in &names {
+            (name_len(name) as i32).buffer_ref(buf)?;
+        }
+        for name in names.iter().filter_map(|name| name.as_ref()) {
+            buf.put(name);
+        }
+        Ok(())
+    }
+}
+
+impl Unbuffer for LoggingNames {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let mut lens = [0_usize; 4];
+        for len in lens.iter_mut() {
+            let v = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+            if v < 0 {
+                return Err(Error::OtherMessage(format!(
+                    "auxiliary logger message has negative name length {}",
+                    v
+                )));
+            }
+            *len = v as usize;
+        }
+        let needed: usize = lens.iter().sum();
+        if buf.len() < needed {
+            return Err(Error::NeedMoreData(BytesRequired::Exactly(
+                needed - buf.len(),
+            )));
+        }
+        let mut names = lens.iter().map(|len| buf.split_to(*len));
+        let mut next_pair = || {
+            let in_name = names.next();
+            let out_name = names.next();
+            LogFileNames::from_names(in_name, out_name)
+        };
+        let local = next_pair();
+        let remote = next_pair();
+        Ok(LoggingNames { local, remote })
+    }
+}
+
+/// Client request for the logging server to (re)start logging to the given files.
+///
+/// Any direction without a name stops being logged.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LoggingRequest(pub LoggingNames);
+
+impl TypedMessageBody for LoggingRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Auxiliary_Logger_Remote Logging_request"),
+    );
+}
+
+impl BufferSize for LoggingRequest {
+    fn buffer_size(&self) -> usize {
+        self.0.buffer_size()
+    }
+}
+
+impl Buffer for LoggingRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.0.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for LoggingRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        LoggingNames::unbuffer_ref(buf).map(LoggingRequest)
+    }
+}
+
+/// Server report of the files it is currently logging to.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LoggingResponse(pub LoggingNames);
+
+impl TypedMessageBody for LoggingResponse {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Auxiliary_Logger_Remote Logging_response"),
+    );
+}
+
+impl BufferSize for LoggingResponse {
+    fn buffer_size(&self) -> usize {
+        self.0.buffer_size()
+    }
+}
+
+impl Buffer for LoggingResponse {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.0.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for LoggingResponse {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        LoggingNames::unbuffer_ref(buf).map(LoggingResponse)
+    }
+}
+
+/// Client request for the logging server to report what it is logging.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LoggingStatusRequest;
+impl EmptyMessage for LoggingStatusRequest {}
+impl TypedMessageBody for LoggingStatusRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Auxiliary_Logger_Remote Logging_status_request"),
+    );
+}
+
+struct ResponseHandler {
+    response: Weak<Mutex<Option<LoggingNames>>>,
+}
+
+impl fmt::Debug for ResponseHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseHandler").finish()
+    }
+}
+
+impl TypedHandler for ResponseHandler {
+    type Item = LoggingResponse;
+    fn handle_typed(&mut self, msg: &Message<LoggingResponse>) -> Result<HandlerCode> {
+        match self.response.upgrade() {
+            Some(response) => {
+                let mut response = response.lock()?;
+                *response = Some(msg.body.0.clone());
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for an auxiliary logger: asks the server to start or stop
+/// logging, and keeps the most recent response.
+///
+/// The server answers every logging or status request with a response,
+/// so `response()` reflects what it is actually logging.
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    response: Arc<Mutex<Option<LoggingNames>>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let response = Arc::new(Mutex::new(None));
+        let _ = connection.add_typed_handler(
+            Box::new(ResponseHandler {
+                response: Arc::downgrade(&response),
+            }),
+            Some(sender),
+        )?;
+        Ok(Remote {
+            connection,
+            sender,
+            response,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// The local sender ID of the logging server.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    fn send_request<U>(&self, body: U) -> Result<()>
+    where
+        U: TypedMessageBody + Buffer,
+    {
+        self.connection
+            .pack_message_body(None, self.sender, body, ServiceFlags::RELIABLE.into())
+    }
+
+    /// Ask the server to log to the given files, replacing any logging in progress.
+    pub fn request_logging(&self, local: LogFileNames, remote: LogFileNames) -> Result<()> {
+        self.send_request(LoggingRequest(LoggingNames::new(local, remote)))
+    }
+
+    /// Ask the server to stop all logging.
+    pub fn request_stop_logging(&self) -> Result<()> {
+        self.send_request(LoggingRequest::default())
+    }
+
+    /// Ask the server to report what it is logging.
+    pub fn request_logging_status(&self) -> Result<()> {
+        self.send_request(LoggingStatusRequest)
+    }
+
+    /// Get the log file names from the server's most recent response, if any.
+    pub fn response(&self) -> Result<Option<LoggingNames>> {
+        Ok(self.response.lock()?.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        test_util::{dispatch, unconnected},
+        StaticSenderName,
+    };
+
+    fn names() -> LoggingNames {
+        LoggingNames::new(
+            LogFileNames::from_names(Some(&b"in.vrpn"[..]), None),
+            LogFileNames::from_names(None, Some(&b"out"[..])),
+        )
+    }
+
+    #[test]
+    fn request() {
+        let request = LoggingRequest(names());
+        let mut expected = Vec::from(&hex!("00 00 00 07 00 00 00 00 00 00 00 00 00 00 00 03")[..]);
+        expected.extend_from_slice(b"in.vrpnout");
+        assert_eq!(request.buffer_size(), expected.len());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(request.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(LoggingRequest::unbuffer_ref(&mut buf).unwrap(), request);
+        assert_eq!(buf.len(), 0);
+
+        let mut short = Bytes::from(&expected[..20]);
+        match LoggingRequest::unbuffer_ref(&mut short) {
+            Err(Error::NeedMoreData(BytesRequired::Exactly(n))) => assert_eq!(n, 6),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stop_request() {
+        let buf = BytesMut::new()
+            .allocate_and_buffer(LoggingRequest::default())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &[0_u8; 16][..]);
+    }
+
+    #[test]
+    fn remote() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Logger0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+        assert_eq!(remote.response().unwrap(), None);
+
+        dispatch(&conn, remote.sender(), LoggingResponse(names()));
+        assert_eq!(remote.response().unwrap(), Some(names()));
+    }
+}
@@ -13,10 +13,7 @@ extern crate futures;
 use std::{sync::Arc, time::Duration};
 use tokio::{prelude::*, timer::Interval};
 use vrpn::{
-    async_io::{
-        connection_ip::ConnectionIpAcceptor, drain_poll_fn, ConnectionIp, ConnectionIpStream,
-        Drain, StreamExtras,
-    },
+    async_io::{drain_poll_fn, ConnectionIp, ConnectionIpStream, Drain, StreamExtras},
     ping,
     prelude::*,
     tracker::PoseReport,
@@ -122,17 +119,13 @@ fn main() -> Result<()> {
     let connection = ConnectionIp::new_server(None, None)?;
     let connection_stream = ConnectionIpStream::new(Arc::clone(&connection));
     let server = NullTracker::new(Arc::clone(&connection))?;
-    let acceptor_stream = ConnectionIpAcceptor::new(Arc::downgrade(&connection), None)?;
 
     tokio::run(
-        Future::select(
-            Stream::select(connection_stream, acceptor_stream).drain(),
-            server,
-        )
-        .map(|_| ())
-        .map_err(|e| {
-            eprintln!("error {:?}", e);
-        }),
+        Future::select(connection_stream.drain(), server)
+            .map(|_| ())
+            .map_err(|e| {
+                eprintln!("error {:?}", e);
+            }),
     );
     Ok(())
 }
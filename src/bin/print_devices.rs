@@ -12,7 +12,9 @@ extern crate vrpn;
 use std::sync::Arc;
 use tokio::prelude::*;
 use vrpn::{
-    async_io::{connect_tcp, ping, ConnectionIp, ConnectionIpStream, Drain, StreamExtras},
+    async_io::{
+        connect_tcp, make_udp_channel, ping, ConnectionIp, ConnectionIpStream, Drain, StreamExtras,
+    },
     handler::{HandlerCode, TypedHandler},
     prelude::*,
     tracker::PoseReport,
@@ -35,7 +37,8 @@ fn main() {
     let addr = "127.0.0.1:3883".parse().unwrap();
     let connection_future = connect_tcp(addr)
         .and_then(|stream| {
-            let connection = ConnectionIp::new_client(None, None, stream)?;
+            let udp = make_udp_channel(&stream)?;
+            let connection = ConnectionIp::new_client(None, None, stream, Some(udp))?;
             let sender = connection
                 .register_sender(StaticSenderName(b"Tracker0"))
                 .expect("should be able to register sender");
@@ -0,0 +1,449 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::prelude::*;
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    Buffer, BufferSize, BytesRequired, Connection, ConstantBufferSize, EmptyResult, Error, LocalId,
+    Message, MessageTypeIdentifier, Result, SenderId, SenderName, ServiceFlags, StaticTypeName,
+    TypedMessageBody, Unbuffer,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Maximum number of buttons on a device, matching vrpn_BUTTON_MAX_BUTTONS.
+pub const MAX_BUTTONS: usize = 256;
+
+/// Button index within a button device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ButtonId(pub i32);
+
+/// Special button index used in mode requests to mean "every button".
+pub const ALL_BUTTONS: ButtonId = ButtonId(-99);
+
+impl WrappedConstantSize for ButtonId {
+    type WrappedType = i32;
+    fn get(&self) -> Self::WrappedType {
+        self.0
+    }
+    fn new(v: Self::WrappedType) -> Self {
+        ButtonId(v)
+    }
+}
+
+fn buffer_bool<T: BufMut>(v: bool, buf: &mut T) -> EmptyResult {
+    (v as i32).buffer_ref(buf)
+}
+
+fn unbuffer_bool(buf: &mut Bytes) -> Result<bool> {
+    Ok(i32::unbuffer_ref(buf)? != 0)
+}
+
+/// A single button being pressed or released.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ButtonChange {
+    pub button: ButtonId,
+    pub pressed: bool,
+}
+
+impl TypedMessageBody for ButtonChange {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Button Change"));
+}
+
+impl ConstantBufferSize for ButtonChange {
+    fn constant_buffer_size() -> usize {
+        ButtonId::constant_buffer_size() + i32::constant_buffer_size()
+    }
+}
+
+impl Buffer for ButtonChange {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.button.buffer_ref(buf)?;
+        buffer_bool(self.pressed, buf)
+    }
+}
+
+impl Unbuffer for ButtonChange {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let button = ButtonId::unbuffer_ref(buf)?;
+        let pressed = unbuffer_bool(buf)?;
+        Ok(ButtonChange { button, pressed })
+    }
+}
+
+/// The state of every button on a device, typically sent when a client connects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ButtonStates {
+    pub states: Vec<bool>,
+}
+
+impl TypedMessageBody for ButtonStates {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Button States"));
+}
+
+impl BufferSize for ButtonStates {
+    fn buffer_size(&self) -> usize {
+        i32::constant_buffer_size() * (self.states.len() + 1)
+    }
+}
+
+impl Buffer for ButtonStates {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if self.states.len() > MAX_BUTTONS {
+            return Err(Error::OtherMessage(format!(
+                "button states has {} buttons, but the maximum is {}",
+                self.states.len(),
+                MAX_BUTTONS
+            )));
+        }
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        (self.states.len() as i32).buffer_ref(buf)?;
+        for state in &self.states {
+            buffer_bool(*state, buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Unbuffer for ButtonStates {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let num_buttons = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        if num_buttons < 0 || num_buttons as usize > MAX_BUTTONS {
+            return Err(Error::OtherMessage(format!(
+                "button states claims {} buttons, but the maximum is {}",
+                num_buttons, MAX_BUTTONS
+            )));
+        }
+        let num_buttons = num_buttons as usize;
+        let needed = num_buttons * i32::constant_buffer_size();
+        if buf.len() < needed {
+            return Err(Error::NeedMoreData(BytesRequired::Exactly(
+                needed - buf.len(),
+            )));
+        }
+        let states = (0..num_buttons)
+            .map(|_| unbuffer_bool(buf))
+            .collect::<Result<Vec<bool>>>()?;
+        Ok(ButtonStates { states })
+    }
+}
+
+/// How a button server should report a button.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ButtonMode {
+    /// Report the physical state of the button.
+    Momentary,
+    /// Each press toggles the reported state, which is currently off.
+    ToggleOff,
+    /// Each press toggles the reported state, which is currently on.
+    ToggleOn,
+}
+
+impl ButtonMode {
+    fn to_raw(self) -> i32 {
+        match self {
+            ButtonMode::Momentary => 10,
+            ButtonMode::ToggleOff => 20,
+            ButtonMode::ToggleOn => 21,
+        }
+    }
+
+    fn from_raw(v: i32) -> Result<ButtonMode> {
+        match v {
+            10 => Ok(ButtonMode::Momentary),
+            20 => Ok(ButtonMode::ToggleOff),
+            21 => Ok(ButtonMode::ToggleOn),
+            _ => Err(Error::OtherMessage(format!(
+                "unrecognized button mode {}",
+                v
+            ))),
+        }
+    }
+}
+
+/// Client request to switch a button (or all buttons) between toggle and momentary mode.
+///
+/// This is the message that the C++ vrpn_Button_Remote::set_toggle() and friends send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeRequest {
+    /// The button to change, or ALL_BUTTONS.
+    pub button: ButtonId,
+    pub mode: ButtonMode,
+}
+
+impl TypedMessageBody for ModeRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Button Admin"));
+}
+
+impl ConstantBufferSize for ModeRequest {
+    fn constant_buffer_size() -> usize {
+        ButtonId::constant_buffer_size() + i32::constant_buffer_size()
+    }
+}
+
+impl Buffer for ModeRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.button.buffer_ref(buf)?;
+        self.mode.to_raw().buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for ModeRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let button = ButtonId::unbuffer_ref(buf)?;
+        let mode = ButtonMode::from_raw(i32::unbuffer_ref(buf)?)?;
+        Ok(ModeRequest { button, mode })
+    }
+}
+
+/// Implemented by the messages that update the button states kept by Remote.
+trait StatesUpdate: TypedMessageBody + Unbuffer + Send + Sync {
+    fn update(&self, states: &mut Vec<bool>);
+}
+
+impl StatesUpdate for ButtonChange {
+    fn update(&self, states: &mut Vec<bool>) {
+        // No device has buttons beyond these: don't grow the states to match a bogus index.
+        if self.button.0 < 0 || self.button.0 as usize >= MAX_BUTTONS {
+            return;
+        }
+        let index = self.button.0 as usize;
+        if index >= states.len() {
+            states.resize(index + 1, false);
+        }
+        states[index] = self.pressed;
+    }
+}
+
+impl StatesUpdate for ButtonStates {
+    fn update(&self, states: &mut Vec<bool>) {
+        states.clone_from(&self.states);
+    }
+}
+
+struct StatesHandler<U: StatesUpdate> {
+    states: Weak<Mutex<Vec<bool>>>,
+    phantom: PhantomData<U>,
+}
+
+impl<U: StatesUpdate> StatesHandler<U> {
+    fn new(states: &Arc<Mutex<Vec<bool>>>) -> Box<StatesHandler<U>> {
+        Box::new(StatesHandler {
+            states: Arc::downgrade(states),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<U: StatesUpdate> fmt::Debug for StatesHandler<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StatesHandler").finish()
+    }
+}
+
+impl<U: StatesUpdate> TypedHandler for StatesHandler<U> {
+    type Item = U;
+    fn handle_typed(&mut self, msg: &Message<U>) -> Result<HandlerCode> {
+        match self.states.upgrade() {
+            Some(states) => {
+                let mut states = states.lock()?;
+                msg.body.update(&mut states);
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for a button device: keeps the current state of every button
+/// up to date from incoming messages, and can change button modes on the server.
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    states: Arc<Mutex<Vec<bool>>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let _ = connection
+            .add_typed_handler(StatesHandler::<ButtonChange>::new(&states), Some(sender))?;
+        let _ = connection
+            .add_typed_handler(StatesHandler::<ButtonStates>::new(&states), Some(sender))?;
+        Ok(Remote {
+            connection,
+            sender,
+            states,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// The local sender ID of the device.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Get a copy of the current state of all buttons (true is pressed).
+    pub fn states(&self) -> Result<Vec<bool>> {
+        Ok(self.states.lock()?.clone())
+    }
+
+    /// Get the current state of a single button, if known.
+    pub fn is_pressed(&self, button: ButtonId) -> Result<Option<bool>> {
+        if button.0 < 0 {
+            return Ok(None);
+        }
+        Ok(self.states.lock()?.get(button.0 as usize).cloned())
+    }
+
+    /// Ask the server to report a button (or ALL_BUTTONS) in the given mode.
+    pub fn set_mode(&self, button: ButtonId, mode: ButtonMode) -> Result<()> {
+        self.connection.pack_message_body(
+            None,
+            self.sender,
+            ModeRequest { button, mode },
+            ServiceFlags::RELIABLE.into(),
+        )
+    }
+
+    /// Ask the server to make a button (or ALL_BUTTONS) a toggle, with the given initial state.
+    pub fn set_toggle(&self, button: ButtonId, initially_on: bool) -> Result<()> {
+        let mode = if initially_on {
+            ButtonMode::ToggleOn
+        } else {
+            ButtonMode::ToggleOff
+        };
+        self.set_mode(button, mode)
+    }
+
+    /// Ask the server to make a button (or ALL_BUTTONS) momentary.
+    pub fn set_momentary(&self, button: ButtonId) -> Result<()> {
+        self.set_mode(button, ButtonMode::Momentary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        test_util::{dispatch, unconnected},
+        StaticSenderName,
+    };
+
+    #[test]
+    fn change() {
+        let change = ButtonChange {
+            button: ButtonId(3),
+            pressed: true,
+        };
+        let expected = hex!("00 00 00 03 00 00 00 01");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(change.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(ButtonChange::unbuffer_ref(&mut buf).unwrap(), change);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn states() {
+        let states = ButtonStates {
+            states: vec![true, false, false, true],
+        };
+        let expected = hex!("00 00 00 04 00 00 00 01 00 00 00 00 00 00 00 00 00 00 00 01");
+        assert_eq!(states.buffer_size(), expected.len());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(states.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(ButtonStates::unbuffer_ref(&mut buf).unwrap(), states);
+        assert_eq!(buf.len(), 0);
+
+        let mut short = Bytes::from(&expected[..12]);
+        assert!(ButtonStates::unbuffer_ref(&mut short).is_err());
+    }
+
+    #[test]
+    fn mode_request() {
+        let request = ModeRequest {
+            button: ALL_BUTTONS,
+            mode: ButtonMode::ToggleOn,
+        };
+        let expected = hex!("ff ff ff 9d 00 00 00 15");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(request.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(ModeRequest::unbuffer_ref(&mut buf).unwrap(), request);
+
+        let mut bad = Bytes::from(&hex!("00 00 00 00 00 00 00 02")[..]);
+        assert!(ModeRequest::unbuffer_ref(&mut bad).is_err());
+    }
+
+    #[test]
+    fn remote() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Button0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+
+        dispatch(
+            &conn,
+            remote.sender(),
+            ButtonStates {
+                states: vec![false, true],
+            },
+        );
+        assert_eq!(remote.states().unwrap(), vec![false, true]);
+
+        dispatch(
+            &conn,
+            remote.sender(),
+            ButtonChange {
+                button: ButtonId(3),
+                pressed: true,
+            },
+        );
+        assert_eq!(remote.states().unwrap(), vec![false, true, false, true]);
+        assert_eq!(remote.is_pressed(ButtonId(3)).unwrap(), Some(true));
+        assert_eq!(remote.is_pressed(ButtonId(4)).unwrap(), None);
+
+        for button in &[MAX_BUTTONS as i32, i32::MAX] {
+            dispatch(
+                &conn,
+                remote.sender(),
+                ButtonChange {
+                    button: ButtonId(*button),
+                    pressed: true,
+                },
+            );
+        }
+        assert_eq!(remote.states().unwrap(), vec![false, true, false, true]);
+
+        remote
+            .set_toggle(ALL_BUTTONS, false)
+            .expect("packing with no endpoints should succeed");
+    }
+}
@@ -0,0 +1,411 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::{
+    handler::{HandlerCode, HandlerHandle, TypedHandler},
+    time::{TimeAdjustment, TimeOffset},
+    Buffer, Connection, ConstantBufferSize, EmptyResult, LocalId, Message, MessageTypeIdentifier,
+    Result, SenderId, ServiceFlags, StaticSenderName, StaticTypeName, TimeVal, TypeId,
+    TypedMessageBody, Unbuffer,
+};
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    fmt,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+/// The sender that clock synchronization messages come from, like the C++ `vrpn_Clock_Server`.
+pub const CLOCK_SENDER: StaticSenderName = StaticSenderName(b"clockServer");
+const QUERY_MESSAGE: StaticTypeName = StaticTypeName(b"vrpn_Clock query");
+const REPLY_MESSAGE: StaticTypeName = StaticTypeName(b"vrpn_Clock reply");
+
+/// A client's request for the server's time: the header has the time it was sent.
+///
+/// The server sends its replies to all its clients, so each tells its own apart by the ID.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClockQuery {
+    pub client_id: i32,
+    pub sequence: i32,
+}
+
+impl TypedMessageBody for ClockQuery {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(QUERY_MESSAGE);
+}
+
+impl ConstantBufferSize for ClockQuery {
+    fn constant_buffer_size() -> usize {
+        2 * i32::constant_buffer_size()
+    }
+}
+
+impl Buffer for ClockQuery {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.client_id.buffer_ref(buf)?;
+        self.sequence.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for ClockQuery {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let client_id = i32::unbuffer_ref(buf)?;
+        let sequence = i32::unbuffer_ref(buf)?;
+        Ok(ClockQuery {
+            client_id,
+            sequence,
+        })
+    }
+}
+
+/// The server's answer to a `ClockQuery`, in a message whose header has the server's time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClockReply {
+    /// The time from the header of the query, by the client's clock.
+    pub query_time: TimeVal,
+    /// The body of the query, echoed back.
+    pub query: ClockQuery,
+}
+
+impl TypedMessageBody for ClockReply {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(REPLY_MESSAGE);
+}
+
+impl ConstantBufferSize for ClockReply {
+    fn constant_buffer_size() -> usize {
+        TimeVal::constant_buffer_size() + ClockQuery::constant_buffer_size()
+    }
+}
+
+impl Buffer for ClockReply {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.query_time.buffer_ref(buf)?;
+        self.query.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for ClockReply {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let query_time = TimeVal::unbuffer_ref(buf)?;
+        let query = ClockQuery::unbuffer_ref(buf)?;
+        Ok(ClockReply { query_time, query })
+    }
+}
+
+#[derive(Debug)]
+struct QueryHandler<T: Connection> {
+    connection: Weak<T>,
+    adjustment: TimeAdjustment,
+    reply_type: LocalId<TypeId>,
+    sender: LocalId<SenderId>,
+}
+
+impl<T: Connection + 'static> TypedHandler for QueryHandler<T> {
+    type Item = ClockQuery;
+    fn handle_typed(&mut self, msg: &Message<ClockQuery>) -> Result<HandlerCode> {
+        match self.connection.upgrade() {
+            Some(connection) => {
+                // The header gets stamped with our time now: that's the answer.
+                let reply = ClockReply {
+                    query_time: msg.header.time + -self.adjustment.offset(),
+                    query: msg.body,
+                };
+                let reply = Message::new(None, self.reply_type, self.sender, reply);
+                connection.pack_message_after_dispatch(reply, ServiceFlags::RELIABLE.into())?;
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Answers clock queries, so clients can tell how our clock relates to theirs.
+#[derive(Debug)]
+pub struct Server {
+    handler: HandlerHandle,
+}
+
+impl Server {
+    pub fn new<T: Connection + 'static>(connection: Arc<T>) -> Result<Server> {
+        let sender = connection.register_sender(CLOCK_SENDER)?;
+        let reply_type = connection.register_type(REPLY_MESSAGE)?;
+        let adjustment = connection.dispatcher().lock()?.incoming_time_adjustment();
+        let handler = connection.add_typed_handler(
+            Box::new(QueryHandler {
+                connection: Arc::downgrade(&connection),
+                adjustment,
+                reply_type,
+                sender,
+            }),
+            Some(sender),
+        )?;
+        Ok(Server { handler })
+    }
+
+    /// The query handler, to pass to `remove_handler` to stop answering.
+    pub fn handler(&self) -> HandlerHandle {
+        self.handler
+    }
+}
+
+/// How often a clock `Client` queries, and what it does with the answers.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Config {
+    /// Time between queries.
+    pub interval: Duration,
+    /// How many of the latest answers to estimate from: the one with the shortest round trip wins,
+    /// since it leaves the least room for error.
+    pub window: usize,
+    /// Whether to apply the estimated offset to the times of all incoming messages on the connection.
+    pub adjust_incoming: bool,
+}
+
+impl Default for Config {
+    /// Like the C++ `vrpn_Clock_Remote`: once a second, the best of the last three.
+    fn default() -> Config {
+        Config {
+            interval: Duration::from_secs(1),
+            window: 3,
+            adjust_incoming: false,
+        }
+    }
+}
+
+/// How the server's clock relates to ours, as estimated from a clock query.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ClockEstimate {
+    /// Add this to a server time to get the same moment on our clock.
+    pub offset: TimeOffset,
+    /// The round-trip time of the query the offset was estimated from.
+    pub round_trip: Duration,
+}
+
+impl ClockEstimate {
+    /// Estimate from a query sent and answered at the given times on our clock,
+    /// assuming the server answered halfway through the round trip.
+    ///
+    /// Returns None if our clock went backwards in the meantime.
+    fn from_query(sent: TimeVal, server_time: TimeVal, received: TimeVal) -> Option<ClockEstimate> {
+        let round_trip = received - sent;
+        if round_trip.0 < 0 {
+            return None;
+        }
+        let midpoint = sent + TimeOffset(round_trip.0 / 2);
+        Some(ClockEstimate {
+            offset: midpoint - server_time,
+            round_trip: Duration::from_micros(round_trip.0 as u64),
+        })
+    }
+}
+
+struct ClientInner {
+    config: Config,
+    next_sequence: i32,
+    /// The latest estimates, oldest first.
+    recent: VecDeque<ClockEstimate>,
+    best: Option<ClockEstimate>,
+}
+
+impl ClientInner {
+    fn add_estimate(&mut self, estimate: ClockEstimate) -> Option<ClockEstimate> {
+        self.recent.push_back(estimate);
+        while self.recent.len() > self.config.window.max(1) {
+            let _ = self.recent.pop_front();
+        }
+        self.best = self.recent.iter().min_by_key(|e| e.round_trip).cloned();
+        self.best
+    }
+}
+
+struct ReplyHandler {
+    inner: Weak<Mutex<ClientInner>>,
+    adjustment: TimeAdjustment,
+    client_id: i32,
+}
+
+impl fmt::Debug for ReplyHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReplyHandler").finish()
+    }
+}
+
+impl TypedHandler for ReplyHandler {
+    type Item = ClockReply;
+    fn handle_typed(&mut self, msg: &Message<ClockReply>) -> Result<HandlerCode> {
+        let inner = match self.inner.upgrade() {
+            Some(inner) => inner,
+            // If we get here, then the client has gone away
+            None => return Ok(HandlerCode::RemoveThisHandler),
+        };
+        if msg.body.query.client_id != self.client_id {
+            // An answer to another client of the same server.
+            return Ok(HandlerCode::ContinueProcessing);
+        }
+        let received = TimeVal::get_time_of_day();
+        // Undo any adjustment made on the way in, to get the time by the server's clock.
+        let server_time = msg.header.time + -self.adjustment.offset();
+        let sent = msg.body.query_time;
+        let estimate = match ClockEstimate::from_query(sent, server_time, received) {
+            Some(estimate) => estimate,
+            None => return Ok(HandlerCode::ContinueProcessing),
+        };
+        let mut inner = inner.lock()?;
+        if let Some(best) = inner.add_estimate(estimate) {
+            if inner.config.adjust_incoming {
+                self.adjustment.set_offset(best.offset);
+            }
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+/// Pick an ID for a new client.
+///
+/// It's random, since it needs to differ from those of clients on other machines too.
+fn new_client_id() -> i32 {
+    static CLIENTS_CREATED: AtomicUsize = AtomicUsize::new(0);
+    let created = CLIENTS_CREATED.fetch_add(1, Ordering::SeqCst);
+    RandomState::new().hash_one(created) as i32
+}
+
+/// Queries a server's clock `Server` to estimate the offset between its clock and ours,
+/// and the round-trip time, like the C++ `vrpn_Clock_Remote`.
+///
+/// Something must call `send_query` regularly: `async_io::clock::Client` does.
+pub struct Client<T: Connection + 'static> {
+    connection: Arc<T>,
+    inner: Arc<Mutex<ClientInner>>,
+    client_id: i32,
+    query_type: LocalId<TypeId>,
+    sender: LocalId<SenderId>,
+}
+
+impl<T: Connection + 'static> Client<T> {
+    pub fn new(connection: Arc<T>) -> Result<Client<T>> {
+        Client::with_config(connection, Config::default())
+    }
+
+    pub fn with_config(connection: Arc<T>, config: Config) -> Result<Client<T>> {
+        let sender = connection.register_sender(CLOCK_SENDER)?;
+        let query_type = connection.register_type(QUERY_MESSAGE)?;
+        let adjustment = connection.dispatcher().lock()?.incoming_time_adjustment();
+        let client_id = new_client_id();
+        let inner = Arc::new(Mutex::new(ClientInner {
+            config,
+            next_sequence: 0,
+            recent: VecDeque::new(),
+            best: None,
+        }));
+        let _ = connection.add_typed_handler(
+            Box::new(ReplyHandler {
+                inner: Arc::downgrade(&inner),
+                adjustment,
+                client_id,
+            }),
+            Some(sender),
+        )?;
+        Ok(Client {
+            connection,
+            inner,
+            client_id,
+            query_type,
+            sender,
+        })
+    }
+
+    /// Ask the server for its time.
+    pub fn send_query(&self) -> Result<()> {
+        let sequence = {
+            let mut inner = self.inner.lock()?;
+            let sequence = inner.next_sequence;
+            inner.next_sequence = sequence.wrapping_add(1);
+            sequence
+        };
+        let query = ClockQuery {
+            client_id: self.client_id,
+            sequence,
+        };
+        let now = TimeVal::get_time_of_day();
+        let msg = Message::new(Some(now), self.query_type, self.sender, query);
+        self.connection
+            .pack_message(msg, ServiceFlags::RELIABLE.into())
+    }
+
+    /// The current best estimate, if any queries have been answered.
+    pub fn estimate(&self) -> Result<Option<ClockEstimate>> {
+        Ok(self.inner.lock()?.best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        time::{Microseconds, Seconds},
+        BytesMutExtras,
+    };
+
+    fn time(sec: i32, usec: i32) -> TimeVal {
+        TimeVal::new(Seconds(sec), Microseconds(usec))
+    }
+
+    #[test]
+    fn reply() {
+        let reply = ClockReply {
+            query_time: time(0x5beb_332e, 0x000c_58b1),
+            query: ClockQuery {
+                client_id: 0x1234,
+                sequence: 2,
+            },
+        };
+        let expected = hex!("5b eb 33 2e 00 0c 58 b1 00 00 12 34 00 00 00 02");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(reply)
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(ClockReply::unbuffer_ref(&mut buf).unwrap(), reply);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn estimate_from_query() {
+        // The server is 10 seconds ahead, and the round trip took 40ms.
+        let estimate =
+            ClockEstimate::from_query(time(100, 0), time(110, 20_000), time(100, 40_000))
+                .expect("time went forwards");
+        assert_eq!(estimate.offset, TimeOffset(-10_000_000));
+        assert_eq!(estimate.round_trip, Duration::from_millis(40));
+        assert_eq!(time(110, 20_000) + estimate.offset, time(100, 20_000));
+
+        assert!(ClockEstimate::from_query(time(100, 0), time(110, 0), time(99, 0)).is_none());
+    }
+
+    #[test]
+    fn best_of_window() {
+        let mut inner = ClientInner {
+            config: Config::default(),
+            next_sequence: 0,
+            recent: VecDeque::new(),
+            best: None,
+        };
+        let estimate = |offset, millis| ClockEstimate {
+            offset: TimeOffset(offset),
+            round_trip: Duration::from_millis(millis),
+        };
+        assert_eq!(inner.add_estimate(estimate(1, 30)), Some(estimate(1, 30)));
+        assert_eq!(inner.add_estimate(estimate(2, 10)), Some(estimate(2, 10)));
+        assert_eq!(inner.add_estimate(estimate(3, 20)), Some(estimate(2, 10)));
+        assert_eq!(inner.add_estimate(estimate(4, 40)), Some(estimate(2, 10)));
+        // The quickest has now dropped out of the window.
+        assert_eq!(inner.add_estimate(estimate(5, 50)), Some(estimate(3, 20)));
+    }
+}
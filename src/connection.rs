@@ -4,9 +4,10 @@
 
 use crate::{
     descriptions::InnerDescription, type_dispatcher::HandlerHandle, BaseTypeSafeId, Buffer,
-    ClassOfService, Endpoint, EndpointGeneric, Handler, LocalId, LogFileNames, MatchingTable,
-    Message, MessageTypeIdentifier, RegisterMapping, Result, SenderId, SenderName, TimeVal,
-    TranslationTables, TypeDispatcher, TypeId, TypeName, TypedHandler, TypedMessageBody,
+    ClassOfService, Endpoint, EndpointGeneric, Error, GenericMessage, Handler, LocalId,
+    LogFileNames, MatchingTable, Message, MessageTypeIdentifier, RegisterMapping, Result, SenderId,
+    SenderName, TimeVal, TranslationTables, TypeDispatcher, TypeId, TypeName, TypedHandler,
+    TypedMessageBody,
 };
 use std::sync::{Arc, Mutex};
 
@@ -95,11 +96,41 @@ pub trait Connection: Send + Sync {
     where
         T: TypedMessageBody + Buffer,
     {
-        let generic_msg = msg.try_into_generic()?;
+        self.pack_generic_message(msg.try_into_generic()?, class)
+    }
 
+    /// Send an already-serialized message to every endpoint.
+    fn pack_generic_message(&self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
         let mut endpoints = self.connection_core().endpoints.lock()?;
         for ep in endpoints.iter_mut().flatten() {
-            ep.buffer_generic_message(generic_msg.clone(), class)?;
+            ep.buffer_generic_message(msg.clone(), class)?;
+        }
+        Ok(())
+    }
+
+    /// Queue a message to be sent once the connection is done dispatching messages.
+    ///
+    /// Handlers are called with the connection locked, so they must use this
+    /// rather than `pack_message` to reply.
+    fn pack_message_after_dispatch<T>(&self, msg: Message<T>, class: ClassOfService) -> Result<()>
+    where
+        T: TypedMessageBody + Buffer,
+    {
+        let msg = msg.try_into_generic()?;
+        self.connection_core()
+            .deferred_messages
+            .lock()?
+            .push((msg, class));
+        Ok(())
+    }
+
+    /// Send the messages queued by `pack_message_after_dispatch`.
+    ///
+    /// Connections call this once they're done dispatching, when polled.
+    fn pack_deferred_messages(&self) -> Result<()> {
+        let deferred = std::mem::take(&mut *self.connection_core().deferred_messages.lock()?);
+        for (msg, class) in deferred {
+            self.pack_generic_message(msg, class)?;
         }
         Ok(())
     }
@@ -144,6 +175,26 @@ pub trait Connection: Send + Sync {
         Ok(())
     }
 
+    /// Close every endpoint, telling each other end we're going away.
+    ///
+    /// Keep polling the connection until the endpoints have finished closing.
+    fn close(&self) -> Result<()> {
+        let mut endpoints = self.connection_core().endpoints.lock()?;
+        for ep in endpoints.iter_mut().flatten() {
+            ep.close()?;
+        }
+        Ok(())
+    }
+
+    /// Close a single endpoint, by its index in `endpoints()`.
+    fn disconnect_endpoint(&self, index: usize) -> Result<()> {
+        let mut endpoints = self.connection_core().endpoints.lock()?;
+        match endpoints.get_mut(index) {
+            Some(Some(ep)) => ep.close(),
+            _ => Err(Error::EndpointNotFound(index)),
+        }
+    }
+
     fn endpoints(&self) -> SharedEndpointVec<Self::SpecificEndpoint> {
         Arc::clone(&self.connection_core().endpoints)
     }
@@ -160,6 +211,8 @@ where
 {
     pub(crate) endpoints: SharedEndpointVec<EP>,
     pub(crate) type_dispatcher: Arc<Mutex<TypeDispatcher>>,
+    /// Messages from handlers, waiting for dispatch to finish.
+    deferred_messages: Mutex<Vec<(GenericMessage, ClassOfService)>>,
     remote_log_names: LogFileNames,
     local_log_names: LogFileNames,
 }
@@ -175,8 +228,26 @@ where
         ConnectionCore {
             endpoints: Arc::new(Mutex::new(endpoints)),
             type_dispatcher: Arc::new(Mutex::new(TypeDispatcher::new())),
+            deferred_messages: Mutex::new(Vec::new()),
             remote_log_names: LogFileNames::from(remote_log_names),
             local_log_names: LogFileNames::from(local_log_names),
         }
     }
+
+    /// The names of the log files we ask the other end of the connection to write.
+    pub(crate) fn remote_log_names(&self) -> &LogFileNames {
+        &self.remote_log_names
+    }
+
+    /// The names of the files to log an endpoint's messages to.
+    ///
+    /// The first endpoint uses the names as given: any others get "-<index>" added
+    /// before the extension, so each endpoint of a server gets its own files.
+    pub(crate) fn local_log_names_for_endpoint(&self, index: usize) -> LogFileNames {
+        if index == 0 {
+            self.local_log_names.clone()
+        } else {
+            self.local_log_names.with_suffix(&format!("-{}", index))
+        }
+    }
 }
@@ -27,6 +27,9 @@ pub const UDP_DESCRIPTION: TypeId = TypeId(-3);
 pub const LOG_DESCRIPTION: TypeId = TypeId(-4);
 pub const DISCONNECT_MESSAGE: TypeId = TypeId(-5);
 
+/// Based on vrpn_DEFAULT_LISTEN_PORT_NO
+pub const DEFAULT_PORT: u16 = 3883;
+
 pub const TCP_BUFLEN: usize = 64000;
 pub const UDP_BUFLEN: usize = 1472;
 
@@ -248,6 +248,19 @@ mod tests {
         assert_eq!(buf.len(), 0);
     }
 
+    #[test]
+    fn log_mode_roundtrip() {
+        let mut magic_cookie = CookieData::from(MAGIC_DATA);
+        magic_cookie.log_mode = Some(LogMode::from(LogFlags::INCOMING_OUTGOING));
+        assert_eq!(format!("{}", magic_cookie), "vrpn: ver. 07.35  3");
+
+        let mut buf = BytesMut::new()
+            .allocate_and_buffer(magic_cookie)
+            .expect("Buffering needs to succeed")
+            .freeze();
+        assert_eq!(CookieData::unbuffer_ref(&mut buf).unwrap(), magic_cookie);
+    }
+
     #[test]
     fn basics() {
         assert_eq!(from_dec(b"1").unwrap(), 1_u8);
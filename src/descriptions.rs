@@ -5,8 +5,9 @@
 use bytes::{BufMut, Bytes};
 use crate::prelude::*;
 use crate::{
-    constants, length_prefixed, BaseTypeSafeId, Buffer, BufferSize, EmptyResult, Error, IdType,
-    Message, MessageTypeIdentifier, Result, SenderId, TypeId, TypedMessageBody, Unbuffer,
+    constants, length_prefixed, BaseTypeSafeId, Buffer, BufferSize, BytesRequired, EmptyResult,
+    Error, IdType, Message, MessageTypeIdentifier, Result, SenderId, TypeId, TypedMessageBody,
+    Unbuffer,
 };
 use std::{
     marker::PhantomData,
@@ -181,6 +182,10 @@ impl Unbuffer for UdpInnerDescription {
             .parse()
             .map_err(|e| Error::OtherMessage(format!("ip address parse error: {}", e)))?;
         buf.advance(ip_buf.len());
+        // Consume the null terminator too, if present.
+        if buf.first() == Some(&0) {
+            buf.advance(1);
+        }
 
         Ok(UdpInnerDescription::new(addr))
     }
@@ -202,3 +207,106 @@ impl Buffer for UdpInnerDescription {
         Ok(())
     }
 }
+
+/// The un-framed datagram a client sends to a server's UDP port in UDP+TCP mode,
+/// asking the server to connect back to it over TCP.
+///
+/// On the wire, this is the address and port as text, separated by a space,
+/// and null-terminated: `127.0.0.1 51221`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct LobPacket {
+    pub callback_address: SocketAddr,
+}
+
+impl LobPacket {
+    pub fn new(callback_address: SocketAddr) -> LobPacket {
+        LobPacket { callback_address }
+    }
+
+    fn to_text(self) -> String {
+        format!(
+            "{} {}",
+            self.callback_address.ip(),
+            self.callback_address.port()
+        )
+    }
+}
+
+impl BufferSize for LobPacket {
+    fn buffer_size(&self) -> usize {
+        self.to_text().len() + 1
+    }
+}
+
+impl Buffer for LobPacket {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        let text = self.to_text();
+        if buf.remaining_mut() < (text.len() + 1) {
+            return Err(Error::OutOfBuffer);
+        }
+        buf.put(text);
+        buf.put_u8(0);
+        Ok(())
+    }
+}
+
+impl Unbuffer for LobPacket {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<LobPacket> {
+        let text_len = match buf.iter().position(|b| *b == 0) {
+            Some(i) => i,
+            None => return Err(Error::NeedMoreData(BytesRequired::AtLeast(1))),
+        };
+        let text = buf.split_to(text_len);
+        buf.advance(1);
+        let text = String::from_utf8_lossy(&text);
+        let mut fields = text.split_whitespace();
+        let (addr, port) = match (fields.next(), fields.next()) {
+            (Some(addr), Some(port)) => (addr, port),
+            _ => {
+                return Err(Error::OtherMessage(format!(
+                    "could not find address and port in lob packet {:?}",
+                    text
+                )))
+            }
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| Error::OtherMessage(format!("ip address parse error: {}", e)))?;
+        let port: u16 = port.parse()?;
+        Ok(LobPacket::new(SocketAddr::new(addr, port)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn lob_packet() {
+        let packet = LobPacket::new("192.168.1.2:51221".parse().unwrap());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(packet)
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &b"192.168.1.2 51221\0"[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(LobPacket::unbuffer_ref(&mut buf).unwrap(), packet);
+        assert_eq!(buf.len(), 0);
+
+        let mut unterminated = Bytes::from(&b"192.168.1.2 51221"[..]);
+        assert!(LobPacket::unbuffer_ref(&mut unterminated).is_err());
+        let mut garbage = Bytes::from(&b"hello\0"[..]);
+        assert!(LobPacket::unbuffer_ref(&mut garbage).is_err());
+    }
+
+    #[test]
+    fn udp_description() {
+        let desc = UdpDescription::new("127.0.0.1:51222".parse().unwrap());
+        let msg = Message::<UdpInnerDescription>::from(desc.clone())
+            .try_into_generic()
+            .unwrap();
+        assert_eq!(&msg.body.inner[..], &b"127.0.0.1\0"[..]);
+        let parsed = Message::<UdpInnerDescription>::try_from_generic(&msg).unwrap();
+        assert_eq!(UdpDescription::from(parsed), desc);
+    }
+}
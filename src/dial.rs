@@ -0,0 +1,232 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    Buffer, Connection, ConstantBufferSize, EmptyResult, LocalId, Message, MessageTypeIdentifier,
+    Result, SenderId, SenderName, StaticTypeName, TypedMessageBody, Unbuffer, WrappedConstantSize,
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Maximum number of dials on a device, matching vrpn_DIAL_MAX.
+pub const DIAL_MAX: usize = 128;
+
+/// Dial index within a dial device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DialId(pub i32);
+
+impl WrappedConstantSize for DialId {
+    type WrappedType = i32;
+    fn get(&self) -> Self::WrappedType {
+        self.0
+    }
+    fn new(v: Self::WrappedType) -> Self {
+        DialId(v)
+    }
+}
+
+/// Relative motion of a dial since the last update.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialUpdate {
+    pub dial: DialId,
+    /// Change in position, in revolutions.
+    pub change: f64,
+}
+
+impl TypedMessageBody for DialUpdate {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Dial update"));
+}
+
+impl ConstantBufferSize for DialUpdate {
+    fn constant_buffer_size() -> usize {
+        f64::constant_buffer_size() + DialId::constant_buffer_size()
+    }
+}
+
+impl Buffer for DialUpdate {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.change.buffer_ref(buf)?;
+        self.dial.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for DialUpdate {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let change = f64::unbuffer_ref(buf)?;
+        let dial = DialId::unbuffer_ref(buf)?;
+        Ok(DialUpdate { dial, change })
+    }
+}
+
+struct DialHandler {
+    positions: Weak<Mutex<Vec<f64>>>,
+}
+
+impl fmt::Debug for DialHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DialHandler").finish()
+    }
+}
+
+impl TypedHandler for DialHandler {
+    type Item = DialUpdate;
+    fn handle_typed(&mut self, msg: &Message<DialUpdate>) -> Result<HandlerCode> {
+        match self.positions.upgrade() {
+            Some(positions) => {
+                let mut positions = positions.lock()?;
+                let dial = msg.body.dial.0;
+                // Anything past DIAL_MAX is bogus, and would have us allocate for it.
+                if dial >= 0 && (dial as usize) < DIAL_MAX {
+                    let index = dial as usize;
+                    if index >= positions.len() {
+                        positions.resize(index + 1, 0.0);
+                    }
+                    positions[index] += msg.body.change;
+                }
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for a dial device: accumulates the relative updates
+/// into absolute positions (in revolutions since this Remote was created or reset).
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    positions: Arc<Mutex<Vec<f64>>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let positions = Arc::new(Mutex::new(Vec::new()));
+        let _ = connection.add_typed_handler(
+            Box::new(DialHandler {
+                positions: Arc::downgrade(&positions),
+            }),
+            Some(sender),
+        )?;
+        Ok(Remote {
+            connection,
+            sender,
+            positions,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// Access the connection this remote is using.
+    pub fn connection(&self) -> &Arc<T> {
+        &self.connection
+    }
+
+    /// The local sender ID of the device.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Get the accumulated position of every dial we've heard from, in revolutions.
+    pub fn positions(&self) -> Result<Vec<f64>> {
+        Ok(self.positions.lock()?.clone())
+    }
+
+    /// Get the accumulated position of a single dial, in revolutions.
+    ///
+    /// Dials we have not received an update for are at 0.
+    pub fn position(&self, dial: DialId) -> Result<f64> {
+        if dial.0 < 0 {
+            return Ok(0.0);
+        }
+        Ok(self
+            .positions
+            .lock()?
+            .get(dial.0 as usize)
+            .cloned()
+            .unwrap_or(0.0))
+    }
+
+    /// Set the accumulated position of every dial back to 0.
+    pub fn reset(&self) -> Result<()> {
+        self.positions.lock()?.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        prelude::*,
+        test_util::{dispatch, unconnected},
+        StaticSenderName,
+    };
+
+    #[test]
+    fn update() {
+        let update = DialUpdate {
+            dial: DialId(2),
+            change: -0.25,
+        };
+        let expected = hex!("bf d0 00 00 00 00 00 00 00 00 00 02");
+        assert_eq!(update.buffer_size(), expected.len());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(update.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(DialUpdate::unbuffer_ref(&mut buf).unwrap(), update);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn remote_accumulates() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Dial0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+        for change in &[0.5, 0.25, -1.0] {
+            dispatch(
+                &conn,
+                remote.sender(),
+                DialUpdate {
+                    dial: DialId(1),
+                    change: *change,
+                },
+            );
+        }
+        assert_eq!(remote.position(DialId(1)).unwrap(), -0.25);
+        assert_eq!(remote.position(DialId(0)).unwrap(), 0.0);
+        assert_eq!(remote.positions().unwrap(), vec![0.0, -0.25]);
+
+        for dial in &[DIAL_MAX as i32, i32::MAX] {
+            dispatch(
+                &conn,
+                remote.sender(),
+                DialUpdate {
+                    dial: DialId(*dial),
+                    change: 1.0,
+                },
+            );
+        }
+        assert_eq!(remote.positions().unwrap(), vec![0.0, -0.25]);
+
+        remote.reset().unwrap();
+        assert_eq!(remote.position(DialId(1)).unwrap(), 0.0);
+    }
+}
@@ -7,8 +7,9 @@ use crate::{
     constants,
     descriptions::{InnerDescription, UdpDescription, UdpInnerDescription},
     BaseTypeSafeId, BaseTypeSafeIdName, Buffer, ClassOfService, Description, Error, GenericMessage,
-    IntoId, LocalId, LogFileNames, MatchingTable, Message, RemoteId, Result, SenderId,
-    ServiceFlags, TranslationTables, TypeDispatcher, TypeId, TypeSafeId, TypedMessageBody,
+    IntoId, LocalId, LogFileNames, MatchingTable, Message, RemoteId, Result, SenderId, SenderName,
+    ServiceFlags, TranslationTables, TypeDispatcher, TypeId, TypeName, TypeSafeId,
+    TypedMessageBody,
 };
 use downcast_rs::Downcast;
 
@@ -35,6 +36,12 @@ pub trait Endpoint: Downcast {
     /// Queue up a generic message for sending.
     fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()>;
 
+    /// Start closing: tell the other end we're going away,
+    /// then shut down once everything already queued has been sent.
+    ///
+    /// The endpoint reports itself closed when polled once it's done.
+    fn close(&mut self) -> Result<()>;
+
     /// Handle a "system" message (for which message_type.is_system_message() returns true).
     ///
     /// Call from within your dispatch function once you've recognized that a message is a system message.
@@ -86,6 +93,47 @@ pub trait Endpoint: Downcast {
         }
         Ok(())
     }
+
+    /// Record a sender described by the other end, registering its name locally if required.
+    fn add_remote_sender(
+        &mut self,
+        desc: Description<SenderId>,
+        dispatcher: &mut TypeDispatcher,
+    ) -> Result<()> {
+        let local_id = dispatcher
+            .register_sender(SenderName(desc.name.clone()))?
+            .get();
+        eprintln!(
+            "Registering sender {:?}: local {:?} = remote {:?}",
+            desc.name, local_id, desc.which
+        );
+        let _ = self.translation_tables_mut().add_remote_entry(
+            desc.name,
+            RemoteId(desc.which),
+            local_id,
+        )?;
+        Ok(())
+    }
+
+    /// Record a message type described by the other end, registering its name locally if required.
+    fn add_remote_type(
+        &mut self,
+        desc: Description<TypeId>,
+        dispatcher: &mut TypeDispatcher,
+    ) -> Result<()> {
+        let local_id = dispatcher.register_type(TypeName(desc.name.clone()))?.get();
+        eprintln!(
+            "Registering type {:?}: local {:?} = remote {:?}",
+            desc.name, local_id, desc.which
+        );
+        let _ = self.translation_tables_mut().add_remote_entry(
+            desc.name,
+            RemoteId(desc.which),
+            local_id,
+        )?;
+        Ok(())
+    }
+
     fn clear_other_senders_and_types(&mut self) {
         self.translation_tables_mut().clear();
     }
@@ -81,6 +81,9 @@ quick_error! {
         HandlerNotFound {
             display("handler not found")
         }
+        EndpointNotFound(index: usize) {
+            display("no endpoint at index {}", index)
+        }
         GenericErrorReturn {
             display("handler returned an error")
         }
@@ -91,6 +94,9 @@ quick_error! {
             display("un-recognized system message id {}", id)
         }
 
+        InvalidLocator(locator: String) {
+            display("could not parse device locator '{}'", locator)
+        }
         VersionMismatch(actual: Version, expected: Version) {
             display(
                     "version mismatch: expected something compatible with {}, got {}",
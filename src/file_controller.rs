@@ -0,0 +1,155 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::{
+    Buffer, Connection, ConstantBufferSize, EmptyMessage, EmptyResult, LocalId,
+    MessageTypeIdentifier, Result, SenderId, ServiceFlags, StaticSenderName, StaticTypeName,
+    TimeVal, TypedMessageBody, Unbuffer,
+};
+use std::sync::Arc;
+
+/// The sender that file playback control messages come from.
+pub const FILE_CONTROLLER_SENDER: StaticSenderName = StaticSenderName(b"vrpn File Controller");
+
+/// Request to change the rate of log file playback.
+///
+/// 1.0 is real time, 0.0 pauses playback.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SetReplayRate(pub f32);
+
+impl TypedMessageBody for SetReplayRate {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_File set_replay_rate"));
+}
+
+impl ConstantBufferSize for SetReplayRate {
+    fn constant_buffer_size() -> usize {
+        f32::constant_buffer_size()
+    }
+}
+
+impl Buffer for SetReplayRate {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.0.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for SetReplayRate {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        f32::unbuffer_ref(buf).map(SetReplayRate)
+    }
+}
+
+/// Request to rewind log file playback to the start of the file.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Reset;
+impl EmptyMessage for Reset {}
+impl TypedMessageBody for Reset {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_File reset"));
+}
+
+/// Request to immediately play back everything in a log file up to the given file time,
+/// then continue at the current rate from there.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PlayToTime(pub TimeVal);
+
+impl TypedMessageBody for PlayToTime {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_File play_to_time"));
+}
+
+impl ConstantBufferSize for PlayToTime {
+    fn constant_buffer_size() -> usize {
+        TimeVal::constant_buffer_size()
+    }
+}
+
+impl Buffer for PlayToTime {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.0.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for PlayToTime {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        TimeVal::unbuffer_ref(buf).map(PlayToTime)
+    }
+}
+
+/// Client-side helper to control playback of a log file connection,
+/// equivalent to the C++ `vrpn_File_Controller`.
+pub struct Controller<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+}
+
+impl<T: Connection + 'static> Controller<T> {
+    pub fn new(connection: Arc<T>) -> Result<Controller<T>> {
+        let sender = connection.register_sender(FILE_CONTROLLER_SENDER)?;
+        Ok(Controller { connection, sender })
+    }
+
+    fn send_request<U>(&self, body: U) -> Result<()>
+    where
+        U: TypedMessageBody + Buffer,
+    {
+        self.connection
+            .pack_message_body(None, self.sender, body, ServiceFlags::RELIABLE.into())
+    }
+
+    /// Change the playback rate: 1.0 is real time, 0.0 pauses.
+    pub fn set_replay_rate(&self, rate: f32) -> Result<()> {
+        self.send_request(SetReplayRate(rate))
+    }
+
+    /// Rewind playback to the start of the file.
+    pub fn reset(&self) -> Result<()> {
+        self.send_request(Reset)
+    }
+
+    /// Immediately play back everything up to the given file time.
+    pub fn play_to_time(&self, time: TimeVal) -> Result<()> {
+        self.send_request(PlayToTime(time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        time::{Microseconds, Seconds},
+        BytesMutExtras,
+    };
+
+    #[test]
+    fn play_to_time() {
+        let request = PlayToTime(TimeVal::new(
+            Seconds(0x5beb_332e),
+            Microseconds(0x000c_58b1),
+        ));
+        let buf = BytesMut::new()
+            .allocate_and_buffer(request)
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &hex!("5b eb 33 2e 00 0c 58 b1")[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(PlayToTime::unbuffer_ref(&mut buf).unwrap(), request);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn set_replay_rate() {
+        let buf = BytesMut::new()
+            .allocate_and_buffer(SetReplayRate(2.0))
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &hex!("40 00 00 00")[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(
+            SetReplayRate::unbuffer_ref(&mut buf).unwrap(),
+            SetReplayRate(2.0)
+        );
+    }
+}
@@ -0,0 +1,547 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    Buffer, Connection, ConstantBufferSize, EmptyResult, LocalId, Message, MessageTypeIdentifier,
+    Quat, Result, SenderId, SenderName, ServiceFlags, StaticTypeName, TypedMessageBody, Unbuffer,
+    Vec3,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, Weak},
+};
+
+fn buffer_f32s<T: BufMut>(values: &[f32], buf: &mut T) -> EmptyResult {
+    for v in values {
+        v.buffer_ref(buf)?;
+    }
+    Ok(())
+}
+
+fn unbuffer_f32s(values: &mut [f32], buf: &mut Bytes) -> EmptyResult {
+    for v in values.iter_mut() {
+        *v = f32::unbuffer_ref(buf)?;
+    }
+    Ok(())
+}
+
+/// Force currently being displayed by a haptic device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForceReport {
+    pub force: Vec3,
+}
+
+impl TypedMessageBody for ForceReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_ForceDevice Force"));
+}
+
+impl ConstantBufferSize for ForceReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size()
+    }
+}
+
+impl Buffer for ForceReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.force.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for ForceReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let force = Vec3::unbuffer_ref(buf)?;
+        Ok(ForceReport { force })
+    }
+}
+
+/// Surface contact point: where the haptic probe is touching the surface.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScpReport {
+    pub pos: Vec3,
+    pub quat: Quat,
+}
+
+impl TypedMessageBody for ScpReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_ForceDevice SCP"));
+}
+
+impl ConstantBufferSize for ScpReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size()
+    }
+}
+
+impl Buffer for ScpReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.pos.buffer_ref(buf)?;
+        self.quat.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for ScpReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let pos = Vec3::unbuffer_ref(buf)?;
+        let quat = Quat::unbuffer_ref(buf)?;
+        Ok(ScpReport { pos, quat })
+    }
+}
+
+/// A force field with a constant force at its origin, varying linearly
+/// (according to the jacobian) with distance from the origin, and zero beyond the radius.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForceField {
+    pub origin: [f32; 3],
+    pub force: [f32; 3],
+    pub jacobian: [[f32; 3]; 3],
+    pub radius: f32,
+}
+
+impl ForceField {
+    /// A force field with zero radius, which turns the force field off.
+    pub fn stopped() -> ForceField {
+        ForceField {
+            origin: [0.0; 3],
+            force: [0.0; 3],
+            jacobian: [[0.0; 3]; 3],
+            radius: 0.0,
+        }
+    }
+}
+
+impl TypedMessageBody for ForceField {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_ForceDevice Force_Field"));
+}
+
+impl ConstantBufferSize for ForceField {
+    fn constant_buffer_size() -> usize {
+        f32::constant_buffer_size() * 16
+    }
+}
+
+impl Buffer for ForceField {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        buffer_f32s(&self.origin, buf)?;
+        buffer_f32s(&self.force, buf)?;
+        for row in &self.jacobian {
+            buffer_f32s(row, buf)?;
+        }
+        self.radius.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for ForceField {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let mut field = ForceField::stopped();
+        unbuffer_f32s(&mut field.origin, buf)?;
+        unbuffer_f32s(&mut field.force, buf)?;
+        for row in field.jacobian.iter_mut() {
+            unbuffer_f32s(row, buf)?;
+        }
+        field.radius = f32::unbuffer_ref(buf)?;
+        Ok(field)
+    }
+}
+
+/// A haptic plane with its surface parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plane {
+    /// Plane equation coefficients: ax + by + cz + d = 0
+    pub plane: [f32; 4],
+    /// Spring constant
+    pub kspring: f32,
+    /// Damping constant
+    pub kdamp: f32,
+    /// Dynamic friction coefficient
+    pub fdyn: f32,
+    /// Static friction coefficient
+    pub fstat: f32,
+    pub plane_index: i32,
+    /// Number of cycles to recover the surface over, after the plane moved
+    pub n_rec_cycles: i32,
+}
+
+impl TypedMessageBody for Plane {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_ForceDevice Plane"));
+}
+
+impl ConstantBufferSize for Plane {
+    fn constant_buffer_size() -> usize {
+        f32::constant_buffer_size() * 8 + i32::constant_buffer_size() * 2
+    }
+}
+
+impl Buffer for Plane {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        buffer_f32s(&self.plane, buf)?;
+        self.kspring.buffer_ref(buf)?;
+        self.kdamp.buffer_ref(buf)?;
+        self.fdyn.buffer_ref(buf)?;
+        self.fstat.buffer_ref(buf)?;
+        self.plane_index.buffer_ref(buf)?;
+        self.n_rec_cycles.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for Plane {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let mut plane = [0.0; 4];
+        unbuffer_f32s(&mut plane, buf)?;
+        let kspring = f32::unbuffer_ref(buf)?;
+        let kdamp = f32::unbuffer_ref(buf)?;
+        let fdyn = f32::unbuffer_ref(buf)?;
+        let fstat = f32::unbuffer_ref(buf)?;
+        let plane_index = i32::unbuffer_ref(buf)?;
+        let n_rec_cycles = i32::unbuffer_ref(buf)?;
+        Ok(Plane {
+            plane,
+            kspring,
+            kdamp,
+            fdyn,
+            fstat,
+            plane_index,
+            n_rec_cycles,
+        })
+    }
+}
+
+/// Set the position of one vertex of a triangle mesh object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetVertex {
+    pub object: i32,
+    pub vertex: i32,
+    pub pos: [f32; 3],
+}
+
+impl TypedMessageBody for SetVertex {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_ForceDevice setVertex"));
+}
+
+impl ConstantBufferSize for SetVertex {
+    fn constant_buffer_size() -> usize {
+        i32::constant_buffer_size() * 2 + f32::constant_buffer_size() * 3
+    }
+}
+
+impl Buffer for SetVertex {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.object.buffer_ref(buf)?;
+        self.vertex.buffer_ref(buf)?;
+        buffer_f32s(&self.pos, buf)
+    }
+}
+
+impl Unbuffer for SetVertex {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let object = i32::unbuffer_ref(buf)?;
+        let vertex = i32::unbuffer_ref(buf)?;
+        let mut pos = [0.0; 3];
+        unbuffer_f32s(&mut pos, buf)?;
+        Ok(SetVertex {
+            object,
+            vertex,
+            pos,
+        })
+    }
+}
+
+/// Set the vertices and normals of one triangle of a triangle mesh object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetTriangle {
+    pub object: i32,
+    pub triangle: i32,
+    pub vertices: [i32; 3],
+    pub normals: [i32; 3],
+}
+
+impl TypedMessageBody for SetTriangle {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_ForceDevice setTriangle"));
+}
+
+impl ConstantBufferSize for SetTriangle {
+    fn constant_buffer_size() -> usize {
+        i32::constant_buffer_size() * 8
+    }
+}
+
+impl Buffer for SetTriangle {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.object.buffer_ref(buf)?;
+        self.triangle.buffer_ref(buf)?;
+        for v in self.vertices.iter().chain(self.normals.iter()) {
+            v.buffer_ref(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Unbuffer for SetTriangle {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let object = i32::unbuffer_ref(buf)?;
+        let triangle = i32::unbuffer_ref(buf)?;
+        let mut vertices = [0; 3];
+        for v in vertices.iter_mut() {
+            *v = i32::unbuffer_ref(buf)?;
+        }
+        let mut normals = [0; 3];
+        for v in normals.iter_mut() {
+            *v = i32::unbuffer_ref(buf)?;
+        }
+        Ok(SetTriangle {
+            object,
+            triangle,
+            vertices,
+            normals,
+        })
+    }
+}
+
+/// The most recent reports received from a force device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ForceState {
+    pub force: Option<ForceReport>,
+    pub scp: Option<ScpReport>,
+}
+
+/// Implemented by the reports that update the ForceState kept by Remote.
+trait ForceStateUpdate: TypedMessageBody + Unbuffer + Clone + Send + Sync {
+    fn update(&self, state: &mut ForceState);
+}
+
+impl ForceStateUpdate for ForceReport {
+    fn update(&self, state: &mut ForceState) {
+        state.force = Some(self.clone());
+    }
+}
+
+impl ForceStateUpdate for ScpReport {
+    fn update(&self, state: &mut ForceState) {
+        state.scp = Some(self.clone());
+    }
+}
+
+struct ForceStateHandler<U: ForceStateUpdate> {
+    state: Weak<Mutex<ForceState>>,
+    phantom: PhantomData<U>,
+}
+
+impl<U: ForceStateUpdate> ForceStateHandler<U> {
+    fn new(state: &Arc<Mutex<ForceState>>) -> Box<ForceStateHandler<U>> {
+        Box::new(ForceStateHandler {
+            state: Arc::downgrade(state),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<U: ForceStateUpdate> fmt::Debug for ForceStateHandler<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ForceStateHandler").finish()
+    }
+}
+
+impl<U: ForceStateUpdate> TypedHandler for ForceStateHandler<U> {
+    type Item = U;
+    fn handle_typed(&mut self, msg: &Message<U>) -> Result<HandlerCode> {
+        match self.state.upgrade() {
+            Some(state) => {
+                let mut state = state.lock()?;
+                msg.body.update(&mut state);
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for a force device: sends force fields, planes and
+/// mesh updates to the server, and keeps the latest force and contact point reports.
+///
+/// For notification of each report as it arrives, add your own TypedHandler
+/// with `Item = ForceReport` or `Item = ScpReport` to the connection.
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    state: Arc<Mutex<ForceState>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let state = Arc::new(Mutex::new(ForceState::default()));
+        let _ = connection
+            .add_typed_handler(ForceStateHandler::<ForceReport>::new(&state), Some(sender))?;
+        let _ = connection
+            .add_typed_handler(ForceStateHandler::<ScpReport>::new(&state), Some(sender))?;
+        Ok(Remote {
+            connection,
+            sender,
+            state,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// The local sender ID of the device.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Get the most recent reports received.
+    pub fn state(&self) -> Result<ForceState> {
+        Ok(self.state.lock()?.clone())
+    }
+
+    fn send<U>(&self, body: U) -> Result<()>
+    where
+        U: TypedMessageBody + Buffer,
+    {
+        self.connection
+            .pack_message_body(None, self.sender, body, ServiceFlags::RELIABLE.into())
+    }
+
+    /// Start (or update) a force field.
+    pub fn send_force_field(&self, field: ForceField) -> Result<()> {
+        self.send(field)
+    }
+
+    /// Turn off the force field.
+    pub fn stop_force_field(&self) -> Result<()> {
+        self.send(ForceField::stopped())
+    }
+
+    /// Set (or update) a haptic plane.
+    pub fn send_plane(&self, plane: Plane) -> Result<()> {
+        self.send(plane)
+    }
+
+    /// Set the position of a vertex of a triangle mesh.
+    pub fn set_vertex(&self, vertex: SetVertex) -> Result<()> {
+        self.send(vertex)
+    }
+
+    /// Set the vertices and normals of a triangle of a triangle mesh.
+    pub fn set_triangle(&self, triangle: SetTriangle) -> Result<()> {
+        self.send(triangle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        prelude::*,
+        test_util::{dispatch, unconnected},
+        StaticSenderName,
+    };
+
+    #[test]
+    fn force_field() {
+        let mut field = ForceField::stopped();
+        field.origin = [1.0, 0.0, 0.0];
+        field.jacobian[2][2] = -2.0;
+        field.radius = 0.5;
+        assert_eq!(field.buffer_size(), 64);
+        let buf = BytesMut::new()
+            .allocate_and_buffer(field.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..4], &hex!("3f 80 00 00")[..]);
+        assert_eq!(&buf[56..], &hex!("c0 00 00 00 3f 00 00 00")[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(ForceField::unbuffer_ref(&mut buf).unwrap(), field);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn plane() {
+        let plane = Plane {
+            plane: [0.0, 1.0, 0.0, 0.0],
+            kspring: 0.5,
+            kdamp: 0.0,
+            fdyn: 0.0,
+            fstat: 0.0,
+            plane_index: 1,
+            n_rec_cycles: 2,
+        };
+        let expected = hex!(
+            "00 00 00 00 3f 80 00 00 00 00 00 00 00 00 00 00
+             3f 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 01 00 00 00 02"
+        );
+        assert_eq!(plane.buffer_size(), expected.len());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(plane.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(Plane::unbuffer_ref(&mut buf).unwrap(), plane);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn mesh() {
+        let vertex = SetVertex {
+            object: 0,
+            vertex: 5,
+            pos: [0.0, 0.5, 1.0],
+        };
+        let expected = hex!("00 00 00 00 00 00 00 05 00 00 00 00 3f 00 00 00 3f 80 00 00");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(vertex.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        assert_eq!(SetVertex::unbuffer_ref(&mut buf.freeze()).unwrap(), vertex);
+
+        let triangle = SetTriangle {
+            object: 0,
+            triangle: 1,
+            vertices: [2, 3, 4],
+            normals: [-1, -1, -1],
+        };
+        assert_eq!(triangle.buffer_size(), 32);
+        let buf = BytesMut::new()
+            .allocate_and_buffer(triangle.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(
+            SetTriangle::unbuffer_ref(&mut buf.freeze()).unwrap(),
+            triangle
+        );
+    }
+
+    #[test]
+    fn remote() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Phantom0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+        assert_eq!(remote.state().unwrap(), ForceState::default());
+
+        dispatch(
+            &conn,
+            remote.sender(),
+            ForceReport {
+                force: Vec3::new(0.0, 1.5, 0.0),
+            },
+        );
+        let state = remote.state().unwrap();
+        assert_eq!(state.force.unwrap().force, Vec3::new(0.0, 1.5, 0.0));
+        assert_eq!(state.scp, None);
+    }
+}
@@ -0,0 +1,870 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::prelude::*;
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    Buffer, BufferSize, BytesRequired, Connection, ConstantBufferSize, EmptyResult, Error, LocalId,
+    Message, MessageTypeIdentifier, Result, SenderId, SenderName, StaticTypeName, TimeVal,
+    TypedMessageBody, Unbuffer,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Size of the fixed-length name and units fields of a channel description, matching cName.
+pub const CHANNEL_NAME_LEN: usize = 100;
+
+/// Maximum number of channels in an imager description, matching vrpn_IMAGER_MAX_CHANNELS.
+pub const CHANNEL_MAX: usize = 10;
+
+/// Maximum number of values (depth * cols * rows) in one channel of a frame
+/// that we're willing to allocate for.
+pub const CHANNEL_VALUES_MAX: usize = 1 << 24;
+
+fn buffer_fixed_string<T: BufMut>(s: &str, buf: &mut T) -> EmptyResult {
+    if s.len() >= CHANNEL_NAME_LEN {
+        return Err(Error::OtherMessage(format!(
+            "imager channel string '{}' is too long, the maximum is {} bytes",
+            s,
+            CHANNEL_NAME_LEN - 1
+        )));
+    }
+    buf.put(s.as_bytes());
+    for _ in s.len()..CHANNEL_NAME_LEN {
+        buf.put_u8(0);
+    }
+    Ok(())
+}
+
+fn unbuffer_fixed_string(buf: &mut Bytes) -> Result<String> {
+    if buf.len() < CHANNEL_NAME_LEN {
+        return Err(Error::NeedMoreData(BytesRequired::Exactly(
+            CHANNEL_NAME_LEN - buf.len(),
+        )));
+    }
+    let field = buf.split_to(CHANNEL_NAME_LEN);
+    let len = field
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(CHANNEL_NAME_LEN);
+    Ok(String::from_utf8_lossy(&field[..len]).into_owned())
+}
+
+/// Description of one channel of an imager.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImagerChannel {
+    pub name: String,
+    pub units: String,
+    pub min_val: f64,
+    pub max_val: f64,
+    /// Offset to apply to raw values, after the scale, to get values in `units`.
+    pub offset: f32,
+    /// Scale to apply to raw values to get values in `units`.
+    pub scale: f32,
+    pub compression: u32,
+}
+
+impl ImagerChannel {
+    pub fn new(name: impl Into<String>, units: impl Into<String>) -> ImagerChannel {
+        ImagerChannel {
+            name: name.into(),
+            units: units.into(),
+            min_val: 0.0,
+            max_val: 0.0,
+            offset: 0.0,
+            scale: 1.0,
+            compression: 0,
+        }
+    }
+}
+
+impl ConstantBufferSize for ImagerChannel {
+    fn constant_buffer_size() -> usize {
+        f64::constant_buffer_size() * 2
+            + f32::constant_buffer_size() * 2
+            + u32::constant_buffer_size()
+            + CHANNEL_NAME_LEN * 2
+    }
+}
+
+impl Buffer for ImagerChannel {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if buf.remaining_mut() < Self::constant_buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        self.min_val.buffer_ref(buf)?;
+        self.max_val.buffer_ref(buf)?;
+        self.offset.buffer_ref(buf)?;
+        self.scale.buffer_ref(buf)?;
+        self.compression.buffer_ref(buf)?;
+        buffer_fixed_string(&self.name, buf)?;
+        buffer_fixed_string(&self.units, buf)
+    }
+}
+
+impl Unbuffer for ImagerChannel {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let min_val = f64::unbuffer_ref(buf)?;
+        let max_val = f64::unbuffer_ref(buf)?;
+        let offset = f32::unbuffer_ref(buf)?;
+        let scale = f32::unbuffer_ref(buf)?;
+        let compression = u32::unbuffer_ref(buf)?;
+        let name = unbuffer_fixed_string(buf)?;
+        let units = unbuffer_fixed_string(buf)?;
+        Ok(ImagerChannel {
+            name,
+            units,
+            min_val,
+            max_val,
+            offset,
+            scale,
+            compression,
+        })
+    }
+}
+
+/// Dimensions and channels of an imager.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImagerDescription {
+    pub depth: i32,
+    pub cols: i32,
+    pub rows: i32,
+    pub channels: Vec<ImagerChannel>,
+}
+
+impl ImagerDescription {
+    /// Number of values in one channel of a whole frame.
+    ///
+    /// Fails if the dimensions are negative or the frame would be unreasonably large.
+    pub fn values_per_channel(&self) -> Result<usize> {
+        let too_large = || {
+            Error::OtherMessage(format!(
+                "imager description has dimensions {}x{}x{}, but the maximum is {} values",
+                self.depth, self.cols, self.rows, CHANNEL_VALUES_MAX
+            ))
+        };
+        if self.depth < 0 || self.cols < 0 || self.rows < 0 {
+            return Err(too_large());
+        }
+        (self.depth as usize)
+            .checked_mul(self.cols as usize)
+            .and_then(|n| n.checked_mul(self.rows as usize))
+            .filter(|&n| n <= CHANNEL_VALUES_MAX)
+            .ok_or_else(too_large)
+    }
+}
+
+impl TypedMessageBody for ImagerDescription {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Imager Description"));
+}
+
+impl BufferSize for ImagerDescription {
+    fn buffer_size(&self) -> usize {
+        i32::constant_buffer_size() * 4
+            + ImagerChannel::constant_buffer_size() * self.channels.len()
+    }
+}
+
+impl Buffer for ImagerDescription {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        self.depth.buffer_ref(buf)?;
+        self.cols.buffer_ref(buf)?;
+        self.rows.buffer_ref(buf)?;
+        (self.channels.len() as i32).buffer_ref(buf)?;
+        for channel in &self.channels {
+            channel.buffer_ref(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Unbuffer for ImagerDescription {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let depth = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        let cols = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        let rows = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        let num_channels = i32::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        if num_channels < 0 || num_channels as usize > CHANNEL_MAX {
+            return Err(Error::OtherMessage(format!(
+                "imager description claims {} channels, but the maximum is {}",
+                num_channels, CHANNEL_MAX
+            )));
+        }
+        let needed = num_channels as usize * ImagerChannel::constant_buffer_size();
+        if buf.len() < needed {
+            return Err(Error::NeedMoreData(BytesRequired::Exactly(
+                needed - buf.len(),
+            )));
+        }
+        let channels = (0..num_channels)
+            .map(|_| ImagerChannel::unbuffer_ref(buf))
+            .collect::<Result<Vec<ImagerChannel>>>()?;
+        let desc = ImagerDescription {
+            depth,
+            cols,
+            rows,
+            channels,
+        };
+        let _ = desc.values_per_channel()?;
+        Ok(desc)
+    }
+}
+
+/// Inclusive column, row and depth bounds of a frame or region.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FrameBounds {
+    pub c_min: u16,
+    pub c_max: u16,
+    pub r_min: u16,
+    pub r_max: u16,
+    pub d_min: u16,
+    pub d_max: u16,
+}
+
+impl FrameBounds {
+    /// Number of values covered by these bounds, or None if any minimum exceeds its maximum.
+    pub fn value_count(&self) -> Option<usize> {
+        if self.c_min > self.c_max || self.r_min > self.r_max || self.d_min > self.d_max {
+            return None;
+        }
+        Some(
+            (self.c_max as usize - self.c_min as usize + 1)
+                * (self.r_max as usize - self.r_min as usize + 1)
+                * (self.d_max as usize - self.d_min as usize + 1),
+        )
+    }
+}
+
+impl ConstantBufferSize for FrameBounds {
+    fn constant_buffer_size() -> usize {
+        u16::constant_buffer_size() * 6
+    }
+}
+
+impl Buffer for FrameBounds {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.c_min.buffer_ref(buf)?;
+        self.c_max.buffer_ref(buf)?;
+        self.r_min.buffer_ref(buf)?;
+        self.r_max.buffer_ref(buf)?;
+        self.d_min.buffer_ref(buf)?;
+        self.d_max.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for FrameBounds {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let c_min = u16::unbuffer_ref(buf)?;
+        let c_max = u16::unbuffer_ref(buf)?;
+        let r_min = u16::unbuffer_ref(buf)?;
+        let r_max = u16::unbuffer_ref(buf)?;
+        let d_min = u16::unbuffer_ref(buf)?;
+        let d_max = u16::unbuffer_ref(buf)?;
+        Ok(FrameBounds {
+            c_min,
+            c_max,
+            r_min,
+            r_max,
+            d_min,
+            d_max,
+        })
+    }
+}
+
+/// Marks the start of a frame: the regions that follow, up to the EndFrame, belong to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BeginFrame(pub FrameBounds);
+
+impl TypedMessageBody for BeginFrame {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Imager Begin_Frame"));
+}
+
+impl ConstantBufferSize for BeginFrame {
+    fn constant_buffer_size() -> usize {
+        FrameBounds::constant_buffer_size()
+    }
+}
+
+impl Buffer for BeginFrame {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.0.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for BeginFrame {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        FrameBounds::unbuffer_ref(buf).map(BeginFrame)
+    }
+}
+
+/// Marks the end of a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EndFrame(pub FrameBounds);
+
+impl TypedMessageBody for EndFrame {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Imager End_Frame"));
+}
+
+impl ConstantBufferSize for EndFrame {
+    fn constant_buffer_size() -> usize {
+        FrameBounds::constant_buffer_size()
+    }
+}
+
+impl Buffer for EndFrame {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.0.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for EndFrame {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        FrameBounds::unbuffer_ref(buf).map(EndFrame)
+    }
+}
+
+/// Number of frames the server dropped rather than send.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiscardedFrames {
+    pub count: u16,
+}
+
+impl TypedMessageBody for DiscardedFrames {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Imager Discarded_Frames"));
+}
+
+impl ConstantBufferSize for DiscardedFrames {
+    fn constant_buffer_size() -> usize {
+        u16::constant_buffer_size()
+    }
+}
+
+impl Buffer for DiscardedFrames {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.count.buffer_ref(buf)
+    }
+}
+
+impl Unbuffer for DiscardedFrames {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let count = u16::unbuffer_ref(buf)?;
+        Ok(DiscardedFrames { count })
+    }
+}
+
+/// Implemented by the value types that imager regions can carry.
+pub trait RegionValue:
+    Buffer + Unbuffer + ConstantBufferSize + Copy + fmt::Debug + PartialEq + Send + Sync + 'static
+{
+    /// The message type for regions with this value type.
+    const REGION_MESSAGE: MessageTypeIdentifier;
+    /// The value type code sent in the region header.
+    const VALTYPE: u16;
+
+    fn to_f32(self) -> f32;
+}
+
+impl RegionValue for u8 {
+    const REGION_MESSAGE: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Imager Regionu8"));
+    const VALTYPE: u16 = 1;
+
+    fn to_f32(self) -> f32 {
+        f32::from(self)
+    }
+}
+
+impl RegionValue for u16 {
+    const REGION_MESSAGE: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Imager Regionu16"));
+    const VALTYPE: u16 = 3;
+
+    fn to_f32(self) -> f32 {
+        f32::from(self)
+    }
+}
+
+impl RegionValue for f32 {
+    const REGION_MESSAGE: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Imager Regionf32"));
+    const VALTYPE: u16 = 4;
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+/// A block of values for one channel of an imager.
+///
+/// Values are in row-major order within each depth slice: the column index varies fastest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region<V: RegionValue> {
+    pub channel: u16,
+    pub bounds: FrameBounds,
+    pub values: Vec<V>,
+}
+
+impl<V: RegionValue> Region<V> {
+    fn header_size() -> usize {
+        u16::constant_buffer_size() * 2 + FrameBounds::constant_buffer_size()
+    }
+}
+
+impl<V: RegionValue> TypedMessageBody for Region<V> {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = V::REGION_MESSAGE;
+}
+
+impl<V: RegionValue> BufferSize for Region<V> {
+    fn buffer_size(&self) -> usize {
+        Self::header_size() + V::constant_buffer_size() * self.values.len()
+    }
+}
+
+impl<V: RegionValue> Buffer for Region<V> {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if self.bounds.value_count() != Some(self.values.len()) {
+            return Err(Error::OtherMessage(format!(
+                "imager region bounds {:?} do not match its {} values",
+                self.bounds,
+                self.values.len()
+            )));
+        }
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        self.channel.buffer_ref(buf)?;
+        self.bounds.buffer_ref(buf)?;
+        V::VALTYPE.buffer_ref(buf)?;
+        for value in &self.values {
+            value.buffer_ref(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl<V: RegionValue> Unbuffer for Region<V> {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let channel = u16::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        let bounds = FrameBounds::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        let _valtype = u16::unbuffer_ref(buf).map_exactly_err_to_at_least()?;
+        let count = bounds.value_count().ok_or_else(|| {
+            Error::OtherMessage(format!("imager region has invalid bounds {:?}", bounds))
+        })?;
+        let needed = count * V::constant_buffer_size();
+        if buf.len() < needed {
+            return Err(Error::NeedMoreData(BytesRequired::Exactly(
+                needed - buf.len(),
+            )));
+        }
+        let values = (0..count)
+            .map(|_| V::unbuffer_ref(buf))
+            .collect::<Result<Vec<V>>>()?;
+        Ok(Region {
+            channel,
+            bounds,
+            values,
+        })
+    }
+}
+
+/// A whole frame put back together from its regions.
+///
+/// Values are raw (before applying the channel scale and offset) and indexed as
+/// `(depth * rows + row) * cols + col`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Timestamp of the end-of-frame message.
+    pub time: TimeVal,
+    pub bounds: FrameBounds,
+    pub channels: Vec<Vec<f32>>,
+}
+
+/// What an imager Remote has received so far.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImagerState {
+    pub description: Option<ImagerDescription>,
+    /// The most recently completed frame.
+    pub frame: Option<Frame>,
+    /// Number of frames completed since the Remote was created.
+    pub frames_completed: usize,
+    /// Total number of frames the server reported discarding.
+    pub frames_discarded: usize,
+    in_progress: Option<Vec<Vec<f32>>>,
+}
+
+impl ImagerState {
+    fn blank_frame(&self) -> Result<Option<Vec<Vec<f32>>>> {
+        match self.description {
+            Some(ref desc) => {
+                let values = desc.values_per_channel()?;
+                Ok(Some(vec![vec![0.0; values]; desc.channels.len()]))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Implemented by the messages that update the ImagerState kept by Remote.
+trait ImagerUpdate: TypedMessageBody + Unbuffer + Send + Sync {
+    fn update(&self, time: TimeVal, state: &mut ImagerState) -> Result<()>;
+}
+
+impl ImagerUpdate for ImagerDescription {
+    fn update(&self, _time: TimeVal, state: &mut ImagerState) -> Result<()> {
+        // Check the dimensions before taking them on.
+        let _ = self.values_per_channel()?;
+        state.description = Some(self.clone());
+        // Any partial frame was for the old dimensions.
+        state.in_progress = None;
+        Ok(())
+    }
+}
+
+impl ImagerUpdate for BeginFrame {
+    fn update(&self, _time: TimeVal, state: &mut ImagerState) -> Result<()> {
+        state.in_progress = state.blank_frame()?;
+        Ok(())
+    }
+}
+
+impl ImagerUpdate for EndFrame {
+    fn update(&self, time: TimeVal, state: &mut ImagerState) -> Result<()> {
+        if let Some(channels) = state.in_progress.take() {
+            state.frame = Some(Frame {
+                time,
+                bounds: self.0,
+                channels,
+            });
+            state.frames_completed += 1;
+        }
+        Ok(())
+    }
+}
+
+impl ImagerUpdate for DiscardedFrames {
+    fn update(&self, _time: TimeVal, state: &mut ImagerState) -> Result<()> {
+        state.frames_discarded += self.count as usize;
+        Ok(())
+    }
+}
+
+impl<V: RegionValue> ImagerUpdate for Region<V> {
+    fn update(&self, _time: TimeVal, state: &mut ImagerState) -> Result<()> {
+        let (cols, rows) = match state.description {
+            Some(ref desc) => (desc.cols as usize, desc.rows as usize),
+            None => return Ok(()),
+        };
+        if state.in_progress.is_none() {
+            // Servers may send regions without framing them.
+            state.in_progress = state.blank_frame()?;
+        }
+        let channel = match state
+            .in_progress
+            .as_mut()
+            .and_then(|frame| frame.get_mut(self.channel as usize))
+        {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let b = &self.bounds;
+        let mut values = self.values.iter();
+        for d in b.d_min..=b.d_max {
+            for r in b.r_min..=b.r_max {
+                for c in b.c_min..=b.c_max {
+                    let value = match values.next() {
+                        Some(v) => v.to_f32(),
+                        None => return Ok(()),
+                    };
+                    let (d, r, c) = (d as usize, r as usize, c as usize);
+                    // Values outside the described image are dropped.
+                    if r < rows && c < cols {
+                        if let Some(dest) = channel.get_mut((d * rows + r) * cols + c) {
+                            *dest = value;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ImagerHandler<U: ImagerUpdate> {
+    state: Weak<Mutex<ImagerState>>,
+    phantom: PhantomData<U>,
+}
+
+impl<U: ImagerUpdate> ImagerHandler<U> {
+    fn new(state: &Arc<Mutex<ImagerState>>) -> Box<ImagerHandler<U>> {
+        Box::new(ImagerHandler {
+            state: Arc::downgrade(state),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<U: ImagerUpdate> fmt::Debug for ImagerHandler<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImagerHandler").finish()
+    }
+}
+
+impl<U: ImagerUpdate> TypedHandler for ImagerHandler<U> {
+    type Item = U;
+    fn handle_typed(&mut self, msg: &Message<U>) -> Result<HandlerCode> {
+        match self.state.upgrade() {
+            Some(state) => {
+                let mut state = state.lock()?;
+                msg.body.update(msg.header.time, &mut state)?;
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for an imager: keeps the description and puts regions
+/// back together into whole frames.
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    state: Arc<Mutex<ImagerState>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let state = Arc::new(Mutex::new(ImagerState::default()));
+        let _ = connection.add_typed_handler(
+            ImagerHandler::<ImagerDescription>::new(&state),
+            Some(sender),
+        )?;
+        let _ =
+            connection.add_typed_handler(ImagerHandler::<BeginFrame>::new(&state), Some(sender))?;
+        let _ =
+            connection.add_typed_handler(ImagerHandler::<EndFrame>::new(&state), Some(sender))?;
+        let _ = connection
+            .add_typed_handler(ImagerHandler::<DiscardedFrames>::new(&state), Some(sender))?;
+        let _ =
+            connection.add_typed_handler(ImagerHandler::<Region<u8>>::new(&state), Some(sender))?;
+        let _ = connection
+            .add_typed_handler(ImagerHandler::<Region<u16>>::new(&state), Some(sender))?;
+        let _ = connection
+            .add_typed_handler(ImagerHandler::<Region<f32>>::new(&state), Some(sender))?;
+        Ok(Remote {
+            connection,
+            sender,
+            state,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// Access the connection this remote is using.
+    pub fn connection(&self) -> &Arc<T> {
+        &self.connection
+    }
+
+    /// The local sender ID of the device.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Get the imager description, if it has been received.
+    pub fn description(&self) -> Result<Option<ImagerDescription>> {
+        Ok(self.state.lock()?.description.clone())
+    }
+
+    /// Get the most recently completed frame, if any.
+    pub fn frame(&self) -> Result<Option<Frame>> {
+        Ok(self.state.lock()?.frame.clone())
+    }
+
+    /// Get everything received so far.
+    pub fn state(&self) -> Result<ImagerState> {
+        Ok(self.state.lock()?.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        test_util::{dispatch, unconnected},
+        SequenceNumber, SequencedGenericMessage, StaticSenderName, TypeId,
+    };
+
+    fn description() -> ImagerDescription {
+        ImagerDescription {
+            depth: 1,
+            cols: 4,
+            rows: 2,
+            channels: vec![ImagerChannel::new("gray", "counts")],
+        }
+    }
+
+    #[test]
+    fn description_roundtrip() {
+        let desc = description();
+        assert_eq!(desc.buffer_size(), 16 + 228);
+        let buf = BytesMut::new()
+            .allocate_and_buffer(desc.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(
+            &buf[..16],
+            &hex!("00 00 00 01 00 00 00 04 00 00 00 02 00 00 00 01")[..]
+        );
+        assert_eq!(&buf[44..48], &b"gray"[..]);
+        assert_eq!(buf[48], 0);
+        let mut buf = buf.freeze();
+        assert_eq!(ImagerDescription::unbuffer_ref(&mut buf).unwrap(), desc);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn oversized_description() {
+        let mut desc = description();
+        desc.depth = i32::MAX;
+        desc.cols = i32::MAX;
+        desc.rows = i32::MAX;
+        assert!(desc.values_per_channel().is_err());
+        let mut buf = BytesMut::new()
+            .allocate_and_buffer(desc)
+            .expect("Buffering needs to succeed")
+            .freeze();
+        assert!(ImagerDescription::unbuffer_ref(&mut buf).is_err());
+
+        let mut desc = description();
+        desc.rows = -1;
+        assert!(desc.values_per_channel().is_err());
+    }
+
+    #[test]
+    fn region_u16() {
+        let region = Region {
+            channel: 0,
+            bounds: FrameBounds {
+                c_min: 1,
+                c_max: 2,
+                r_min: 0,
+                r_max: 0,
+                d_min: 0,
+                d_max: 0,
+            },
+            values: vec![0x1234_u16, 0xabcd],
+        };
+        let expected = hex!("00 00 00 01 00 02 00 00 00 00 00 00 00 00 00 03 12 34 ab cd");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(region.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(Region::<u16>::unbuffer_ref(&mut buf).unwrap(), region);
+        assert_eq!(buf.len(), 0);
+
+        let mut short = Bytes::from(&expected[..18]);
+        match Region::<u16>::unbuffer_ref(&mut short) {
+            Err(Error::NeedMoreData(BytesRequired::Exactly(n))) => assert_eq!(n, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn large_region_message() {
+        // An odd-sized body, to exercise the padding of a large message.
+        let bounds = FrameBounds {
+            c_min: 0,
+            c_max: 62,
+            r_min: 0,
+            r_max: 46,
+            d_min: 0,
+            d_max: 0,
+        };
+        let values: Vec<u8> = (0..bounds.value_count().unwrap())
+            .map(|i| i as u8)
+            .collect();
+        let region = Region {
+            channel: 0,
+            bounds,
+            values,
+        };
+        let msg = Message::new(None, TypeId(3), SenderId(0), region.clone())
+            .try_into_generic()
+            .unwrap()
+            .into_sequenced_message(SequenceNumber(0));
+        let buf = BytesMut::new()
+            .allocate_and_buffer(msg)
+            .expect("Buffering needs to succeed");
+        assert_eq!(buf.len() % 8, 0);
+        let (generic, rest) = buf.freeze().unbuffer::<SequencedGenericMessage>().unwrap();
+        assert_eq!(rest.len(), 0);
+        let decoded = Message::<Region<u8>>::try_from_generic(&generic.message).unwrap();
+        assert_eq!(decoded.body, region);
+    }
+
+    #[test]
+    fn remote_assembles_frame() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Imager0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+
+        let sender = remote.sender();
+        let full = FrameBounds {
+            c_min: 0,
+            c_max: 3,
+            r_min: 0,
+            r_max: 1,
+            d_min: 0,
+            d_max: 0,
+        };
+        dispatch(&conn, sender, description());
+        dispatch(&conn, sender, BeginFrame(full));
+        for row in 0..2 {
+            dispatch(
+                &conn,
+                sender,
+                Region {
+                    channel: 0,
+                    bounds: FrameBounds {
+                        r_min: row,
+                        r_max: row,
+                        ..full
+                    },
+                    values: vec![row as u8 * 10; 4],
+                },
+            );
+        }
+        assert_eq!(remote.frame().unwrap(), None);
+        dispatch(&conn, sender, EndFrame(full));
+        dispatch(&conn, sender, DiscardedFrames { count: 2 });
+
+        let state = remote.state().unwrap();
+        let frame = state.frame.expect("should have a frame");
+        assert_eq!(
+            frame.channels,
+            vec![vec![0.0, 0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 10.0]]
+        );
+        assert_eq!(state.frames_completed, 1);
+        assert_eq!(state.frames_discarded, 2);
+    }
+}
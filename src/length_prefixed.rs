@@ -41,20 +41,20 @@ pub fn buffer_string<T: BufMut>(
     termination: NullTermination,
     null_in_len: LengthBehavior,
 ) -> EmptyResult {
-    let mut buf_size = buffer_size(s, termination);
-    if buf.remaining_mut() < buf_size {
+    if buf.remaining_mut() < buffer_size(s, termination) {
         return Err(Error::OutOfBuffer);
     }
-    if termination == NullTermination::AddTrailingNull && null_in_len == LengthBehavior::ExcludeNull
+    // The length we transmit doesn't count the length field itself.
+    let mut len = s.len();
+    if termination == NullTermination::AddTrailingNull && null_in_len == LengthBehavior::IncludeNull
     {
-        // Decrement the length that we transmit if we're adding a null terminator but not including it in the length.
-        buf_size -= 1;
+        len += 1;
     }
-    let buf_size = buf_size as u32;
-    buf_size.buffer_ref(buf).and_then(|()| {
+    (len as u32).buffer_ref(buf).map(|()| {
         buf.put(s);
-        buf.put_u8(0);
-        Ok(())
+        if termination == NullTermination::AddTrailingNull {
+            buf.put_u8(0);
+        }
     })
 }
 
@@ -28,22 +28,34 @@ extern crate quick_error;
 #[macro_use]
 extern crate tokio;
 
+pub mod analog;
+pub mod analog_output;
 pub mod async_io;
+pub mod auxiliary_logger;
 pub mod buffer;
+pub mod button;
+pub mod clock;
 pub mod connection;
 pub mod constants;
 pub mod cookie;
 pub mod descriptions;
+pub mod dial;
 pub mod endpoint;
 pub mod error;
+pub mod file_controller;
+pub mod force_device;
 pub mod handler;
+pub mod imager;
 pub mod length_prefixed;
+pub mod locator;
 pub mod log;
 pub mod message;
 pub mod ping;
+pub mod poser;
 pub mod prelude;
 pub mod primitives;
 pub mod size;
+pub mod text;
 pub mod time;
 pub mod tracker;
 pub mod translation_table;
@@ -51,15 +63,19 @@ pub mod type_dispatcher;
 pub mod types;
 pub mod unbuffer;
 
+#[cfg(test)]
+mod test_util;
+
 pub use crate::{
     buffer::{BufMutExtras, Buffer, BytesMutExtras},
     connection::Connection,
     cookie::{CookieData, Version},
-    descriptions::{Description, UdpDescription},
+    descriptions::{Description, LobPacket, UdpDescription},
     endpoint::*,
     error::*,
     handler::{Handler, TypedBodylessHandler, TypedHandler},
-    log::{LogFileNames, LogFlags, LogMode},
+    locator::DeviceLocator,
+    log::{LogEntry, LogFileNames, LogFileWriter, LogFlags, LogMode},
     message::{
         GenericBody, GenericMessage, Message, MessageBody, MessageHeader, MessageTypeIdentifier,
         MessageTypeIdentifier::UserMessageName, SequencedGenericMessage, SequencedMessage,
@@ -0,0 +1,215 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::Bytes;
+use crate::{constants::DEFAULT_PORT, Error, Result, SenderName};
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+/// How to reach the connection named in a device locator.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    /// "tcp://": connect directly over TCP, with no UDP channel.
+    Tcp,
+    /// "x-vrpn://" or no scheme: lob a UDP packet at the server so it connects back
+    /// over TCP, then also use UDP for low-latency messages, like the C++ client.
+    UdpAndTcp,
+    /// "file:": play back a log file.
+    File,
+}
+
+/// A parsed device name, like "Tracker0@localhost" or "Tracker0@file:///tmp/log.vrpn".
+///
+/// The part before the first '@' is the device (sender) name,
+/// the part after says where its connection is.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceLocator {
+    device: SenderName,
+    transport: Transport,
+    address: String,
+    port: Option<u16>,
+}
+
+impl DeviceLocator {
+    pub fn parse(locator: &str) -> Result<DeviceLocator> {
+        let invalid = || Error::InvalidLocator(locator.to_string());
+        let at = locator.find('@').ok_or_else(invalid)?;
+        let (device, rest) = (&locator[..at], &locator[at + 1..]);
+        if device.is_empty() {
+            return Err(invalid());
+        }
+        let device = SenderName(Bytes::from(device));
+
+        if let Some(path) = rest
+            .strip_prefix("file://")
+            .or_else(|| rest.strip_prefix("file:"))
+        {
+            if path.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(DeviceLocator {
+                device,
+                transport: Transport::File,
+                address: path.to_string(),
+                port: None,
+            });
+        }
+
+        let (transport, host_port) = if let Some(host_port) = rest.strip_prefix("tcp://") {
+            (Transport::Tcp, host_port)
+        } else if let Some(host_port) = rest.strip_prefix("x-vrpn://") {
+            (Transport::UdpAndTcp, host_port)
+        } else {
+            (Transport::UdpAndTcp, rest)
+        };
+        let (host, port) = split_host_port(host_port.trim_end_matches('/')).ok_or_else(invalid)?;
+        Ok(DeviceLocator {
+            device,
+            transport,
+            address: host.to_string(),
+            port: Some(port.unwrap_or(DEFAULT_PORT)),
+        })
+    }
+
+    /// The name of the device, as a sender on its connection.
+    pub fn device(&self) -> SenderName {
+        self.device.clone()
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// The host name or address (without IPv6 brackets), or the path for a file.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The port, defaulted if not given: `None` only for files.
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Resolve the host and port to a socket address.
+    ///
+    /// This may block on a name lookup.
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        let port = self.port.ok_or_else(|| {
+            Error::OtherMessage(format!("{} is a file, not a network address", self.address))
+        })?;
+        (self.address.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::OtherMessage(format!("could not resolve {}", self.address)))
+    }
+}
+
+impl FromStr for DeviceLocator {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<DeviceLocator> {
+        DeviceLocator::parse(s)
+    }
+}
+
+/// Split "host", "host:port", "[v6addr]" or "[v6addr]:port".
+///
+/// An unbracketed host with more than one colon is taken to be a bare IPv6 address.
+fn split_host_port(s: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = if let Some(bracketed) = s.strip_prefix('[') {
+        let end = bracketed.find(']')?;
+        let port = match &bracketed[end + 1..] {
+            "" => None,
+            rest => Some(rest.strip_prefix(':')?),
+        };
+        (&bracketed[..end], port)
+    } else if s.matches(':').count() > 1 {
+        (s, None)
+    } else {
+        match s.rfind(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        Some(port) => Some(port.parse().ok()?),
+        None => None,
+    };
+    Some((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Transport::*, *};
+
+    fn check(cases: &[(&str, Transport, &str, Option<u16>)]) {
+        for (locator, transport, address, port) in cases {
+            let parsed = DeviceLocator::parse(locator).expect("should parse");
+            assert_eq!(parsed.device(), SenderName(Bytes::from_static(b"Tracker0")));
+            assert_eq!(parsed.transport(), *transport, "{}", locator);
+            assert_eq!(parsed.address(), *address, "{}", locator);
+            assert_eq!(parsed.port(), *port, "{}", locator);
+        }
+    }
+
+    #[test]
+    fn host_forms() {
+        check(&[
+            ("Tracker0@host", UdpAndTcp, "host", Some(3883)),
+            ("Tracker0@host:3884", UdpAndTcp, "host", Some(3884)),
+            ("Tracker0@tcp://host:3884", Tcp, "host", Some(3884)),
+            ("Tracker0@tcp://10.0.0.1/", Tcp, "10.0.0.1", Some(3883)),
+            ("Tracker0@x-vrpn://host:3884", UdpAndTcp, "host", Some(3884)),
+        ]);
+    }
+
+    #[test]
+    fn ipv6() {
+        check(&[
+            ("Tracker0@[::1]", UdpAndTcp, "::1", Some(3883)),
+            ("Tracker0@[::1]:3884", UdpAndTcp, "::1", Some(3884)),
+            ("Tracker0@tcp://[fe80::1]:3884", Tcp, "fe80::1", Some(3884)),
+            ("Tracker0@::1", UdpAndTcp, "::1", Some(3883)),
+        ]);
+    }
+
+    #[test]
+    fn file() {
+        check(&[
+            ("Tracker0@file:///tmp/log.vrpn", File, "/tmp/log.vrpn", None),
+            ("Tracker0@file:log.vrpn", File, "log.vrpn", None),
+        ]);
+    }
+
+    #[test]
+    fn invalid() {
+        for locator in &[
+            "Tracker0",
+            "@localhost",
+            "Tracker0@",
+            "Tracker0@localhost:port",
+            "Tracker0@localhost:99999",
+            "Tracker0@[::1",
+            "Tracker0@[::1]3884",
+            "Tracker0@file://",
+        ] {
+            assert!(DeviceLocator::parse(locator).is_err(), "{}", locator);
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let parsed: DeviceLocator = "Tracker0@127.0.0.1:3884".parse().unwrap();
+        assert_eq!(
+            parsed.socket_addr().unwrap(),
+            "127.0.0.1:3884".parse().unwrap()
+        );
+        let parsed: DeviceLocator = "Tracker0@file:log.vrpn".parse().unwrap();
+        assert!(parsed.socket_addr().is_err());
+    }
+}
@@ -2,11 +2,18 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use bytes::{BufMut, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 use crate::{
-    constants::LOG_DESCRIPTION, unbuffer::check_expected, Buffer, BufferSize, BytesRequired,
-    ConstantBufferSize, EmptyResult, Error, MessageTypeIdentifier, Result, TypedMessageBody,
-    Unbuffer,
+    constants::{FILE_MAGIC_DATA, LOG_DESCRIPTION},
+    unbuffer::check_expected,
+    Buffer, BufferSize, BytesMutExtras, BytesRequired, ConstantBufferSize, CookieData, EmptyResult,
+    Error, GenericBody, GenericMessage, IdType, Message, MessageHeader, MessageTypeIdentifier,
+    Result, SenderId, TimeVal, TypeId, TypedMessageBody, Unbuffer,
+};
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
 };
 
 bitmask!{
@@ -80,6 +87,48 @@ impl LogFileNames {
         in_mode | out_mode
     }
 
+    /// Get a copy of these names, keeping only those for the directions in the given mode.
+    pub fn with_mode(&self, mode: LogMode) -> LogFileNames {
+        LogFileNames {
+            in_log_file: self
+                .in_log_file
+                .clone()
+                .filter(|_| mode.contains(LogFlags::INCOMING)),
+            out_log_file: self
+                .out_log_file
+                .clone()
+                .filter(|_| mode.contains(LogFlags::OUTGOING)),
+        }
+    }
+
+    /// Get a copy of these names with a suffix added to each one, before the extension if any:
+    /// "server.vrpn" with "-1" becomes "server-1.vrpn".
+    pub fn with_suffix(&self, suffix: &str) -> LogFileNames {
+        let add_suffix = |name: &Option<Bytes>| {
+            name.as_ref().map(|name| {
+                let file_start = name
+                    .iter()
+                    .rposition(|&c| c == b'/' || c == b'\\')
+                    .map_or(0, |i| i + 1);
+                // A leading dot is part of the name, not an extension.
+                let insert_at = name[file_start..]
+                    .iter()
+                    .rposition(|&c| c == b'.')
+                    .filter(|&i| i > 0)
+                    .map_or(name.len(), |i| file_start + i);
+                let mut with_suffix = BytesMut::with_capacity(name.len() + suffix.len());
+                with_suffix.extend_from_slice(&name[..insert_at]);
+                with_suffix.extend_from_slice(suffix.as_bytes());
+                with_suffix.extend_from_slice(&name[insert_at..]);
+                with_suffix.freeze()
+            })
+        };
+        LogFileNames {
+            in_log_file: add_suffix(&self.in_log_file),
+            out_log_file: add_suffix(&self.out_log_file),
+        }
+    }
+
     pub fn filenames_iter<'a>(&'a self) -> LogFileNameIter<'a> {
         LogFileNameIter {
             names: self,
@@ -173,6 +222,20 @@ impl TypedMessageBody for LogFileNames {
         MessageTypeIdentifier::SystemMessageId(LOG_DESCRIPTION);
 }
 
+/// A log description asks the other end to log the connection to these files.
+///
+/// As in the C++ implementation, the requested log mode is carried in the "sender" field.
+impl From<LogFileNames> for Message<LogFileNames> {
+    fn from(v: LogFileNames) -> Message<LogFileNames> {
+        Message::new(
+            None,
+            LOG_DESCRIPTION,
+            SenderId(*v.log_mode() as IdType),
+            v,
+        )
+    }
+}
+
 fn unbuffer_logname(len: usize, buf: &mut Bytes) -> Result<Option<Bytes>> {
     let name = if len > 0 {
         Some(buf.split_to(len))
@@ -202,6 +265,190 @@ impl Unbuffer for LogFileNames {
     }
 }
 
+/// Size of the fixed header preceding each message body in a log file.
+pub const LOG_ENTRY_HEADER_SIZE: usize = 6 * 4;
+
+/// A message as stored in a log file, following the file's magic cookie.
+///
+/// Each entry is six i32 values: message type, sender, seconds, microseconds,
+/// body length, and a placeholder (written as 0 and ignored on read),
+/// followed by the body without any padding.
+/// Unlike on the wire, there is no sequence number.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogEntry(pub GenericMessage);
+
+impl LogEntry {
+    /// Get the body length from the start of an entry, if the header is all there.
+    pub fn peek_body_len(buf: &[u8]) -> Result<Option<usize>> {
+        if buf.len() < LOG_ENTRY_HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = i32::unbuffer_ref(&mut Bytes::from(&buf[16..20]))?;
+        if len < 0 {
+            return Err(Error::OtherMessage(format!(
+                "log entry has negative body length {}",
+                len
+            )));
+        }
+        Ok(Some(len as usize))
+    }
+}
+
+impl BufferSize for LogEntry {
+    fn buffer_size(&self) -> usize {
+        LOG_ENTRY_HEADER_SIZE + self.0.body.inner.len()
+    }
+}
+
+impl Buffer for LogEntry {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        let header = &self.0.header;
+        header.message_type.buffer_ref(buf)?;
+        header.sender.buffer_ref(buf)?;
+        header.time.buffer_ref(buf)?;
+        (self.0.body.inner.len() as i32).buffer_ref(buf)?;
+        0_i32.buffer_ref(buf)?;
+        buf.put(self.0.body.inner.clone());
+        Ok(())
+    }
+}
+
+impl Unbuffer for LogEntry {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<LogEntry> {
+        let body_len = match LogEntry::peek_body_len(buf)? {
+            Some(len) => len,
+            None => {
+                return Err(Error::NeedMoreData(BytesRequired::AtLeast(
+                    LOG_ENTRY_HEADER_SIZE - buf.len(),
+                )))
+            }
+        };
+        if buf.len() < LOG_ENTRY_HEADER_SIZE + body_len {
+            return Err(Error::NeedMoreData(BytesRequired::Exactly(
+                LOG_ENTRY_HEADER_SIZE + body_len - buf.len(),
+            )));
+        }
+        let message_type = TypeId::unbuffer_ref(buf)?;
+        let sender = SenderId::unbuffer_ref(buf)?;
+        let time = TimeVal::unbuffer_ref(buf)?;
+        // body length, already peeked, and the placeholder
+        buf.advance(2 * i32::constant_buffer_size());
+        let body = GenericBody::new(buf.split_to(body_len));
+        Ok(LogEntry(GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(time), message_type, sender),
+            body,
+        )))
+    }
+}
+
+fn log_path(name: &Bytes) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(name).into_owned())
+}
+
+/// Writes messages to a log file: the file magic cookie, then a LogEntry per message.
+///
+/// The result can be played back by ConnectionFile, or by the C++ vrpn_File_Connection.
+#[derive(Debug)]
+pub struct LogFileWriter {
+    file: BufWriter<fs::File>,
+}
+
+impl LogFileWriter {
+    /// Create (or truncate) a log file and write its cookie.
+    pub fn create(path: impl AsRef<Path>) -> Result<LogFileWriter> {
+        LogFileWriter::from_file(fs::File::create(path)?)
+    }
+
+    /// Create a log file and write its cookie, failing if the file already exists.
+    pub fn create_new(path: impl AsRef<Path>) -> Result<LogFileWriter> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        LogFileWriter::from_file(file)
+    }
+
+    fn from_file(file: fs::File) -> Result<LogFileWriter> {
+        let mut file = BufWriter::new(file);
+        let cookie = BytesMut::new().allocate_and_buffer(CookieData::from(FILE_MAGIC_DATA))?;
+        file.write_all(&cookie)?;
+        Ok(LogFileWriter { file })
+    }
+
+    pub fn write_message(&mut self, msg: &GenericMessage) -> Result<()> {
+        let entry = BytesMut::new().allocate_and_buffer(LogEntry(msg.clone()))?;
+        self.file.write_all(&entry)?;
+        Ok(())
+    }
+
+    /// Write out anything buffered. (This also happens when the writer is dropped.)
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// The log files, if any, for the messages an endpoint receives and sends.
+///
+/// Incoming messages are logged with the IDs the other end uses,
+/// outgoing messages with our own, so each file is consistent with the descriptions in it.
+#[derive(Debug, Default)]
+pub struct EndpointLog {
+    incoming: Option<LogFileWriter>,
+    outgoing: Option<LogFileWriter>,
+}
+
+impl EndpointLog {
+    pub fn new() -> EndpointLog {
+        EndpointLog::default()
+    }
+
+    /// Create the named log files.
+    ///
+    /// Like the C++ vrpn_Log, this refuses to touch files that already exist:
+    /// the names may have come from the other end of the connection.
+    pub fn open(names: &LogFileNames) -> Result<EndpointLog> {
+        let create = |name: &Option<Bytes>| -> Result<Option<LogFileWriter>> {
+            match name {
+                Some(name) => Ok(Some(LogFileWriter::create_new(log_path(name))?)),
+                None => Ok(None),
+            }
+        };
+        Ok(EndpointLog {
+            incoming: create(names.in_log())?,
+            outgoing: create(names.out_log())?,
+        })
+    }
+
+    pub fn log_mode(&self) -> LogMode {
+        let mut mode = LogMode::from(LogFlags::NONE);
+        if self.incoming.is_some() {
+            mode.set(LogFlags::INCOMING);
+        }
+        if self.outgoing.is_some() {
+            mode.set(LogFlags::OUTGOING);
+        }
+        mode
+    }
+
+    pub fn log_incoming(&mut self, msg: &GenericMessage) -> Result<()> {
+        match &mut self.incoming {
+            Some(log) => log.write_message(msg),
+            None => Ok(()),
+        }
+    }
+
+    pub fn log_outgoing(&mut self, msg: &GenericMessage) -> Result<()> {
+        match &mut self.outgoing {
+            Some(log) => log.write_message(msg),
+            None => Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -232,4 +479,85 @@ mod tests {
             LogMode::from(LogFlags::INCOMING_OUTGOING)
         );
     }
+
+    #[test]
+    fn with_suffix() {
+        let names = LogFileNames::from_names(Some(&b"in.vrpn"[..]), None).with_suffix("-1");
+        assert_eq!(names.in_log(), &Some(Bytes::from_static(b"in-1.vrpn")));
+        assert_eq!(names.out_log(), &None);
+
+        let suffixed = |name: &'static [u8]| {
+            LogFileNames::from_names(None, Some(name))
+                .with_suffix("-2")
+                .out_log()
+                .clone()
+                .unwrap()
+        };
+        assert_eq!(
+            suffixed(b"/tmp/logs.d/out"),
+            Bytes::from_static(b"/tmp/logs.d/out-2")
+        );
+        assert_eq!(suffixed(b"/tmp/.out"), Bytes::from_static(b"/tmp/.out-2"));
+        assert_eq!(
+            suffixed(b"out.tar.vrpn"),
+            Bytes::from_static(b"out.tar-2.vrpn")
+        );
+    }
+
+    #[test]
+    fn log_entry() {
+        use crate::time::{Microseconds, Seconds};
+        let entry = LogEntry(GenericMessage::from_header_and_body(
+            MessageHeader::new(
+                Some(TimeVal::new(
+                    Seconds(0x5beb_332e),
+                    Microseconds(0x000c_58b1),
+                )),
+                TypeId(2),
+                SenderId(1),
+            ),
+            GenericBody::new(Bytes::from(&b"abc"[..])),
+        ));
+        let buf = BytesMut::new()
+            .allocate_and_buffer(entry.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(
+            &buf[..],
+            &hex!(
+                "00 00 00 02 00 00 00 01 5b eb 33 2e 00 0c 58 b1 00 00 00 03 00 00 00 00 61 62 63"
+            )[..]
+        );
+        assert_eq!(LogEntry::peek_body_len(&buf).unwrap(), Some(3));
+        let mut buf = buf.freeze();
+        assert_eq!(LogEntry::unbuffer_ref(&mut buf).unwrap(), entry);
+        assert_eq!(buf.len(), 0);
+
+        let mut short = Bytes::from(&b"\0\0\0\x02"[..]);
+        assert!(LogEntry::unbuffer_ref(&mut short).is_err());
+    }
+
+    #[test]
+    fn log_file_writer() {
+        use std::io::Read;
+        let path = std::env::temp_dir().join(format!("vrpn-rs-writer-{}.vrpn", std::process::id()));
+        let msg = GenericMessage::from_header_and_body(
+            MessageHeader::new(None, TypeId(2), SenderId(1)),
+            GenericBody::new(Bytes::from(&b"abc"[..])),
+        );
+        {
+            let mut writer = LogFileWriter::create(&path).unwrap();
+            writer.write_message(&msg).unwrap();
+        }
+        let mut contents = Vec::new();
+        fs::File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .unwrap();
+        let _ = fs::remove_file(&path);
+
+        let cookie_len = CookieData::constant_buffer_size();
+        assert_eq!(&contents[..16], &b"vrpn: ver. 04.00"[..]);
+        let mut entry = Bytes::from(&contents[cookie_len..]);
+        assert_eq!(LogEntry::unbuffer_ref(&mut entry).unwrap(), LogEntry(msg));
+        assert_eq!(entry.len(), 0);
+    }
 }
@@ -2,15 +2,15 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use chrono::{prelude::*, Duration};
 use crate::{
     handler::{HandlerCode, HandlerHandle, TypedBodylessHandler},
     Connection, EmptyMessage, LocalId, Message, MessageHeader, MessageTypeIdentifier, Result,
-    SenderId, SenderName, ServiceFlags, StaticTypeName, TypeId, TypeSafeId, TypedMessageBody,
+    SenderId, SenderName, ServiceFlags, StaticTypeName, TypeId, TypedMessageBody,
 };
 use std::{
     fmt,
     sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
 };
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -41,8 +41,39 @@ impl TypedMessageBody for Pong {
         MessageTypeIdentifier::UserMessageName(PONG_MESSAGE);
 }
 
+/// How often a ping `Client` pings, and how long it waits for an answer.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Config {
+    /// Time between pings, and between re-sent pings while waiting for an answer.
+    pub interval: Duration,
+    /// How long without an answer before the server is considered flatlined.
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    /// The same timing as the C++ `vrpn_BaseClass`: every second, flatlined after ten.
+    fn default() -> Config {
+        Config {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A change in the health of the server, as seen by a ping `Client`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PingEvent {
+    /// No answer to our pings for at least the configured timeout.
+    Flatlined { radio_silence: Duration },
+    /// An answer at last, after having flatlined.
+    Recovered { round_trip: Duration },
+}
+
+type Listeners = Mutex<Vec<Box<dyn FnMut(PingEvent) + Send>>>;
+
 struct PongHandler {
     inner: Weak<Mutex<ClientInner>>,
+    listeners: Weak<Listeners>,
 }
 
 impl fmt::Debug for PongHandler {
@@ -54,69 +85,124 @@ impl fmt::Debug for PongHandler {
 impl TypedBodylessHandler for PongHandler {
     type Item = Pong;
     fn handle_typed_bodyless(&mut self, _header: &MessageHeader) -> Result<HandlerCode> {
-        match self.inner.upgrade() {
-            Some(inner) => {
-                let mut inner = inner.lock()?;
-                inner.unanswered_ping = None;
-                inner.last_warning = None;
-                if inner.flatlined {
-                    eprintln!("Remote host started responding again");
-                    inner.flatlined = false;
-                }
-                Ok(HandlerCode::ContinueProcessing)
-            }
-
-            // If we get here, then the inner has gone away
-            None => Ok(HandlerCode::RemoveThisHandler),
+        let (inner, listeners) = match (self.inner.upgrade(), self.listeners.upgrade()) {
+            (Some(inner), Some(listeners)) => (inner, listeners),
+            // If we get here, then the client has gone away
+            _ => return Ok(HandlerCode::RemoveThisHandler),
+        };
+        let event = inner.lock()?.handle_pong(Instant::now());
+        if let Some(event) = event {
+            notify_listeners(&listeners, event)?;
         }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+fn notify_listeners(listeners: &Listeners, event: PingEvent) -> Result<()> {
+    match event {
+        PingEvent::Flatlined { radio_silence } => eprintln!(
+            "No response from the remote host in {:?}: it seems to be gone",
+            radio_silence
+        ),
+        PingEvent::Recovered { .. } => eprintln!("Remote host started responding again"),
+    }
+    for listener in listeners.lock()?.iter_mut() {
+        listener(event);
     }
+    Ok(())
 }
 
+/// Sends pings to a server's `ping::Server` and watches for answers,
+/// to tell whether the server (and the connection to it) is still alive.
+///
+/// Something must call `check_ping_cycle` regularly: `async_io::ping::Client` does.
 pub struct Client<T: Connection + 'static> {
     connection: Arc<T>,
     inner: Arc<Mutex<ClientInner>>,
+    listeners: Arc<Listeners>,
     ping_type: LocalId<TypeId>,
     sender: LocalId<SenderId>,
 }
 
 struct ClientInner {
+    config: Config,
     /// The time of the first unanswered ping.
-    unanswered_ping: Option<DateTime<Utc>>,
-    /// The time of the last warning message and unanswered ping.
-    last_warning: Option<DateTime<Utc>>,
+    unanswered_ping: Option<Instant>,
+    /// The time we last sent a ping.
+    last_ping: Option<Instant>,
+    /// The most recently measured round-trip time.
+    round_trip: Option<Duration>,
     /// whether the server seems disconnected or unresponsive
     flatlined: bool,
 }
 
 impl ClientInner {
-    fn new() -> Arc<Mutex<ClientInner>> {
+    fn new(config: Config) -> Arc<Mutex<ClientInner>> {
         Arc::new(Mutex::new(ClientInner {
+            config,
             unanswered_ping: None,
-            last_warning: None,
+            last_ping: None,
+            round_trip: None,
             flatlined: false,
         }))
     }
+
+    fn ping_due(&self, now: Instant) -> bool {
+        // A little early is fine: a timer firing every interval may well fire
+        // a hair less than an interval after the last ping went out.
+        let due_after = self.config.interval * 9 / 10;
+        self.last_ping
+            .is_none_or(|last| now.duration_since(last) >= due_after)
+    }
+
+    fn handle_pong(&mut self, now: Instant) -> Option<PingEvent> {
+        // A pong when we aren't waiting for one (a second answer to a re-sent ping, say)
+        // doesn't tell us anything.
+        self.unanswered_ping.take()?;
+        // Measured from the latest ping: if it answers an earlier one, this is an underestimate.
+        let round_trip = now.duration_since(self.last_ping?);
+        self.round_trip = Some(round_trip);
+        if self.flatlined {
+            self.flatlined = false;
+            Some(PingEvent::Recovered { round_trip })
+        } else {
+            None
+        }
+    }
 }
+
 impl<T: Connection + 'static> Client<T> {
     pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Client<T>> {
+        Client::with_config(sender, connection, Config::default())
+    }
+
+    pub fn with_config(
+        sender: LocalId<SenderId>,
+        connection: Arc<T>,
+        config: Config,
+    ) -> Result<Client<T>> {
         let ping_type = connection.register_type(PING_MESSAGE)?;
-        let inner = ClientInner::new();
+        let inner = ClientInner::new(config);
+        let listeners: Arc<Listeners> = Arc::new(Mutex::new(Vec::new()));
 
         let _ = connection.add_typed_handler(
             Box::new(PongHandler {
                 inner: Arc::downgrade(&inner),
+                listeners: Arc::downgrade(&listeners),
             }),
             Some(sender),
         )?;
         let client = Client {
             connection,
             inner,
+            listeners,
             ping_type,
-            sender: sender,
+            sender,
         };
         client.initiate_ping_cycle()?;
         Ok(client)
     }
+
     pub fn new_from_name(
         sender: impl Into<SenderName> + Clone,
         connection: Arc<T>,
@@ -125,40 +211,74 @@ impl<T: Connection + 'static> Client<T> {
         Self::new(sender_id, connection)
     }
 
+    /// Call the given function on each `PingEvent`.
+    ///
+    /// It is called while the connection is being polled, so should not block.
+    pub fn add_event_listener(
+        &self,
+        listener: impl FnMut(PingEvent) + Send + 'static,
+    ) -> Result<()> {
+        self.listeners.lock()?.push(Box::new(listener));
+        Ok(())
+    }
+
+    /// Send a ping now, and start waiting for the answer if we weren't already.
     pub fn initiate_ping_cycle(&self) -> Result<()> {
         {
             let mut inner = self.inner.lock()?;
-            inner.unanswered_ping = Some(Utc::now());
+            let now = Instant::now();
+            inner.unanswered_ping.get_or_insert(now);
+            inner.last_ping = Some(now);
         }
         self.send_ping()
     }
 
-    /// Checks to see if we're due for another ping.
+    /// Sends a ping if we're due for another, and checks whether the server has flatlined.
     ///
     /// Returns the duration since the first unanswered ping,
     /// or None if there are no unanswered pings.
     pub fn check_ping_cycle(&self) -> Result<Option<Duration>> {
-        let mut inner = self.inner.lock()?;
-        if let (Some(unanswered), Some(last_warning)) =
-            (inner.unanswered_ping, &mut inner.last_warning)
-        {
-            let now = Utc::now();
-            let radio_silence = now.signed_duration_since(unanswered);
-            if now.signed_duration_since(*last_warning) > Duration::seconds(1) {
-                *last_warning = now;
-                if radio_silence > Duration::seconds(10) {
+        let now = Instant::now();
+        let (radio_silence, event) = {
+            let mut inner = self.inner.lock()?;
+            let radio_silence = inner
+                .unanswered_ping
+                .map(|unanswered| now.duration_since(unanswered));
+            if !inner.ping_due(now) {
+                return Ok(radio_silence);
+            }
+            inner.last_ping = Some(now);
+            inner.unanswered_ping.get_or_insert(now);
+            let event = match radio_silence {
+                Some(radio_silence)
+                    if radio_silence >= inner.config.timeout && !inner.flatlined =>
+                {
                     inner.flatlined = true;
+                    Some(PingEvent::Flatlined { radio_silence })
                 }
-                self.send_ping()?;
-            }
-            Ok(Some(radio_silence))
-        } else {
-            Ok(None)
+                _ => None,
+            };
+            (radio_silence, event)
+        };
+        self.send_ping()?;
+        if let Some(event) = event {
+            notify_listeners(&self.listeners, event)?;
         }
+        Ok(radio_silence)
+    }
+
+    /// The most recently measured round-trip time, if any pings have been answered.
+    pub fn round_trip_time(&self) -> Result<Option<Duration>> {
+        Ok(self.inner.lock()?.round_trip)
+    }
+
+    /// Whether the server has stopped answering pings.
+    pub fn is_flatlined(&self) -> Result<bool> {
+        Ok(self.inner.lock()?.flatlined)
     }
 
     fn send_ping(&self) -> Result<()> {
-        let msg = Message::new(None, self.ping_type, self.sender, Pong::default());
+        let msg = Message::new(None, self.ping_type, self.sender, Ping);
         self.connection
             .pack_message(msg, ServiceFlags::RELIABLE.into())?;
         Ok(())
@@ -172,14 +292,14 @@ struct PingHandler<T: Connection> {
     sender: LocalId<SenderId>,
 }
 
-impl<T: Connection + Send> TypedBodylessHandler for PingHandler<T> {
+impl<T: Connection + 'static> TypedBodylessHandler for PingHandler<T> {
     type Item = Ping;
     fn handle_typed_bodyless(&mut self, _header: &MessageHeader) -> Result<HandlerCode> {
         // TODO use sender from header?
         match self.connection.upgrade() {
             Some(connection) => {
-                let msg = Message::new(None, self.pong_type, self.sender, Pong::default());
-                connection.pack_message(msg, ServiceFlags::RELIABLE.into())?;
+                let msg = Message::new(None, self.pong_type, self.sender, Pong);
+                connection.pack_message_after_dispatch(msg, ServiceFlags::RELIABLE.into())?;
                 Ok(HandlerCode::ContinueProcessing)
             }
             None => Ok(HandlerCode::RemoveThisHandler),
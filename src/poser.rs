@@ -0,0 +1,615 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use cgmath::InnerSpace;
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    Buffer, Connection, ConstantBufferSize, EmptyResult, LocalId, Message, MessageTypeIdentifier,
+    Quat, Result, SenderId, SenderName, StaticTypeName, TypedMessageBody, Unbuffer, Vec3,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Request to move a poser to an absolute position and orientation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoseRequest {
+    pub pos: Vec3,
+    pub quat: Quat,
+}
+
+impl TypedMessageBody for PoseRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Poser Request_Pos_Quat"));
+}
+
+impl ConstantBufferSize for PoseRequest {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size()
+    }
+}
+
+impl Buffer for PoseRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.pos.buffer_ref(buf)?;
+        self.quat.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for PoseRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let pos = Vec3::unbuffer_ref(buf)?;
+        let quat = Quat::unbuffer_ref(buf)?;
+        Ok(PoseRequest { pos, quat })
+    }
+}
+
+/// Request to move a poser by an offset from its current position and orientation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelativePoseRequest {
+    pub pos: Vec3,
+    pub quat: Quat,
+}
+
+impl TypedMessageBody for RelativePoseRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Poser Request_Relative_Pos_Quat"),
+    );
+}
+
+impl ConstantBufferSize for RelativePoseRequest {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size()
+    }
+}
+
+impl Buffer for RelativePoseRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.pos.buffer_ref(buf)?;
+        self.quat.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for RelativePoseRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let pos = Vec3::unbuffer_ref(buf)?;
+        let quat = Quat::unbuffer_ref(buf)?;
+        Ok(RelativePoseRequest { pos, quat })
+    }
+}
+
+/// Request to move a poser with an absolute linear and angular velocity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VelocityRequest {
+    pub vel: Vec3,
+    pub vel_quat: Quat,
+    pub vel_quat_dt: f64,
+}
+
+impl TypedMessageBody for VelocityRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Poser Request_Velocity"));
+}
+
+impl ConstantBufferSize for VelocityRequest {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size() + f64::constant_buffer_size()
+    }
+}
+
+impl Buffer for VelocityRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.vel.buffer_ref(buf)?;
+        self.vel_quat.buffer_ref(buf)?;
+        self.vel_quat_dt.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for VelocityRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let vel = Vec3::unbuffer_ref(buf)?;
+        let vel_quat = Quat::unbuffer_ref(buf)?;
+        let vel_quat_dt = f64::unbuffer_ref(buf)?;
+        Ok(VelocityRequest {
+            vel,
+            vel_quat,
+            vel_quat_dt,
+        })
+    }
+}
+
+/// Request to change a poser's velocity by an offset from its current velocity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelativeVelocityRequest {
+    pub vel: Vec3,
+    pub vel_quat: Quat,
+    pub vel_quat_dt: f64,
+}
+
+impl TypedMessageBody for RelativeVelocityRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Poser Request_Relative_Velocity"),
+    );
+}
+
+impl ConstantBufferSize for RelativeVelocityRequest {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size() + f64::constant_buffer_size()
+    }
+}
+
+impl Buffer for RelativeVelocityRequest {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.vel.buffer_ref(buf)?;
+        self.vel_quat.buffer_ref(buf)?;
+        self.vel_quat_dt.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for RelativeVelocityRequest {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let vel = Vec3::unbuffer_ref(buf)?;
+        let vel_quat = Quat::unbuffer_ref(buf)?;
+        let vel_quat_dt = f64::unbuffer_ref(buf)?;
+        Ok(RelativeVelocityRequest {
+            vel,
+            vel_quat,
+            vel_quat_dt,
+        })
+    }
+}
+
+/// Implemented by a device that can be driven by a poser Server.
+///
+/// The Server takes care of relative requests and of clamping to its limits,
+/// so the device only ever sees absolute, in-range values.
+pub trait PoserDevice: Send + 'static {
+    /// Move to the given position and orientation.
+    fn set_pose(&mut self, pos: Vec3, quat: Quat) -> Result<()>;
+
+    /// Move with the given linear velocity, and angular velocity
+    /// expressed as the rotation covered in `vel_quat_dt` seconds.
+    fn set_velocity(&mut self, vel: Vec3, vel_quat: Quat, vel_quat_dt: f64) -> Result<()>;
+}
+
+/// Bounds a poser Server clamps requests to, per axis.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoserLimits {
+    pub pos_min: Vec3,
+    pub pos_max: Vec3,
+    pub vel_min: Vec3,
+    pub vel_max: Vec3,
+}
+
+impl Default for PoserLimits {
+    /// No clamping at all.
+    fn default() -> PoserLimits {
+        PoserLimits {
+            pos_min: Vec3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            pos_max: Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            vel_min: Vec3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            vel_max: Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+        }
+    }
+}
+
+fn clamp(v: Vec3, min: Vec3, max: Vec3) -> Vec3 {
+    Vec3::new(
+        v.x.max(min.x).min(max.x),
+        v.y.max(min.y).min(max.y),
+        v.z.max(min.z).min(max.z),
+    )
+}
+
+fn is_finite(v: Vec3) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+/// Whether a quaternion can be normalized into a rotation.
+fn is_usable_rotation(q: Quat) -> bool {
+    let norm2 = q.magnitude2();
+    q.s.is_finite() && is_finite(q.v) && norm2.is_finite() && norm2 > f64::EPSILON
+}
+
+/// The pose and velocity most recently commanded by a poser Server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoserState {
+    pub pos: Vec3,
+    pub quat: Quat,
+    pub vel: Vec3,
+    pub vel_quat: Quat,
+    pub vel_quat_dt: f64,
+}
+
+impl Default for PoserState {
+    fn default() -> PoserState {
+        PoserState {
+            pos: Vec3::new(0.0, 0.0, 0.0),
+            quat: Quat::from_sv(1.0, Vec3::new(0.0, 0.0, 0.0)),
+            vel: Vec3::new(0.0, 0.0, 0.0),
+            vel_quat: Quat::from_sv(1.0, Vec3::new(0.0, 0.0, 0.0)),
+            vel_quat_dt: 1.0,
+        }
+    }
+}
+
+struct ServerInner<D: PoserDevice> {
+    device: D,
+    limits: PoserLimits,
+    state: PoserState,
+}
+
+impl<D: PoserDevice> ServerInner<D> {
+    fn apply_pose(&mut self, pos: Vec3, quat: Quat) -> Result<()> {
+        if !is_finite(pos) || !is_usable_rotation(quat) {
+            eprintln!("Ignoring poser request for an invalid pose");
+            return Ok(());
+        }
+        let pos = clamp(pos, self.limits.pos_min, self.limits.pos_max);
+        let quat = quat.normalize();
+        self.device.set_pose(pos, quat)?;
+        self.state.pos = pos;
+        self.state.quat = quat;
+        Ok(())
+    }
+
+    fn apply_velocity(&mut self, vel: Vec3, vel_quat: Quat, vel_quat_dt: f64) -> Result<()> {
+        if !is_finite(vel) || !is_usable_rotation(vel_quat) || !vel_quat_dt.is_finite() {
+            eprintln!("Ignoring poser request for an invalid velocity");
+            return Ok(());
+        }
+        let vel = clamp(vel, self.limits.vel_min, self.limits.vel_max);
+        let vel_quat = vel_quat.normalize();
+        self.device.set_velocity(vel, vel_quat, vel_quat_dt)?;
+        self.state.vel = vel;
+        self.state.vel_quat = vel_quat;
+        self.state.vel_quat_dt = vel_quat_dt;
+        Ok(())
+    }
+}
+
+/// Implemented by the requests a poser Server accepts.
+trait PoserRequest: TypedMessageBody + Unbuffer + Send + Sync {
+    fn apply<D: PoserDevice>(&self, inner: &mut ServerInner<D>) -> Result<()>;
+}
+
+impl PoserRequest for PoseRequest {
+    fn apply<D: PoserDevice>(&self, inner: &mut ServerInner<D>) -> Result<()> {
+        inner.apply_pose(self.pos, self.quat)
+    }
+}
+
+impl PoserRequest for RelativePoseRequest {
+    fn apply<D: PoserDevice>(&self, inner: &mut ServerInner<D>) -> Result<()> {
+        let pos = inner.state.pos + self.pos;
+        let quat = self.quat * inner.state.quat;
+        inner.apply_pose(pos, quat)
+    }
+}
+
+impl PoserRequest for VelocityRequest {
+    fn apply<D: PoserDevice>(&self, inner: &mut ServerInner<D>) -> Result<()> {
+        inner.apply_velocity(self.vel, self.vel_quat, self.vel_quat_dt)
+    }
+}
+
+impl PoserRequest for RelativeVelocityRequest {
+    fn apply<D: PoserDevice>(&self, inner: &mut ServerInner<D>) -> Result<()> {
+        let vel = inner.state.vel + self.vel;
+        let vel_quat = self.vel_quat * inner.state.vel_quat;
+        inner.apply_velocity(vel, vel_quat, self.vel_quat_dt)
+    }
+}
+
+struct RequestHandler<D: PoserDevice, U: PoserRequest> {
+    inner: Weak<Mutex<ServerInner<D>>>,
+    phantom: PhantomData<U>,
+}
+
+impl<D: PoserDevice, U: PoserRequest> RequestHandler<D, U> {
+    fn new(inner: &Arc<Mutex<ServerInner<D>>>) -> Box<RequestHandler<D, U>> {
+        Box::new(RequestHandler {
+            inner: Arc::downgrade(inner),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<D: PoserDevice, U: PoserRequest> fmt::Debug for RequestHandler<D, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestHandler").finish()
+    }
+}
+
+impl<D: PoserDevice, U: PoserRequest> TypedHandler for RequestHandler<D, U> {
+    type Item = U;
+    fn handle_typed(&mut self, msg: &Message<U>) -> Result<HandlerCode> {
+        match self.inner.upgrade() {
+            Some(inner) => {
+                let mut inner = inner.lock()?;
+                msg.body.apply(&mut inner)?;
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Server has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Server-side helper for a poser: accepts pose and velocity requests for a device,
+/// resolves relative requests against the last commanded state, and clamps them
+/// to the configured limits before passing them on.
+pub struct Server<T: Connection + 'static, D: PoserDevice> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    inner: Arc<Mutex<ServerInner<D>>>,
+}
+
+impl<T: Connection + 'static, D: PoserDevice> Server<T, D> {
+    pub fn new(
+        sender: LocalId<SenderId>,
+        connection: Arc<T>,
+        device: D,
+        limits: PoserLimits,
+    ) -> Result<Server<T, D>> {
+        let inner = Arc::new(Mutex::new(ServerInner {
+            device,
+            limits,
+            state: PoserState::default(),
+        }));
+        let _ = connection
+            .add_typed_handler(RequestHandler::<D, PoseRequest>::new(&inner), Some(sender))?;
+        let _ = connection.add_typed_handler(
+            RequestHandler::<D, RelativePoseRequest>::new(&inner),
+            Some(sender),
+        )?;
+        let _ = connection.add_typed_handler(
+            RequestHandler::<D, VelocityRequest>::new(&inner),
+            Some(sender),
+        )?;
+        let _ = connection.add_typed_handler(
+            RequestHandler::<D, RelativeVelocityRequest>::new(&inner),
+            Some(sender),
+        )?;
+        Ok(Server {
+            connection,
+            sender,
+            inner,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+        device: D,
+        limits: PoserLimits,
+    ) -> Result<Server<T, D>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection, device, limits)
+    }
+
+    /// Access the connection this server is using.
+    pub fn connection(&self) -> &Arc<T> {
+        &self.connection
+    }
+
+    /// The local sender ID of the device.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Get the pose and velocity most recently passed to the device.
+    pub fn state(&self) -> Result<PoserState> {
+        Ok(self.inner.lock()?.state.clone())
+    }
+
+    /// Get the limits requests are clamped to.
+    pub fn limits(&self) -> Result<PoserLimits> {
+        Ok(self.inner.lock()?.limits.clone())
+    }
+
+    /// Change the limits future requests are clamped to.
+    pub fn set_limits(&self, limits: PoserLimits) -> Result<()> {
+        self.inner.lock()?.limits = limits;
+        Ok(())
+    }
+
+    /// Run a closure with access to the device.
+    pub fn with_device<R>(&self, f: impl FnOnce(&mut D) -> R) -> Result<R> {
+        Ok(f(&mut self.inner.lock()?.device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        prelude::*,
+        test_util::{dispatch, unconnected},
+        StaticSenderName,
+    };
+
+    #[test]
+    fn pose_request() {
+        let request = PoseRequest {
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        };
+        assert_eq!(request.buffer_size(), 56);
+        let expected = hex!(
+            "3f f0 00 00 00 00 00 00 40 00 00 00 00 00 00 00 40 08 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             3f f0 00 00 00 00 00 00"
+        );
+        let buf = BytesMut::new()
+            .allocate_and_buffer(request.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(PoseRequest::unbuffer_ref(&mut buf).unwrap(), request);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn velocity_request() {
+        let request = RelativeVelocityRequest {
+            vel: Vec3::new(0.5, 0.0, 0.0),
+            vel_quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+            vel_quat_dt: 0.25,
+        };
+        assert_eq!(request.buffer_size(), 64);
+        let mut buf = BytesMut::new()
+            .allocate_and_buffer(request.clone())
+            .expect("Buffering needs to succeed")
+            .freeze();
+        assert_eq!(&buf[56..], &hex!("3f d0 00 00 00 00 00 00")[..]);
+        assert_eq!(
+            RelativeVelocityRequest::unbuffer_ref(&mut buf).unwrap(),
+            request
+        );
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[derive(Debug, Default)]
+    struct FakeDevice {
+        poses: Vec<Vec3>,
+        velocities: Vec<Vec3>,
+    }
+
+    impl PoserDevice for FakeDevice {
+        fn set_pose(&mut self, pos: Vec3, _quat: Quat) -> Result<()> {
+            self.poses.push(pos);
+            Ok(())
+        }
+        fn set_velocity(&mut self, vel: Vec3, _vel_quat: Quat, _vel_quat_dt: f64) -> Result<()> {
+            self.velocities.push(vel);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn server_clamps() {
+        let conn = unconnected();
+        let limits = PoserLimits {
+            pos_min: Vec3::new(-1.0, -1.0, 0.0),
+            pos_max: Vec3::new(1.0, 1.0, 2.0),
+            vel_min: Vec3::new(-0.5, -0.5, -0.5),
+            vel_max: Vec3::new(0.5, 0.5, 0.5),
+        };
+        let server = Server::new_from_name(
+            StaticSenderName(b"Poser0"),
+            Arc::clone(&conn),
+            FakeDevice::default(),
+            limits,
+        )
+        .expect("should be able to create server");
+        let identity = Quat::from_sv(1.0, Vec3::new(0.0, 0.0, 0.0));
+
+        dispatch(
+            &conn,
+            server.sender(),
+            PoseRequest {
+                pos: Vec3::new(0.5, 3.0, -1.0),
+                quat: identity,
+            },
+        );
+        assert_eq!(server.state().unwrap().pos, Vec3::new(0.5, 1.0, 0.0));
+
+        dispatch(
+            &conn,
+            server.sender(),
+            RelativePoseRequest {
+                pos: Vec3::new(1.0, -0.5, 0.5),
+                quat: identity,
+            },
+        );
+        assert_eq!(server.state().unwrap().pos, Vec3::new(1.0, 0.5, 0.5));
+
+        dispatch(
+            &conn,
+            server.sender(),
+            VelocityRequest {
+                vel: Vec3::new(0.25, 2.0, 0.0),
+                vel_quat: identity,
+                vel_quat_dt: 1.0,
+            },
+        );
+        dispatch(
+            &conn,
+            server.sender(),
+            RelativeVelocityRequest {
+                vel: Vec3::new(0.5, 0.0, -1.0),
+                vel_quat: identity,
+                vel_quat_dt: 1.0,
+            },
+        );
+        server
+            .with_device(|device| {
+                assert_eq!(
+                    device.poses,
+                    vec![Vec3::new(0.5, 1.0, 0.0), Vec3::new(1.0, 0.5, 0.5)]
+                );
+                assert_eq!(
+                    device.velocities,
+                    vec![Vec3::new(0.25, 0.5, 0.0), Vec3::new(0.5, 0.5, -0.5)]
+                );
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn server_ignores_invalid_requests() {
+        let conn = unconnected();
+        let server = Server::new_from_name(
+            StaticSenderName(b"Poser0"),
+            Arc::clone(&conn),
+            FakeDevice::default(),
+            PoserLimits::default(),
+        )
+        .expect("should be able to create server");
+        let identity = Quat::from_sv(1.0, Vec3::new(0.0, 0.0, 0.0));
+
+        dispatch(
+            &conn,
+            server.sender(),
+            PoseRequest {
+                pos: Vec3::new(0.0, 0.0, 0.0),
+                quat: Quat::from_sv(0.0, Vec3::new(0.0, 0.0, 0.0)),
+            },
+        );
+        dispatch(
+            &conn,
+            server.sender(),
+            PoseRequest {
+                pos: Vec3::new(f64::NAN, 0.0, 0.0),
+                quat: identity,
+            },
+        );
+        dispatch(
+            &conn,
+            server.sender(),
+            VelocityRequest {
+                vel: Vec3::new(0.0, 0.0, 0.0),
+                vel_quat: Quat::from_sv(f64::INFINITY, Vec3::new(0.0, 0.0, 0.0)),
+                vel_quat_dt: 1.0,
+            },
+        );
+        assert_eq!(server.state().unwrap(), PoserState::default());
+        server
+            .with_device(|device| {
+                assert!(device.poses.is_empty());
+                assert!(device.velocities.is_empty());
+            })
+            .unwrap();
+    }
+}
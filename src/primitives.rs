@@ -27,6 +27,7 @@ macro_rules! buffer_primitive {
     };
 }
 
+buffer_primitive!(u8, put_u8, get_u8);
 buffer_primitive!(i8, put_i8, get_i8);
 buffer_primitive!(i16, put_i16_be, get_i16_be);
 buffer_primitive!(u16, put_u16_be, get_u16_be);
@@ -0,0 +1,35 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Helpers for testing device modules' handlers without a network connection.
+
+use crate::{
+    async_io::ConnectionIp, Buffer, Connection, LocalId, Message, MessageTypeIdentifier, SenderId,
+    TypedMessageBody,
+};
+use std::sync::Arc;
+
+/// A connection with nothing connected to it: anything packed on it goes nowhere.
+pub(crate) fn unconnected() -> Arc<ConnectionIp> {
+    ConnectionIp::new_unconnected()
+}
+
+/// Call the connection's handlers for a message from `sender`, as if it had just arrived.
+pub(crate) fn dispatch<T: TypedMessageBody + Buffer>(
+    conn: &ConnectionIp,
+    sender: LocalId<SenderId>,
+    body: T,
+) {
+    let msg_type = match T::MESSAGE_IDENTIFIER {
+        MessageTypeIdentifier::UserMessageName(name) => conn.register_type(name).unwrap(),
+        MessageTypeIdentifier::SystemMessageId(_) => unreachable!(),
+    };
+    let msg = Message::new(None, msg_type, sender, body);
+    conn.dispatcher()
+        .lock()
+        .unwrap()
+        .call(&msg.try_into_generic().unwrap())
+        .unwrap();
+    conn.pack_deferred_messages().unwrap();
+}
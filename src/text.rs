@@ -0,0 +1,285 @@
+// Copyright 2018, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use bytes::{BufMut, Bytes};
+use crate::{
+    handler::{HandlerCode, TypedHandler},
+    Buffer, BufferSize, BytesRequired, Connection, ConstantBufferSize, EmptyResult, Error, LocalId,
+    Message, MessageTypeIdentifier, Result, SenderId, SenderName, ServiceFlags, StaticTypeName,
+    TypedMessageBody, Unbuffer,
+};
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+};
+
+/// Maximum length of a text message, including the null terminator, matching vrpn_MAX_TEXT_LEN.
+pub const MAX_TEXT_LEN: usize = 1024;
+
+/// How serious a text message is.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum TextSeverity {
+    #[default]
+    Normal,
+    Warning,
+    Error,
+}
+
+impl TextSeverity {
+    fn to_raw(self) -> u32 {
+        match self {
+            TextSeverity::Normal => 0,
+            TextSeverity::Warning => 1,
+            TextSeverity::Error => 2,
+        }
+    }
+
+    fn from_raw(v: u32) -> Result<TextSeverity> {
+        match v {
+            0 => Ok(TextSeverity::Normal),
+            1 => Ok(TextSeverity::Warning),
+            2 => Ok(TextSeverity::Error),
+            _ => Err(Error::OtherMessage(format!(
+                "unrecognized text message severity {}",
+                v
+            ))),
+        }
+    }
+}
+
+impl Display for TextSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextSeverity::Normal => write!(f, "normal"),
+            TextSeverity::Warning => write!(f, "warning"),
+            TextSeverity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A text message sent by a device, usually to report a warning or error.
+///
+/// The timestamp of the message is the one in the message header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextMessage {
+    pub severity: TextSeverity,
+    /// Device-defined level of detail, 0 being the most important.
+    pub level: u32,
+    pub message: String,
+}
+
+impl TextMessage {
+    pub fn new(severity: TextSeverity, level: u32, message: impl Into<String>) -> TextMessage {
+        TextMessage {
+            severity,
+            level,
+            message: message.into(),
+        }
+    }
+}
+
+impl TypedMessageBody for TextMessage {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Base text_message"));
+}
+
+impl BufferSize for TextMessage {
+    fn buffer_size(&self) -> usize {
+        2 * u32::constant_buffer_size() + self.message.len() + 1
+    }
+}
+
+impl Buffer for TextMessage {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        if self.message.len() + 1 > MAX_TEXT_LEN {
+            return Err(Error::OtherMessage(format!(
+                "text message is {} bytes long, but the maximum is {}",
+                self.message.len(),
+                MAX_TEXT_LEN - 1
+            )));
+        }
+        if self.message.contains('\0') {
+            return Err(Error::OtherMessage(String::from(
+                "text message contains an embedded null character",
+            )));
+        }
+        if buf.remaining_mut() < self.buffer_size() {
+            return Err(Error::OutOfBuffer);
+        }
+        self.severity.to_raw().buffer_ref(buf)?;
+        self.level.buffer_ref(buf)?;
+        buf.put(self.message.as_bytes());
+        buf.put_u8(0);
+        Ok(())
+    }
+}
+
+impl Unbuffer for TextMessage {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let severity = TextSeverity::from_raw(u32::unbuffer_ref(buf)?)?;
+        let level = u32::unbuffer_ref(buf)?;
+        match buf.iter().take(MAX_TEXT_LEN).position(|b| *b == 0) {
+            Some(len) => {
+                let message = String::from_utf8_lossy(&buf.split_to(len)).into_owned();
+                buf.advance(1);
+                Ok(TextMessage {
+                    severity,
+                    level,
+                    message,
+                })
+            }
+            None if buf.len() >= MAX_TEXT_LEN => Err(Error::OtherMessage(format!(
+                "text message is not null-terminated within {} bytes",
+                MAX_TEXT_LEN
+            ))),
+            None => Err(Error::NeedMoreData(BytesRequired::AtLeast(1))),
+        }
+    }
+}
+
+/// Sends text messages on behalf of a device, like the C++ vrpn_Text_Sender.
+pub struct Sender<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+}
+
+impl<T: Connection + 'static> Sender<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Sender<T> {
+        Sender { connection, sender }
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Sender<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Ok(Self::new(sender_id, connection))
+    }
+
+    /// The local sender ID the messages are sent from.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Send a text message, timestamped now.
+    pub fn send_message(
+        &self,
+        severity: TextSeverity,
+        level: u32,
+        message: impl Into<String>,
+    ) -> Result<()> {
+        self.connection.pack_message_body(
+            None,
+            self.sender,
+            TextMessage::new(severity, level, message),
+            ServiceFlags::RELIABLE.into(),
+        )
+    }
+
+    /// Send a message with normal severity and level 0.
+    pub fn info(&self, message: impl Into<String>) -> Result<()> {
+        self.send_message(TextSeverity::Normal, 0, message)
+    }
+
+    /// Send a message with warning severity and level 0.
+    pub fn warning(&self, message: impl Into<String>) -> Result<()> {
+        self.send_message(TextSeverity::Warning, 0, message)
+    }
+
+    /// Send a message with error severity and level 0.
+    pub fn error(&self, message: impl Into<String>) -> Result<()> {
+        self.send_message(TextSeverity::Error, 0, message)
+    }
+}
+
+/// Handler that prints received text messages to stderr, like the C++ vrpn_System_TextPrinter.
+///
+/// Register it with `add_typed_handler`, filtering to one sender or not.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextPrinter {
+    /// Messages less severe than this are not printed.
+    pub min_severity: TextSeverity,
+    /// Messages with a level greater than this are not printed.
+    pub max_level: Option<u32>,
+}
+
+impl TextPrinter {
+    pub fn new() -> TextPrinter {
+        Default::default()
+    }
+
+    /// Whether this printer would print the given message.
+    pub fn accepts(&self, msg: &TextMessage) -> bool {
+        let level_ok = match self.max_level {
+            Some(max) => msg.level <= max,
+            None => true,
+        };
+        msg.severity >= self.min_severity && level_ok
+    }
+}
+
+impl TypedHandler for TextPrinter {
+    type Item = TextMessage;
+    fn handle_typed(&mut self, msg: &Message<TextMessage>) -> Result<HandlerCode> {
+        if self.accepts(&msg.body) {
+            eprintln!(
+                "[{}.{:06}] {:?} {} (level {}): {}",
+                msg.header.time.seconds().0,
+                msg.header.time.microseconds().0,
+                msg.header.sender,
+                msg.body.severity,
+                msg.body.level,
+                msg.body.message
+            );
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::prelude::*;
+
+    #[test]
+    fn text_message() {
+        let text = TextMessage::new(TextSeverity::Warning, 3, "hi");
+        let expected = hex!("00 00 00 01 00 00 00 03 68 69 00");
+        assert_eq!(text.buffer_size(), expected.len());
+        let buf = BytesMut::new()
+            .allocate_and_buffer(text.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(TextMessage::unbuffer_ref(&mut buf).unwrap(), text);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn unterminated() {
+        let mut buf = Bytes::from(&hex!("00 00 00 02 00 00 00 00 68 69")[..]);
+        match TextMessage::unbuffer_ref(&mut buf) {
+            Err(Error::NeedMoreData(BytesRequired::AtLeast(1))) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        let text = TextMessage::new(TextSeverity::Error, 0, "x".repeat(MAX_TEXT_LEN));
+        assert!(BytesMut::new().allocate_and_buffer(text).is_err());
+    }
+
+    #[test]
+    fn printer_filter() {
+        let printer = TextPrinter {
+            min_severity: TextSeverity::Warning,
+            max_level: Some(1),
+        };
+        assert!(!printer.accepts(&TextMessage::new(TextSeverity::Normal, 0, "")));
+        assert!(printer.accepts(&TextMessage::new(TextSeverity::Error, 1, "")));
+        assert!(!printer.accepts(&TextMessage::new(TextSeverity::Error, 2, "")));
+    }
+}
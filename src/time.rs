@@ -4,7 +4,14 @@
 
 use bytes::{BufMut, Bytes};
 use crate::{error::*, prelude::*, Buffer, ConstantBufferSize, Unbuffer, WrappedConstantSize};
-use std::time::{Duration, SystemTime};
+use std::{
+    ops::{Add, Neg, Sub},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 #[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Debug, Hash)]
 pub struct Seconds(pub i32);
@@ -39,6 +46,18 @@ impl TimeVal {
     pub fn get_time_of_day() -> TimeVal {
         TimeVal::from(SystemTime::now())
     }
+
+    fn as_micros(&self) -> i64 {
+        i64::from(self.sec.0) * 1_000_000 + i64::from(self.usec.0)
+    }
+
+    /// Normalized, so the microseconds part is always in [0, 1000000).
+    fn from_micros(micros: i64) -> TimeVal {
+        TimeVal::new(
+            Seconds(micros.div_euclid(1_000_000) as i32),
+            Microseconds(micros.rem_euclid(1_000_000) as i32),
+        )
+    }
 }
 
 impl Default for TimeVal {
@@ -107,3 +126,83 @@ impl Unbuffer for TimeVal {
             .and_then(|(usec, sec)| Ok(TimeVal::new(sec, usec)))
     }
 }
+
+/// A signed difference between two times, in microseconds.
+///
+/// Used to move times from one clock to another:
+/// a remote time plus the remote clock's offset to ours is the same moment on our clock.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Debug, Default, Hash)]
+pub struct TimeOffset(pub i64);
+
+impl Add<TimeOffset> for TimeVal {
+    type Output = TimeVal;
+    fn add(self, offset: TimeOffset) -> TimeVal {
+        TimeVal::from_micros(self.as_micros() + offset.0)
+    }
+}
+
+impl Sub for TimeVal {
+    type Output = TimeOffset;
+    fn sub(self, other: TimeVal) -> TimeOffset {
+        TimeOffset(self.as_micros() - other.as_micros())
+    }
+}
+
+impl Neg for TimeOffset {
+    type Output = TimeOffset;
+    fn neg(self) -> TimeOffset {
+        TimeOffset(-self.0)
+    }
+}
+
+/// A shared, changeable offset to apply to times: for instance, to the timestamps
+/// of incoming messages, to put them in our clock's time base.
+///
+/// Clones share the same offset. It starts at zero, leaving times unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct TimeAdjustment(Arc<AtomicI64>);
+
+impl TimeAdjustment {
+    pub fn offset(&self) -> TimeOffset {
+        TimeOffset(self.0.load(Ordering::SeqCst))
+    }
+
+    pub fn set_offset(&self, offset: TimeOffset) {
+        self.0.store(offset.0, Ordering::SeqCst);
+    }
+
+    pub fn apply(&self, time: TimeVal) -> TimeVal {
+        time + self.offset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets() {
+        let time = TimeVal::new(Seconds(10), Microseconds(250_000));
+        let later = TimeVal::new(Seconds(12), Microseconds(100_000));
+        assert_eq!(later - time, TimeOffset(1_850_000));
+        assert_eq!(time - later, TimeOffset(-1_850_000));
+        assert_eq!(time + (later - time), later);
+        assert_eq!(later + -(later - time), time);
+        assert_eq!(
+            time + TimeOffset(-300_000),
+            TimeVal::new(Seconds(9), Microseconds(950_000))
+        );
+    }
+
+    #[test]
+    fn adjustment() {
+        let adjustment = TimeAdjustment::default();
+        let time = TimeVal::new(Seconds(10), Microseconds(0));
+        assert_eq!(adjustment.apply(time), time);
+        adjustment.clone().set_offset(TimeOffset(-1));
+        assert_eq!(
+            adjustment.apply(time),
+            TimeVal::new(Seconds(9), Microseconds(999_999))
+        );
+    }
+}
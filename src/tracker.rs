@@ -4,9 +4,17 @@
 
 use bytes::{BufMut, Bytes};
 use crate::{
-    Buffer, ConstantBufferSize, EmptyResult, MessageTypeIdentifier, Quat, Result, Sensor,
+    handler::{HandlerCode, TypedHandler},
+    Buffer, Connection, ConstantBufferSize, EmptyMessage, EmptyResult, LocalId, Message,
+    MessageTypeIdentifier, Quat, Result, SenderId, SenderName, Sensor, ServiceFlags,
     StaticTypeName, TypedMessageBody, Unbuffer, Vec3,
 };
+use std::{
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, Weak},
+};
 
 /// Position and orientation for trackers.
 #[derive(Clone, Debug, PartialEq)]
@@ -64,6 +72,43 @@ impl TypedMessageBody for VelocityReport {
         MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Tracker Velocity"));
 }
 
+impl ConstantBufferSize for VelocityReport {
+    fn constant_buffer_size() -> usize {
+        Sensor::constant_buffer_size() * 2
+            + Vec3::constant_buffer_size()
+            + Quat::constant_buffer_size()
+            + f64::constant_buffer_size()
+    }
+}
+
+impl Buffer for VelocityReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.sensor.buffer_ref(buf)?;
+        // padding
+        self.sensor.buffer_ref(buf)?;
+        self.vel.buffer_ref(buf)?;
+        self.vel_quat.buffer_ref(buf)?;
+        self.vel_quat_dt.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for VelocityReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let sensor = Sensor::unbuffer_ref(buf)?;
+        let _ = Sensor::unbuffer_ref(buf)?;
+        let vel = Vec3::unbuffer_ref(buf)?;
+        let vel_quat = Quat::unbuffer_ref(buf)?;
+        let vel_quat_dt = f64::unbuffer_ref(buf)?;
+        Ok(VelocityReport {
+            sensor,
+            vel,
+            vel_quat,
+            vel_quat_dt,
+        })
+    }
+}
+
 /// Linear and angular acceleration for trackers.
 #[derive(Clone, Debug, PartialEq)]
 pub struct AccelReport {
@@ -77,3 +122,494 @@ impl TypedMessageBody for AccelReport {
     const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
         MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Tracker Acceleration"));
 }
+
+impl ConstantBufferSize for AccelReport {
+    fn constant_buffer_size() -> usize {
+        Sensor::constant_buffer_size() * 2
+            + Vec3::constant_buffer_size()
+            + Quat::constant_buffer_size()
+            + f64::constant_buffer_size()
+    }
+}
+
+impl Buffer for AccelReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.sensor.buffer_ref(buf)?;
+        // padding
+        self.sensor.buffer_ref(buf)?;
+        self.acc.buffer_ref(buf)?;
+        self.acc_quat.buffer_ref(buf)?;
+        self.acc_quat_dt.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for AccelReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let sensor = Sensor::unbuffer_ref(buf)?;
+        let _ = Sensor::unbuffer_ref(buf)?;
+        let acc = Vec3::unbuffer_ref(buf)?;
+        let acc_quat = Quat::unbuffer_ref(buf)?;
+        let acc_quat_dt = f64::unbuffer_ref(buf)?;
+        Ok(AccelReport {
+            sensor,
+            acc,
+            acc_quat,
+            acc_quat_dt,
+        })
+    }
+}
+
+/// Transform from the tracker's coordinate system to the room's coordinate system.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackerToRoomReport {
+    pub pos: Vec3,
+    pub quat: Quat,
+}
+
+impl TypedMessageBody for TrackerToRoomReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Tracker To_Room"));
+}
+
+impl ConstantBufferSize for TrackerToRoomReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size()
+    }
+}
+
+impl Buffer for TrackerToRoomReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.pos.buffer_ref(buf)?;
+        self.quat.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for TrackerToRoomReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let pos = Vec3::unbuffer_ref(buf)?;
+        let quat = Quat::unbuffer_ref(buf)?;
+        Ok(TrackerToRoomReport { pos, quat })
+    }
+}
+
+/// Transform from a tracker sensor's "unit" coordinate system to the sensor's coordinate system.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnitToSensorReport {
+    pub sensor: Sensor,
+    pub pos: Vec3,
+    pub quat: Quat,
+}
+
+impl TypedMessageBody for UnitToSensorReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Tracker Unit_To_Sensor"));
+}
+
+impl ConstantBufferSize for UnitToSensorReport {
+    fn constant_buffer_size() -> usize {
+        Sensor::constant_buffer_size() * 2
+            + Vec3::constant_buffer_size()
+            + Quat::constant_buffer_size()
+    }
+}
+
+impl Buffer for UnitToSensorReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.sensor.buffer_ref(buf)?;
+        // padding: unlike the other reports, the C++ code sends zero here.
+        0_i32.buffer_ref(buf)?;
+        self.pos.buffer_ref(buf)?;
+        self.quat.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for UnitToSensorReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let sensor = Sensor::unbuffer_ref(buf)?;
+        let _ = i32::unbuffer_ref(buf)?;
+        let pos = Vec3::unbuffer_ref(buf)?;
+        let quat = Quat::unbuffer_ref(buf)?;
+        Ok(UnitToSensorReport { sensor, pos, quat })
+    }
+}
+
+/// Bounding box of the volume a tracker can report in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkspaceReport {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl TypedMessageBody for WorkspaceReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Tracker Workspace"));
+}
+
+impl ConstantBufferSize for WorkspaceReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() * 2
+    }
+}
+
+impl Buffer for WorkspaceReport {
+    fn buffer_ref<T: BufMut>(&self, buf: &mut T) -> EmptyResult {
+        self.min.buffer_ref(buf)?;
+        self.max.buffer_ref(buf)?;
+        Ok(())
+    }
+}
+
+impl Unbuffer for WorkspaceReport {
+    fn unbuffer_ref(buf: &mut Bytes) -> Result<Self> {
+        let min = Vec3::unbuffer_ref(buf)?;
+        let max = Vec3::unbuffer_ref(buf)?;
+        Ok(WorkspaceReport { min, max })
+    }
+}
+
+/// Asks a tracker server to send its TrackerToRoomReport.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TrackerToRoomRequest;
+impl EmptyMessage for TrackerToRoomRequest {}
+impl TypedMessageBody for TrackerToRoomRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Tracker Request_Tracker_To_Room"),
+    );
+}
+
+/// Asks a tracker server to send a UnitToSensorReport for each of its sensors.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct UnitToSensorRequest;
+impl EmptyMessage for UnitToSensorRequest {}
+impl TypedMessageBody for UnitToSensorRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Tracker Request_Unit_To_Sensor"),
+    );
+}
+
+/// Asks a tracker server to send its WorkspaceReport.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct WorkspaceRequest;
+impl EmptyMessage for WorkspaceRequest {}
+impl TypedMessageBody for WorkspaceRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticTypeName(b"vrpn_Tracker Request_Tracker_Workspace"),
+    );
+}
+
+/// Asks a tracker server to make its current pose the origin.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ResetOrigin;
+impl EmptyMessage for ResetOrigin {}
+impl TypedMessageBody for ResetOrigin {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticTypeName(b"vrpn_Tracker Reset_Origin"));
+}
+
+/// Calibration transforms most recently reported by a tracker server.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Calibration {
+    pub tracker_to_room: Option<TrackerToRoomReport>,
+    pub unit_to_sensor: HashMap<Sensor, UnitToSensorReport>,
+    pub workspace: Option<WorkspaceReport>,
+}
+
+/// Implemented by the reports that make up a Calibration.
+trait CalibrationReport: TypedMessageBody + Unbuffer + Clone + Send + Sync {
+    fn update(&self, calibration: &mut Calibration);
+}
+
+impl CalibrationReport for TrackerToRoomReport {
+    fn update(&self, calibration: &mut Calibration) {
+        calibration.tracker_to_room = Some(self.clone());
+    }
+}
+
+impl CalibrationReport for UnitToSensorReport {
+    fn update(&self, calibration: &mut Calibration) {
+        calibration.unit_to_sensor.insert(self.sensor, self.clone());
+    }
+}
+
+impl CalibrationReport for WorkspaceReport {
+    fn update(&self, calibration: &mut Calibration) {
+        calibration.workspace = Some(self.clone());
+    }
+}
+
+struct CalibrationHandler<U: CalibrationReport> {
+    calibration: Weak<Mutex<Calibration>>,
+    phantom: PhantomData<U>,
+}
+
+impl<U: CalibrationReport> CalibrationHandler<U> {
+    fn new(calibration: &Arc<Mutex<Calibration>>) -> Box<CalibrationHandler<U>> {
+        Box::new(CalibrationHandler {
+            calibration: Arc::downgrade(calibration),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<U: CalibrationReport> fmt::Debug for CalibrationHandler<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CalibrationHandler").finish()
+    }
+}
+
+impl<U: CalibrationReport> TypedHandler for CalibrationHandler<U> {
+    type Item = U;
+    fn handle_typed(&mut self, msg: &Message<U>) -> Result<HandlerCode> {
+        match self.calibration.upgrade() {
+            Some(calibration) => {
+                let mut calibration = calibration.lock()?;
+                msg.body.update(&mut calibration);
+                Ok(HandlerCode::ContinueProcessing)
+            }
+
+            // If we get here, then the Remote has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side helper for a tracker: requests calibration transforms from the server
+/// and caches them as they are reported.
+pub struct Remote<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+    calibration: Arc<Mutex<Calibration>>,
+}
+
+impl<T: Connection + 'static> Remote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Remote<T>> {
+        let calibration = Arc::new(Mutex::new(Calibration::default()));
+        let _ = connection.add_typed_handler(
+            CalibrationHandler::<TrackerToRoomReport>::new(&calibration),
+            Some(sender),
+        )?;
+        let _ = connection.add_typed_handler(
+            CalibrationHandler::<UnitToSensorReport>::new(&calibration),
+            Some(sender),
+        )?;
+        let _ = connection.add_typed_handler(
+            CalibrationHandler::<WorkspaceReport>::new(&calibration),
+            Some(sender),
+        )?;
+        Ok(Remote {
+            connection,
+            sender,
+            calibration,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName> + Clone,
+        connection: Arc<T>,
+    ) -> Result<Remote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    fn send_request<U>(&self, body: U) -> Result<()>
+    where
+        U: TypedMessageBody + Buffer,
+    {
+        self.connection
+            .pack_message_body(None, self.sender, body, ServiceFlags::RELIABLE.into())
+    }
+
+    /// Ask the server for its tracker-to-room transform.
+    pub fn request_tracker_to_room(&self) -> Result<()> {
+        self.send_request(TrackerToRoomRequest)
+    }
+
+    /// Ask the server for the unit-to-sensor transform of every sensor.
+    pub fn request_unit_to_sensor(&self) -> Result<()> {
+        self.send_request(UnitToSensorRequest)
+    }
+
+    /// Ask the server for its workspace bounds.
+    pub fn request_workspace(&self) -> Result<()> {
+        self.send_request(WorkspaceRequest)
+    }
+
+    /// Ask the server to make the current pose the origin.
+    pub fn reset_origin(&self) -> Result<()> {
+        self.send_request(ResetOrigin)
+    }
+
+    /// Get a copy of all the calibration received so far.
+    pub fn calibration(&self) -> Result<Calibration> {
+        Ok(self.calibration.lock()?.clone())
+    }
+
+    /// Get the most recently received tracker-to-room transform, if any.
+    pub fn tracker_to_room(&self) -> Result<Option<TrackerToRoomReport>> {
+        Ok(self.calibration.lock()?.tracker_to_room.clone())
+    }
+
+    /// Get the most recently received unit-to-sensor transform for a sensor, if any.
+    pub fn unit_to_sensor(&self, sensor: Sensor) -> Result<Option<UnitToSensorReport>> {
+        Ok(self
+            .calibration
+            .lock()?
+            .unit_to_sensor
+            .get(&sensor)
+            .cloned())
+    }
+
+    /// Get the most recently received workspace bounds, if any.
+    pub fn workspace(&self) -> Result<Option<WorkspaceReport>> {
+        Ok(self.calibration.lock()?.workspace.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::{
+        prelude::*,
+        test_util::{dispatch, unconnected},
+        StaticSenderName,
+    };
+
+    fn velocity_report() -> VelocityReport {
+        VelocityReport {
+            sensor: Sensor(1),
+            vel: Vec3::new(1.0, 2.0, 3.0),
+            vel_quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+            vel_quat_dt: 0.5,
+        }
+    }
+
+    fn velocity_bytes() -> Vec<u8> {
+        Vec::from(&hex!("00 00 00 01 00 00 00 01 3f f0 00 00 00 00 00 00 40 00 00 00 00 00 00 00 40 08 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 3f f0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00")[..])
+    }
+
+    fn accel_report() -> AccelReport {
+        AccelReport {
+            sensor: Sensor(2),
+            acc: Vec3::new(-1.0, 0.25, 4.0),
+            acc_quat: Quat::new(0.5, 0.5, 0.5, 0.5),
+            acc_quat_dt: 0.01,
+        }
+    }
+
+    fn accel_bytes() -> Vec<u8> {
+        Vec::from(&hex!("00 00 00 02 00 00 00 02 bf f0 00 00 00 00 00 00 3f d0 00 00 00 00 00 00 40 10 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f 84 7a e1 47 ae 14 7b")[..])
+    }
+
+    #[test]
+    fn velocity_buffer() {
+        let report = velocity_report();
+        assert_eq!(report.buffer_size(), 72);
+        let buf = BytesMut::new()
+            .allocate_and_buffer(report)
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &velocity_bytes()[..]);
+    }
+
+    #[test]
+    fn velocity_unbuffer() {
+        let mut buf = Bytes::from(velocity_bytes());
+        let report = VelocityReport::unbuffer_ref(&mut buf).expect("Unbuffering needs to succeed");
+        assert_eq!(report, velocity_report());
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn accel_buffer() {
+        let report = accel_report();
+        assert_eq!(report.buffer_size(), 72);
+        let buf = BytesMut::new()
+            .allocate_and_buffer(report)
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &accel_bytes()[..]);
+    }
+
+    #[test]
+    fn accel_unbuffer() {
+        let mut buf = Bytes::from(accel_bytes());
+        let report = AccelReport::unbuffer_ref(&mut buf).expect("Unbuffering needs to succeed");
+        assert_eq!(report, accel_report());
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn short_buffer() {
+        let mut buf = Bytes::from(&velocity_bytes()[..40]);
+        assert!(VelocityReport::unbuffer_ref(&mut buf).is_err());
+    }
+
+    #[test]
+    fn tracker_to_room() {
+        let report = TrackerToRoomReport {
+            pos: Vec3::new(0.5, 1.0, 2.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        };
+        let expected = hex!("3f e0 00 00 00 00 00 00 3f f0 00 00 00 00 00 00 40 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 3f f0 00 00 00 00 00 00");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(report.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(TrackerToRoomReport::unbuffer_ref(&mut buf).unwrap(), report);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn unit_to_sensor() {
+        let report = UnitToSensorReport {
+            sensor: Sensor(3),
+            pos: Vec3::new(0.0, 0.0, 0.25),
+            quat: Quat::new(0.5, 0.5, 0.5, 0.5),
+        };
+        let expected = hex!("00 00 00 03 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 3f d0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(report.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(UnitToSensorReport::unbuffer_ref(&mut buf).unwrap(), report);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn workspace() {
+        let report = WorkspaceReport {
+            min: Vec3::new(-1.0, -1.0, 0.0),
+            max: Vec3::new(1.0, 1.0, 2.5),
+        };
+        let expected = hex!("bf f0 00 00 00 00 00 00 bf f0 00 00 00 00 00 00 00 00 00 00 00 00 00 00 3f f0 00 00 00 00 00 00 3f f0 00 00 00 00 00 00 40 04 00 00 00 00 00 00");
+        let buf = BytesMut::new()
+            .allocate_and_buffer(report.clone())
+            .expect("Buffering needs to succeed");
+        assert_eq!(&buf[..], &expected[..]);
+        let mut buf = buf.freeze();
+        assert_eq!(WorkspaceReport::unbuffer_ref(&mut buf).unwrap(), report);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn remote_caches_calibration() {
+        let conn = unconnected();
+        let remote = Remote::new_from_name(StaticSenderName(b"Tracker0"), Arc::clone(&conn))
+            .expect("should be able to create remote");
+        assert_eq!(remote.calibration().unwrap(), Calibration::default());
+
+        let report = UnitToSensorReport {
+            sensor: Sensor(1),
+            pos: Vec3::new(0.0, 0.0, 1.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        };
+        dispatch(&conn, remote.sender, report.clone());
+        assert_eq!(remote.unit_to_sensor(Sensor(1)).unwrap(), Some(report));
+        assert_eq!(remote.unit_to_sensor(Sensor(0)).unwrap(), None);
+        assert_eq!(remote.tracker_to_room().unwrap(), None);
+    }
+}
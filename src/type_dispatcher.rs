@@ -7,8 +7,8 @@ use bytes::Bytes;
 use crate::handler::*;
 use crate::types::*;
 use crate::{
-    constants, determine_id_range, types, Error, GenericMessage, MessageTypeIdentifier, RangedId,
-    Result, TypedMessageBody,
+    constants, determine_id_range, time::TimeAdjustment, types, Error, GenericBody, GenericMessage,
+    MessageTypeIdentifier, RangedId, Result, TypedMessageBody,
 };
 use std::{
     collections::HashMap,
@@ -186,6 +186,8 @@ pub struct TypeDispatcher {
     /// Index is the local sender ID
     senders: Vec<SenderName>,
     senders_by_name: HashMap<Name, LocalId<SenderId>>,
+    /// Applied to the times of messages from other ends as they're dispatched.
+    incoming_time_adjustment: TimeAdjustment,
 }
 
 impl Default for TypeDispatcher {
@@ -202,6 +204,7 @@ impl TypeDispatcher {
             generic_callbacks: CallbackCollection::new(Bytes::from_static(constants::GENERIC)),
             senders: Vec::new(),
             senders_by_name: HashMap::new(),
+            incoming_time_adjustment: TimeAdjustment::default(),
         };
 
         disp.register_sender(constants::CONTROL)
@@ -333,6 +336,26 @@ impl TypeDispatcher {
         mapping.call(&msg)
     }
 
+    /// Dispatch a connection event (like `constants::GOT_CONNECTION`) to local handlers only,
+    /// as a bodyless message from the `constants::CONTROL` sender.
+    pub fn call_control_event(&mut self, event: StaticTypeName) -> Result<()> {
+        let message_type = self.register_type(event)?.get();
+        let sender = self.register_sender(constants::CONTROL)?.get();
+        self.call(&GenericMessage::new(
+            None,
+            message_type,
+            sender,
+            GenericBody::default(),
+        ))
+    }
+
+    /// The offset applied to the times of incoming messages: zero unless set.
+    ///
+    /// Clones share the offset, so it may be changed even while handlers are being called.
+    pub fn incoming_time_adjustment(&self) -> TimeAdjustment {
+        self.incoming_time_adjustment.clone()
+    }
+
     pub fn senders_iter<'a>(
         &'a self,
     ) -> impl Iterator<Item = (LocalId<SenderId>, &'a SenderName)> + 'a {
@@ -16,7 +16,7 @@ use vrpn::{
 fn main() {
     let addr = "127.0.0.1:3883".parse().unwrap();
     let _conn = connect_tcp(addr)
-        .and_then(|tcp_stream| Ok(ConnectionIp::new_client(None, None, tcp_stream)))
+        .and_then(|tcp_stream| Ok(ConnectionIp::new_client(None, None, tcp_stream, None)))
         .wait()
         .unwrap();
     println!("Hello, world!");